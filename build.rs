@@ -0,0 +1,5 @@
+fn main() {
+	// generate the gRPC control service only when needed
+	#[cfg(feature = "grpc")]
+	tonic_build::compile_protos("proto/rconfd.proto").expect("compiling proto/rconfd.proto");
+}
@@ -0,0 +1,144 @@
+use crate::{
+	libc::{group_id, User},
+	result::Error,
+};
+
+use std::str::FromStr;
+
+/// version of the extended attribute format
+const ACL_VERSION: u32 = 2;
+
+/// entry tags
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// id of entries not refering to a named user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// name of the extended attribute holding the access ACL
+pub const ACL_XATTR: &str = "system.posix_acl_access";
+
+/// An ACL entry giving permissions to a named user or group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+	tag: u16,
+	id: u32,
+	perm: u16,
+}
+
+impl FromStr for Entry {
+	type Err = Error;
+
+	/// Parse an entry of the form (u[ser]|g[roup]):name:perms, where name can also be a numeric id
+	/// and perms a combination of rwx
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || Error::InvalidAcl(s.to_owned());
+		let mut parts = s.splitn(3, ':');
+		let tag = match parts.next() {
+			Some("u") | Some("user") => ACL_USER,
+			Some("g") | Some("group") => ACL_GROUP,
+			_ => return Err(invalid()),
+		};
+		let name = parts
+			.next()
+			.filter(|name| !name.is_empty())
+			.ok_or_else(invalid)?;
+		let id = match name.parse::<u32>() {
+			Ok(id) => id,
+			Err(_) if tag == ACL_USER => {
+				User::new(name).map(|user| user.uid).ok_or_else(invalid)?
+			}
+			Err(_) => group_id(name).ok_or_else(invalid)?,
+		};
+		let mut perm = 0;
+		for c in parts.next().ok_or_else(invalid)?.chars() {
+			perm |= match c {
+				'r' => 4,
+				'w' => 2,
+				'x' => 1,
+				'-' => 0,
+				_ => return Err(invalid()),
+			};
+		}
+		Ok(Self { tag, id, perm })
+	}
+}
+
+/// Return the value of the access ACL attribute combining file mode and named entries
+pub fn encode(mode: u32, entries: &[Entry]) -> Vec<u8> {
+	let mut all = vec![
+		Entry {
+			tag: ACL_USER_OBJ,
+			id: ACL_UNDEFINED_ID,
+			perm: ((mode >> 6) & 7) as u16,
+		},
+		Entry {
+			tag: ACL_GROUP_OBJ,
+			id: ACL_UNDEFINED_ID,
+			perm: ((mode >> 3) & 7) as u16,
+		},
+		Entry {
+			tag: ACL_OTHER,
+			id: ACL_UNDEFINED_ID,
+			perm: (mode & 7) as u16,
+		},
+	];
+	all.extend_from_slice(entries);
+	// the mask is the union of the group class permissions
+	if !entries.is_empty() {
+		all.push(Entry {
+			tag: ACL_MASK,
+			id: ACL_UNDEFINED_ID,
+			perm: all
+				.iter()
+				.filter(|e| e.tag != ACL_USER_OBJ && e.tag != ACL_OTHER)
+				.fold(0, |mask, e| mask | e.perm),
+		});
+	}
+	// the kernel expects entries sorted by tag and id
+	all.sort_unstable();
+	all.dedup_by(|a, b| a.tag == b.tag && a.id == b.id);
+
+	let mut value = Vec::with_capacity(4 + all.len() * 8);
+	value.extend_from_slice(&ACL_VERSION.to_le_bytes());
+	for entry in all {
+		value.extend_from_slice(&entry.tag.to_le_bytes());
+		value.extend_from_slice(&entry.perm.to_le_bytes());
+		value.extend_from_slice(&entry.id.to_le_bytes());
+	}
+	value
+}
+
+#[test]
+fn parse_entry() {
+	assert_eq!(
+		"g:1000:r".parse::<Entry>().unwrap(),
+		Entry {
+			tag: ACL_GROUP,
+			id: 1000,
+			perm: 4
+		}
+	);
+	assert!("other::r".parse::<Entry>().is_err());
+	assert!("user:1000:rwz".parse::<Entry>().is_err());
+}
+
+#[test]
+fn encode_group() {
+	let value = encode(0o640, &["group:1000:r".parse().unwrap()]);
+	assert_eq!(
+		value,
+		vec![
+			2, 0, 0, 0, // version
+			1, 0, 6, 0, 255, 255, 255, 255, // user::rw-
+			4, 0, 4, 0, 255, 255, 255, 255, // group::r--
+			8, 0, 4, 0, 232, 3, 0, 0, // group:1000:r--
+			16, 0, 4, 0, 255, 255, 255, 255, // mask::r--
+			32, 0, 0, 0, 255, 255, 255, 255, // other::---
+		]
+	);
+}
@@ -1,19 +1,32 @@
+use crate::{render, result::Error};
+
 use argh::{FromArgs, TopLevelCommand};
 use std::env;
 use std::path::Path;
+use std::time::Duration;
 
 /// Generate files from jsonnet templates and eventually keep them in sync with secrets fetched from a
 /// vault server using a jwt token to authenticate with.
 #[derive(FromArgs)]
 pub struct Args {
-	/// directory containing the rconfd config files (/etc/rconfd)
-	#[argh(option, short = 'd', default = "\"/etc/rconfd\".to_owned()")]
-	pub dir: String,
+	/// directory containing the rconfd config files, overlaying the previous ones (repeatable or : separated)
+	/// (/etc/rconfd)
+	#[argh(option, short = 'd')]
+	pub dir: Vec<String>,
+
+	/// file of KEY=value lines to load in the environment before processing the config files (repeatable)
+	#[argh(option)]
+	pub env_file: Vec<String>,
 
 	/// the vault url ($VAULT_URL or https://localhost:8200/v1)
 	#[argh(option, short = 'u', default = "default_url()")]
 	pub url: String,
 
+	/// url of a vault performance standby (or replica) where to send secret reads instead of the active node
+	/// (repeatable)
+	#[argh(option)]
+	pub read_url: Vec<String>,
+
 	/// the login path (/auth/kubernetes/login)
 	#[argh(option, short = 'l', default = "\"/auth/kubernetes/login\".to_owned()")]
 	pub login_path: String,
@@ -22,6 +35,22 @@ pub struct Args {
 	#[argh(option, short = 'j')]
 	pub jpath: Option<String>,
 
+	/// maximum depth of the jsonnet evaluation stack (200)
+	#[argh(option, default = "render::MAX_STACK")]
+	pub max_stack: usize,
+
+	/// maximum number of frames shown in jsonnet error traces (20)
+	#[argh(option, default = "render::MAX_TRACE")]
+	pub max_trace: usize,
+
+	/// width of the line numbers column in jsonnet error traces (4)
+	#[argh(option, default = "render::TRACE_PADDING")]
+	pub trace_padding: usize,
+
+	/// show absolute paths instead of paths relative to the template dir in jsonnet error traces
+	#[argh(switch)]
+	pub trace_absolute: bool,
+
 	/// path of vault CA certificate (/var/run/secrets/kubernetes.io/serviceaccount/ca.crt)
 	#[argh(
 		option,
@@ -42,9 +71,21 @@ pub struct Args {
 	)]
 	pub token_path: String,
 
-	/// verbose mode
+	/// verbose mode: debug logs, or trace logs and dependencies logs if given twice as -v -v (-vv is rejected)
 	#[argh(switch, short = 'v')]
-	pub verbose: bool,
+	pub verbose: u8,
+
+	/// quiet mode: only log warnings and errors
+	#[argh(switch, short = 'q')]
+	pub quiet: bool,
+
+	/// where to send logs: "stderr", "syslog" or "file:<path>" (stderr)
+	#[argh(option, default = "\"stderr\".to_owned()")]
+	pub log_target: String,
+
+	/// size in bytes above which the log file is rotated (renamed with a .1 suffix)
+	#[argh(option)]
+	pub log_max_size: Option<u64>,
 
 	/// s6 readiness file descriptor
 	#[argh(option, short = 'r')]
@@ -53,6 +94,346 @@ pub struct Args {
 	/// daemon mode (stays in the foreground)
 	#[argh(switch, short = 'D')]
 	pub daemon: bool,
+
+	/// append an entry for each secret access to the given file or to "syslog"
+	#[argh(option, short = 'a')]
+	pub audit_log: Option<String>,
+
+	/// forbid hooks and exe secrets to execute any command
+	#[argh(switch)]
+	pub no_exe: bool,
+
+	/// absolute path of an executable (or a directory ending with /) that hooks and exe secrets are allowed to
+	/// execute (repeatable)
+	#[argh(option)]
+	pub allow_exe: Vec<String>,
+
+	/// execute hooks and exe secrets without network access and with restricted file system access (landlock
+	/// feature)
+	#[argh(switch)]
+	pub sandbox: bool,
+
+	/// additional path sandboxed commands can write to (repeatable)
+	#[argh(option)]
+	pub sandbox_path: Vec<String>,
+
+	/// user[:group] or uid[:gid] the exe secrets are executed as when rconfd runs as root (65534:65534)
+	#[argh(option, default = "\"65534:65534\".to_owned()")]
+	pub exe_user: String,
+
+	/// address (ip:port) where to serve /healthz and /readyz http probes
+	#[argh(option)]
+	pub http_listen: Option<String>,
+
+	/// OTLP collector endpoint where to export traces (requires the otel feature)
+	#[argh(option)]
+	pub otlp_endpoint: Option<String>,
+
+	/// url where to post failures (template, secret, hook) as JSON
+	#[argh(option)]
+	pub error_webhook: Option<String>,
+
+	/// sentry DSN where to report failures (requires the sentry feature)
+	#[argh(option)]
+	pub sentry_dsn: Option<String>,
+
+	/// fail that percentage of the secret fetches and hook executions to test retries and hooks (requires the
+	/// chaos feature)
+	#[argh(option)]
+	pub chaos: Option<u32>,
+
+	/// delay the secret fetches and hook executions by a random duration up to that (ex: 5s) (requires the
+	/// chaos feature)
+	#[argh(option, from_str_fn(parse_duration))]
+	pub chaos_delay: Option<Duration>,
+
+	/// warn when evaluating and writing a template takes longer than that many milliseconds
+	#[argh(option)]
+	pub slow_template: Option<u64>,
+
+	/// fetch again secrets containing PEM certificates that many seconds before they expire
+	#[argh(option)]
+	pub cert_threshold: Option<u64>,
+
+	/// warn when a secret (lease or certificate) expires within that delay without having been renewed (1h)
+	#[argh(
+		option,
+		default = "Duration::from_secs(3600)",
+		from_str_fn(parse_duration)
+	)]
+	pub expiry_warning: Duration,
+
+	/// check the version of static KV v2 secrets every that many seconds and fetch them again when it
+	/// changes (daemon mode)
+	#[argh(option)]
+	pub kv_poll: Option<u64>,
+
+	/// daily window (HH:MM-HH:MM in local time) outside of which the rotations of secrets that are not about
+	/// to expire are deferred (repeatable, daemon mode)
+	#[argh(option)]
+	pub rotation_window: Vec<String>,
+
+	/// maximum size in bytes of a file secret (16777216)
+	#[argh(option, default = "16 * 1024 * 1024")]
+	pub max_secret_size: u64,
+
+	/// fetch up to that many pending vault secrets of the same role concurrently, pipelined over the same
+	/// connection
+	#[argh(option)]
+	pub batch_reads: Option<usize>,
+
+	/// url of an etcd endpoint of the etcd backend, watched for changes in daemon mode (repeatable,
+	/// http://127.0.0.1:2379 by default, requires the etcd feature)
+	#[argh(option)]
+	pub etcd_endpoint: Vec<String>,
+
+	/// pause vault requests and leave the files as is after that many consecutive vault failures, until a
+	/// probe succeeds (daemon mode)
+	#[argh(option)]
+	pub circuit_threshold: Option<u32>,
+
+	/// delay between two vault probes while the circuit is open, and between two retries before (30s)
+	#[argh(
+		option,
+		default = "Duration::from_secs(30)",
+		from_str_fn(parse_duration)
+	)]
+	pub circuit_probe: Duration,
+
+	/// fail if a secret is leased or dynamic, to guarantee that generated files don't contain expiring
+	/// credentials
+	#[argh(switch)]
+	pub require_static: bool,
+
+	/// regenerate templates when the lease of a secret changes even if its value is the same
+	#[argh(switch)]
+	pub compare_leases: bool,
+
+	/// revoke the leases of the secrets no longer used after a reload (requires sudo on
+	/// sys/leases/revoke-prefix)
+	#[argh(switch)]
+	pub revoke_unused: bool,
+
+	/// lock file preventing concurrent instances (default rconfd.lock in the state dir, no lock without)
+	#[argh(option)]
+	pub lock_file: Option<String>,
+
+	/// generate byte identical files from identical inputs (write files in order, pin times to SOURCE_DATE_EPOCH)
+	#[argh(switch)]
+	pub deterministic: bool,
+
+	/// directory prepended to the paths of all generated files (ex: a staged root filesystem at image build time)
+	#[argh(option)]
+	pub root: Option<String>,
+
+	/// refuse to overwrite files not previously generated by rconfd or modified since
+	#[argh(switch)]
+	pub no_clobber: bool,
+
+	/// refuse to write files outside of memory backed filesystems (tmpfs, ramfs)
+	#[argh(switch)]
+	pub require_tmpfs: bool,
+
+	/// umask of the process (octal, ex: 077) applied to created files and directories (inherited if not given)
+	#[argh(option)]
+	pub umask: Option<String>,
+
+	/// switch to that user[:group] once all templates have been generated (daemon mode)
+	#[argh(option)]
+	pub run_as: Option<String>,
+
+	/// signal readiness only after the ready hook (and the modified hooks) succeeded, and fail if they don't
+	#[argh(switch)]
+	pub ready_after_hooks: bool,
+
+	/// phase of templates generated, along with their ready hooks, before the templates of the next ones and
+	/// the templates without phase (repeatable, in order)
+	#[argh(option)]
+	pub phase: Vec<String>,
+
+	/// fail (or report the failure and execute the error hooks in daemon mode) if readiness can't be signaled
+	/// within that delay (ex: 60s)
+	#[argh(option, from_str_fn(parse_duration))]
+	pub ready_timeout: Option<Duration>,
+
+	/// skip templates with missing secrets or evaluation errors instead of aborting, and signal readiness anyway
+	#[argh(switch, short = 'k')]
+	pub keep_going: bool,
+
+	/// abort on the first missing secret or evaluation error (default), overriding --keep-going
+	#[argh(switch)]
+	pub strict: bool,
+
+	/// lock memory to prevent secrets from being swapped and disable core dumps
+	#[argh(switch)]
+	pub mlock: bool,
+
+	/// show secret values in logs and error messages (debugging only)
+	#[argh(switch)]
+	pub unsafe_log_values: bool,
+
+	/// fetch all secrets again and regenerate all templates at that interval (ex: 10m) to repair drifts
+	/// (daemon mode)
+	#[argh(option, from_str_fn(parse_duration))]
+	pub reconcile: Option<Duration>,
+
+	/// regenerate all templates each time that file is touched (daemon mode)
+	#[argh(option)]
+	pub trigger: Option<String>,
+
+	/// check at that interval (ex: 1m) that the generated files have not been modified by someone else
+	/// (daemon mode)
+	#[argh(option, from_str_fn(parse_duration))]
+	pub tamper_check: Option<Duration>,
+
+	/// path of a unix socket accepting control commands
+	#[argh(option)]
+	pub control_socket: Option<String>,
+
+	/// address (ip:port) where to serve the gRPC control service (requires the grpc feature)
+	#[argh(option)]
+	pub grpc_listen: Option<String>,
+
+	/// register the org.rconfd.Manager service on the "system" or "session" D-Bus (requires the dbus feature)
+	#[argh(option)]
+	pub dbus: Option<String>,
+
+	/// algorithm used to detect the changes of the generated files: "blake3" or "sha1" (blake3)
+	#[argh(option, default = "\"blake3\".to_owned()")]
+	pub checksum: String,
+
+	/// directory where leased secrets are saved to resume renewals after a restart
+	#[argh(option, short = 's')]
+	pub state_dir: Option<String>,
+
+	/// print the resolved configuration as JSON and exit
+	#[argh(switch)]
+	pub print_config: bool,
+
+	/// format of the summary printed at the end of one-shot runs: "text" or "json" (text)
+	#[argh(option, default = "\"text\".to_owned()")]
+	pub output: String,
+
+	#[argh(subcommand)]
+	pub cmd: Option<SubCommand>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SubCommand {
+	Ctl(CtlArgs),
+	Test(TestArgs),
+	Migrate(MigrateArgs),
+}
+
+/// Send a command (status, reload, render <template>, refresh <secret>) to a running rconfd
+#[derive(FromArgs)]
+#[argh(subcommand, name = "ctl")]
+pub struct CtlArgs {
+	/// path of the control socket of the running rconfd
+	#[argh(option, short = 's')]
+	pub socket: String,
+
+	/// command and its argument
+	#[argh(positional)]
+	pub command: Vec<String>,
+}
+
+/// default directory of the config files
+const DEFAULT_DIR: &str = "/etc/rconfd";
+
+impl Args {
+	/// Return the directories of the config files by increasing precedence
+	pub fn dirs(&self) -> Vec<&str> {
+		let dirs: Vec<&str> = self
+			.dir
+			.iter()
+			.flat_map(|dir| dir.split(':'))
+			.filter(|dir| !dir.is_empty())
+			.collect();
+		if dirs.is_empty() {
+			vec![DEFAULT_DIR]
+		} else {
+			dirs
+		}
+	}
+
+	/// Return true if the failing templates are skipped instead of aborting the run
+	pub fn skips_failures(&self) -> bool {
+		self.keep_going && !self.strict
+	}
+
+	/// Return the default log filter corresponding to the verbosity flags
+	pub fn log_filter(&self) -> &'static str {
+		if self.quiet {
+			"rconfd=warn"
+		} else {
+			match self.verbose {
+				0 => "rconfd=info",
+				1 => "rconfd=debug",
+				_ => "debug,rconfd=trace",
+			}
+		}
+	}
+}
+
+/// Render templates with fixture secrets and compare them with expected outputs
+#[derive(FromArgs)]
+#[argh(subcommand, name = "test")]
+pub struct TestArgs {
+	/// directory containing <template>.secrets.json files and <template>/ directories of expected
+	/// outputs (tests)
+	#[argh(option, short = 'f', default = "\"tests\".to_owned()")]
+	pub fixtures: String,
+
+	/// (re)generate the expected outputs instead of comparing them
+	#[argh(switch, short = 'u')]
+	pub update: bool,
+}
+
+/// Convert the configuration of another daemon into rconfd config and templates
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+pub struct MigrateArgs {
+	#[argh(subcommand)]
+	pub from: MigrateFrom,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum MigrateFrom {
+	ConsulTemplate(ConsulTemplateArgs),
+	Confd(ConfdArgs),
+}
+
+/// Convert the template stanzas of a consul-template configuration
+#[derive(FromArgs)]
+#[argh(subcommand, name = "consul-template")]
+pub struct ConsulTemplateArgs {
+	/// path of the consul-template HCL configuration
+	#[argh(positional)]
+	pub config: String,
+
+	/// directory where to write the rconfd config and jsonnet templates (.)
+	#[argh(option, short = 'o', default = "\".\".to_owned()")]
+	pub output: String,
+}
+
+/// Convert the template resources of a confd configuration directory (conf.d/*.toml and templates/)
+#[derive(FromArgs)]
+#[argh(subcommand, name = "confd")]
+pub struct ConfdArgs {
+	/// path of the confd configuration directory
+	#[argh(positional)]
+	pub confdir: String,
+
+	/// directory where to write the rconfd config and jsonnet templates (.)
+	#[argh(option, short = 'o', default = "\".\".to_owned()")]
+	pub output: String,
+
+	/// rconfd backend where to fetch confd keys: "env" or "vault" (env)
+	#[argh(option, short = 'b', default = "\"env\".to_owned()")]
+	pub backend: String,
 }
 
 /// returns the default vault url if not defined on command line argument
@@ -64,6 +445,52 @@ fn default_url() -> String {
 		.unwrap()
 }
 
+/// lookup list for duration units
+const UNITS: &[(&str, u64)] = &[("s", 1), ("m", 60), ("h", 3600), ("d", 86400)];
+
+/// parse a duration given in seconds or with a unit (10m, 1h, 1d)
+fn parse_duration(value: &str) -> Result<Duration, String> {
+	let (number, unit) = UNITS
+		.iter()
+		.find_map(|(suffix, unit)| value.strip_suffix(suffix).map(|number| (number, *unit)))
+		.unwrap_or((value, 1));
+	number
+		.parse::<u64>()
+		.map(|number| Duration::from_secs(number * unit))
+		.map_err(|_| format!("invalid duration \"{}\"", value))
+}
+
+/// Configuration of the pipeline run by [`crate::run`], with the options and defaults of the command line
+pub struct Config {
+	args: Args,
+}
+
+impl Config {
+	/// Return the configuration generating once the templates declared in the given config directories
+	pub fn new(dirs: &[&str]) -> Result<Self, Error> {
+		let args: Vec<&str> = dirs.iter().flat_map(|dir| vec!["-d", *dir]).collect();
+		Self::from_args(&args)
+	}
+
+	/// Return the configuration given by command line arguments (without the program name)
+	pub fn from_args(args: &[&str]) -> Result<Self, Error> {
+		Args::from_args(&[env!("CARGO_PKG_NAME")], args)
+			.map(Self::from)
+			.map_err(|early_exit| Error::InvalidArgs(early_exit.output))
+	}
+
+	/// Return the options of the configuration
+	pub fn args(&self) -> &Args {
+		&self.args
+	}
+}
+
+impl From<Args> for Config {
+	fn from(args: Args) -> Self {
+		Self { args }
+	}
+}
+
 /// copy of argh::from_env to insert command name and version in help text
 pub fn from_env<T: TopLevelCommand>() -> T {
 	let args: Vec<String> = std::env::args().collect();
@@ -73,7 +500,7 @@ pub fn from_env<T: TopLevelCommand>() -> T {
 		.unwrap_or(&args[0]);
 	let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
 	T::from_args(&[cmd], &args_str[1..]).unwrap_or_else(|early_exit| {
-		println!("{} {}\n", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
+		println!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 		println!("{}", early_exit.output);
 		std::process::exit(match early_exit.status {
 			Ok(()) => 0,
@@ -81,3 +508,18 @@ pub fn from_env<T: TopLevelCommand>() -> T {
 		})
 	})
 }
+
+#[test]
+fn config_dirs() {
+	let config = Config::new(&["/etc/rconfd", "/run/rconfd"]).unwrap();
+	assert_eq!(config.args().dirs(), vec!["/etc/rconfd", "/run/rconfd"]);
+	assert!(!config.args().daemon);
+	assert!(Config::from_args(&["--unknown"]).is_err());
+}
+
+#[test]
+fn durations() {
+	assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+	assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+	assert!(parse_duration("1w").is_err());
+}
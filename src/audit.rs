@@ -0,0 +1,122 @@
+use crate::libc::User;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+	collections::BTreeMap,
+	ffi::CString,
+	fs::{File, OpenOptions},
+	io::Write,
+	os::unix::fs::OpenOptionsExt,
+	path::PathBuf,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Where to write the audit entries
+enum Target {
+	/// append to a file
+	File(File, PathBuf),
+	/// send to the local syslog daemon
+	Syslog,
+}
+
+/// Append-only log of the secrets accesses
+pub struct Audit {
+	target: Target,
+}
+
+/// An entry of the audit log
+#[derive(Serialize)]
+pub struct Entry<'a> {
+	/// path expression of the secret
+	pub path: &'a str,
+	/// backend used to fetch the secret
+	pub backend: &'a str,
+	/// role used to fetch the secret (vault) or first argument
+	pub role: &'a str,
+	/// templates consuming the secret
+	pub templates: Vec<&'a str>,
+	/// labels declared for the secret
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub labels: &'a BTreeMap<String, String>,
+	/// "ok" or the (redacted) error
+	pub result: String,
+}
+
+/// An entry with its timestamp as written in the audit log
+#[derive(Serialize)]
+struct Line<'a> {
+	/// unix time (s) of the access
+	time: u64,
+	#[serde(flatten)]
+	entry: &'a Entry<'a>,
+}
+
+impl Audit {
+	/// Open the audit log which is either "syslog" or a file path
+	pub fn new(target: &str) -> Result<Self> {
+		let target = if target == "syslog" {
+			// SAFETY: ident is a static nul terminated string
+			unsafe {
+				libc::openlog(
+					b"rconfd\0".as_ptr() as *const libc::c_char,
+					libc::LOG_PID,
+					libc::LOG_AUTHPRIV,
+				)
+			};
+			Target::Syslog
+		} else {
+			Target::File(
+				OpenOptions::new()
+					.append(true)
+					.create(true)
+					.mode(0o600)
+					.open(target)
+					.with_context(|| format!("Opening audit log {}", target))?,
+				PathBuf::from(target),
+			)
+		};
+		Ok(Self { target })
+	}
+
+	/// Give the audit log file (if any) to the user the process is about to switch to
+	pub fn chown(&self, user: &User) {
+		if let Target::File(_, ref path) = self.target {
+			user.chown(path);
+		}
+	}
+
+	/// Record an entry (a failure to record is logged but not fatal)
+	pub fn record(&mut self, entry: &Entry) {
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_secs())
+			.unwrap_or(0);
+		let line = match serde_json::to_string(&Line { time, entry }) {
+			Ok(line) => line,
+			Err(e) => {
+				log::error!("Serializing audit entry: {}", e);
+				return;
+			}
+		};
+		match self.target {
+			Target::File(ref mut file, _) => {
+				if let Err(e) = writeln!(file, "{}", line) {
+					log::error!("Writing audit entry: {}", e);
+				}
+			}
+			Target::Syslog => {
+				if let Ok(msg) = CString::new(line) {
+					// SAFETY: format and message are nul terminated strings
+					unsafe {
+						libc::syslog(
+							libc::LOG_INFO,
+							b"%s\0".as_ptr() as *const libc::c_char,
+							msg.as_ptr(),
+						)
+					};
+				}
+			}
+		}
+	}
+}
@@ -12,6 +12,24 @@ pub enum Backend {
 	File,
 	/// Exe
 	Exe,
+	/// Host facts
+	Sys,
+	/// Kernel command line and procfs/sysfs files
+	Proc,
+	/// TPM sealed objects
+	Tpm,
+	/// Files encrypted for a PKCS#11 private key
+	Pkcs11,
+	/// Linux kernel keyrings
+	Keyring,
+	/// DNS TXT records
+	Dns,
+	/// Kubernetes Secrets
+	K8s,
+	/// etcd keys
+	Etcd,
+	/// HTTP(S) urls
+	Http,
 }
 
 /// lookup list for backend
@@ -20,6 +38,15 @@ const BACKENDS: &[(&str, Backend)] = &[
 	("env", Backend::Env),
 	("file", Backend::File),
 	("exe", Backend::Exe),
+	("sys", Backend::Sys),
+	("proc", Backend::Proc),
+	("tpm", Backend::Tpm),
+	("pkcs11", Backend::Pkcs11),
+	("keyring", Backend::Keyring),
+	("dns", Backend::Dns),
+	("k8s", Backend::K8s),
+	("etcd", Backend::Etcd),
+	("http", Backend::Http),
 ];
 
 impl<'a> fmt::Display for Backend {
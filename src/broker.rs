@@ -0,0 +1,192 @@
+use crate::message::{send_message, Message};
+
+use anyhow::Result;
+use async_std::{
+	channel::{bounded, Receiver, Sender},
+	prelude::FutureExt,
+	stream::StreamExt,
+};
+use std::collections::VecDeque;
+
+/// number of pending messages above which pending manifestations are merged into one RenderAll
+const MAX_PENDING: usize = 1024;
+
+/// number of requests from outside the actor loop queued before their senders wait
+const MAX_REQUESTS: usize = 64;
+
+/// number of priority classes
+const PRIORITIES: usize = 5;
+
+/// Return the priority class of a message (0 is handled first)
+fn priority(msg: &Message) -> usize {
+	match msg {
+		Message::Login(_, _) | Message::RenewToken(_, _) => 0,
+		Message::Reload | Message::Probe => 1,
+		Message::GetSecret(_, _)
+		| Message::Refresh(_)
+		| Message::CheckVersion(_)
+		| Message::CheckExpiry(_) => 2,
+		Message::GenerateTemplate(_) | Message::RenderAll | Message::Reconcile => 3,
+		Message::CheckReady | Message::CheckTampered | Message::ReadyTimeout => 4,
+	}
+}
+
+/// Queue of the messages sent to the actor loop, handled by priority then in order, without duplicates
+#[derive(Default)]
+pub struct Broker {
+	queues: [VecDeque<Message>; PRIORITIES],
+	/// the last message was waited for (all the previous ones have been handled)
+	waited: bool,
+}
+
+impl Broker {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// return the number of pending messages
+	fn len(&self) -> usize {
+		self.queues.iter().map(VecDeque::len).sum()
+	}
+
+	/// Queue a message unless an identical one is already pending
+	pub fn push(&mut self, msg: Message) {
+		let queue = &mut self.queues[priority(&msg)];
+		match msg {
+			// a pending fetch of the same secret generates the templates if any of them asked to
+			Message::GetSecret(ref path, gen_tmpl) => {
+				let pending = queue.iter_mut().find_map(|pending| match pending {
+					Message::GetSecret(pending_path, pending_gen) if pending_path == path => {
+						Some(pending_gen)
+					}
+					_ => None,
+				});
+				match pending {
+					Some(pending_gen) => *pending_gen |= gen_tmpl,
+					None => queue.push_back(msg),
+				}
+			}
+			// all templates are generated anyway
+			Message::GenerateTemplate(_) if queue.contains(&Message::RenderAll) => {}
+			msg if queue.contains(&msg) => {}
+			msg => queue.push_back(msg),
+		}
+		// during rotation storms, generate all templates once instead of one by one
+		if self.len() > MAX_PENDING {
+			let queue = &mut self.queues[priority(&Message::RenderAll)];
+			let before = queue.len();
+			queue.retain(|msg| !matches!(msg, Message::GenerateTemplate(_) | Message::RenderAll));
+			if queue.len() != before {
+				log::warn!("Too many pending messages: merging the pending manifestations");
+				queue.push_back(Message::RenderAll);
+			}
+		}
+	}
+
+	/// Return true if the last message returned by [`Broker::next`] was waited for, meaning that a new burst of
+	/// messages begins
+	pub fn waited(&self) -> bool {
+		self.waited
+	}
+
+	/// Return the pending messages in the order they will be handled
+	pub fn pending(&self) -> impl Iterator<Item = &Message> {
+		self.queues.iter().flatten()
+	}
+
+	/// Return the next message to handle
+	pub fn pop(&mut self) -> Option<Message> {
+		self.queues.iter_mut().find_map(VecDeque::pop_front)
+	}
+
+	/// Move the messages and requests sent since last call into the queue and return the next message to
+	/// handle, waiting for one if none is pending. Return None when all senders are gone
+	pub async fn next(
+		&mut self,
+		receiver: &mut Receiver<Message>,
+		requests: &mut Receiver<Message>,
+	) -> Option<Message> {
+		self.waited = false;
+		loop {
+			while let Ok(msg) = receiver.try_recv() {
+				self.push(msg);
+			}
+			// requests are moved into the deduplicated queue, freeing their senders
+			while let Ok(msg) = requests.try_recv() {
+				self.push(msg);
+			}
+			if let Some(msg) = self.pop() {
+				return Some(msg);
+			}
+			let msg = receiver.next().race(requests.next()).await?;
+			self.waited = true;
+			self.push(msg);
+		}
+	}
+}
+
+/// Handle on the actor loop of a pipeline started with [`crate::run_with`], letting the embedding service ask
+/// for reloads, renders and refreshes. A broker drives a single pipeline. At most 64 requests
+/// are queued while the loop is busy, further ones wait for it to catch up
+#[derive(Clone)]
+pub struct SecretBroker {
+	sender: Sender<Message>,
+	receiver: Receiver<Message>,
+}
+
+impl SecretBroker {
+	pub fn new() -> Self {
+		// the actor loop sends messages to itself on its own unbounded channel
+		let (sender, receiver) = bounded(MAX_REQUESTS);
+		Self { sender, receiver }
+	}
+
+	/// Return the channel of the requests to the actor loop
+	pub(crate) fn channel(&self) -> (Sender<Message>, Receiver<Message>) {
+		(self.sender.clone(), self.receiver.clone())
+	}
+
+	/// Parse the config files again
+	pub async fn reload(&self) -> Result<()> {
+		send_message(self.sender.clone(), Message::Reload).await
+	}
+
+	/// Generate a template (config name) again
+	pub async fn render(&self, template: &str) -> Result<()> {
+		send_message(
+			self.sender.clone(),
+			Message::GenerateTemplate(template.to_owned()),
+		)
+		.await
+	}
+
+	/// Fetch a secret (path) again even if still valid and regenerate the templates using it
+	pub async fn refresh(&self, secret: &str) -> Result<()> {
+		send_message(self.sender.clone(), Message::Refresh(secret.to_owned())).await
+	}
+}
+
+impl Default for SecretBroker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[test]
+fn dedup_and_priority() {
+	let mut broker = Broker::new();
+	broker.push(Message::GenerateTemplate("app".to_owned()));
+	broker.push(Message::GetSecret("env:str:HOME".to_owned(), false));
+	broker.push(Message::GenerateTemplate("app".to_owned()));
+	broker.push(Message::GetSecret("env:str:HOME".to_owned(), true));
+	assert_eq!(broker.len(), 2);
+	assert_eq!(
+		broker.pop(),
+		Some(Message::GetSecret("env:str:HOME".to_owned(), true))
+	);
+	assert_eq!(
+		broker.pop(),
+		Some(Message::GenerateTemplate("app".to_owned()))
+	);
+	assert_eq!(broker.pop(), None);
+}
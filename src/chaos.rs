@@ -0,0 +1,103 @@
+use crate::result::{Error, Result};
+
+#[cfg(feature = "chaos")]
+use std::{
+	sync::atomic::{AtomicU32, AtomicU64, Ordering},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// percentage of secret fetches and hook executions to fail
+#[cfg(feature = "chaos")]
+static PERCENT: AtomicU32 = AtomicU32::new(0);
+
+/// maximum delay (ms) added to secret fetches and hook executions
+#[cfg(feature = "chaos")]
+static MAX_DELAY: AtomicU64 = AtomicU64::new(0);
+
+/// state of the pseudo random generator
+#[cfg(feature = "chaos")]
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Fail a percentage of the secret fetches and hook executions, after a random delay of at most max_delay
+#[cfg(feature = "chaos")]
+pub fn init(percent: u32, max_delay: Option<Duration>) {
+	PERCENT.store(percent.min(100), Ordering::Relaxed);
+	MAX_DELAY.store(
+		max_delay.map(|d| d.as_millis() as u64).unwrap_or(0),
+		Ordering::Relaxed,
+	);
+	let seed = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_nanos() as u64)
+		.unwrap_or(0);
+	// xorshift state must not be 0
+	STATE.store(seed | 1, Ordering::Relaxed);
+}
+
+/// return the next pseudo random number (xorshift64)
+#[cfg(feature = "chaos")]
+fn random() -> u64 {
+	let next = |mut x: u64| {
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		x
+	};
+	let prev = STATE
+		.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x)))
+		.unwrap_or(1);
+	next(prev)
+}
+
+/// Return the delay to add to an operation and whether it must fail
+#[cfg(feature = "chaos")]
+fn draw() -> (Duration, bool) {
+	let max_delay = MAX_DELAY.load(Ordering::Relaxed);
+	let delay = if max_delay > 0 {
+		Duration::from_millis(random() % (max_delay + 1))
+	} else {
+		Duration::from_millis(0)
+	};
+	(
+		delay,
+		random() % 100 < PERCENT.load(Ordering::Relaxed) as u64,
+	)
+}
+
+/// Delay the fetch of a secret and make it fail if drawn
+#[cfg(feature = "chaos")]
+pub async fn fetch(path: &str) -> Result<()> {
+	let (delay, fail) = draw();
+	if !delay.is_zero() {
+		log::warn!("chaos: delaying the fetch of \"{}\" by {:?}", path, delay);
+		async_std::task::sleep(delay).await;
+	}
+	if fail {
+		return Err(Error::Chaos(format!("fetch of \"{}\"", path)));
+	}
+	Ok(())
+}
+
+#[cfg(not(feature = "chaos"))]
+pub async fn fetch(_path: &str) -> Result<()> {
+	Ok(())
+}
+
+/// Delay the execution of a hook and make it fail if drawn
+#[cfg(feature = "chaos")]
+pub fn hook(cmd: &str) -> Result<()> {
+	let (delay, fail) = draw();
+	if !delay.is_zero() {
+		log::warn!("chaos: delaying \"{}\" by {:?}", cmd, delay);
+		std::thread::sleep(delay);
+	}
+	if fail {
+		return Err(Error::Chaos(format!("execution of \"{}\"", cmd)));
+	}
+	Ok(())
+}
+
+#[cfg(not(feature = "chaos"))]
+pub fn hook(_cmd: &str) -> Result<()> {
+	Ok(())
+}
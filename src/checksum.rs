@@ -1,32 +1,185 @@
+use crate::{result::Error, state::write_private};
+
 use anyhow::Result;
 use async_std::{
-	fs::read,
+	io::ReadExt,
 	path::{Path, PathBuf},
 };
-use sha1::{Digest, Sha1};
 use std::{
 	collections::HashMap,
+	fmt,
+	fs::File,
+	io::BufReader,
 	ops::{Deref, DerefMut},
+	str::FromStr,
 };
 
-pub struct Checksums(HashMap<PathBuf, Option<Digest>>);
+/// size of the buffer used to read files
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Algorithm used to calculate the checksums of the generated files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+	Blake3,
+	Sha1,
+}
+
+/// lookup list for algorithm names
+const ALGORITHMS: &[(&str, Algorithm)] =
+	&[("blake3", Algorithm::Blake3), ("sha1", Algorithm::Sha1)];
+
+impl fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (s, algorithm) in ALGORITHMS.iter() {
+			if self == algorithm {
+				return write!(f, "{}", s);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for Algorithm {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		ALGORITHMS
+			.iter()
+			.find(|(name, _)| *name == s)
+			.map(|(_, algorithm)| *algorithm)
+			.ok_or_else(|| Error::UnknownChecksum(s.to_owned()))
+	}
+}
+
+/// Digest of a content tagged with the algorithm used to calculate it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+	algorithm: Algorithm,
+	hex: String,
+}
+
+/// Saved as algorithm:hex
+impl fmt::Display for Digest {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.algorithm, self.hex)
+	}
+}
+
+/// Parse algorithm:hex, or a bare hex sha1 digest saved by previous versions
+impl FromStr for Digest {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (algorithm, hex) = match s.split_once(':') {
+			Some((algorithm, hex)) => (algorithm.parse()?, hex),
+			None => (Algorithm::Sha1, s),
+		};
+		Ok(Digest {
+			algorithm,
+			hex: hex.to_owned(),
+		})
+	}
+}
+
+/// Incremental hasher of any algorithm
+enum Hasher {
+	Blake3(Box<blake3::Hasher>),
+	Sha1(sha1::Sha1),
+}
+
+impl Hasher {
+	fn new(algorithm: Algorithm) -> Self {
+		match algorithm {
+			Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+			Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+		}
+	}
+
+	fn update(&mut self, data: &[u8]) {
+		match self {
+			Hasher::Blake3(hasher) => {
+				hasher.update(data);
+			}
+			Hasher::Sha1(hasher) => hasher.update(data),
+		}
+	}
+
+	fn finalize(self) -> Digest {
+		match self {
+			Hasher::Blake3(hasher) => Digest {
+				algorithm: Algorithm::Blake3,
+				hex: hasher.finalize().to_hex().to_string(),
+			},
+			Hasher::Sha1(hasher) => Digest {
+				algorithm: Algorithm::Sha1,
+				hex: hasher.digest().to_string(),
+			},
+		}
+	}
+}
+
+pub struct Checksums {
+	algorithm: Algorithm,
+	digests: HashMap<PathBuf, Option<Digest>>,
+}
 
 impl Deref for Checksums {
 	type Target = HashMap<PathBuf, Option<Digest>>;
 	fn deref(&self) -> &Self::Target {
-		&self.0
+		&self.digests
 	}
 }
 
 impl DerefMut for Checksums {
 	fn deref_mut(&mut self) -> &mut Self::Target {
-		&mut self.0
+		&mut self.digests
 	}
 }
 
 impl Checksums {
-	pub fn new() -> Self {
-		Self(HashMap::<PathBuf, Option<Digest>>::new())
+	pub fn new(algorithm: Algorithm) -> Self {
+		Self {
+			algorithm,
+			digests: HashMap::<PathBuf, Option<Digest>>::new(),
+		}
+	}
+
+	/// Load checksums saved by a previous run, or return an empty map if the file doesn't exist or
+	/// can't be read
+	pub fn load(file: &std::path::Path, algorithm: Algorithm) -> Result<Self> {
+		let mut checksums = Self::new(algorithm);
+		if file.exists() {
+			// corrupted checksums are not fatal: the generated files are just considered new
+			let saved: HashMap<String, String> = File::open(file)
+				.map_err(anyhow::Error::from)
+				.and_then(|f| {
+					serde_json::from_reader(BufReader::new(f)).map_err(anyhow::Error::from)
+				})
+				.unwrap_or_else(|e| {
+					log::warn!("Ignoring invalid checksums file {:?}: {}", file, e);
+					HashMap::new()
+				});
+			for (path, digest) in saved.into_iter() {
+				// ignore digests we can't parse, they will be recalculated
+				if let Ok(digest) = digest.parse::<Digest>() {
+					checksums.insert(PathBuf::from(path), Some(digest));
+				}
+			}
+		}
+		Ok(checksums)
+	}
+
+	/// Save the checksums to be able to detect real changes after a restart
+	pub fn save(&self, file: &std::path::Path) -> Result<()> {
+		let saved: HashMap<String, String> = self
+			.iter()
+			.filter_map(|(path, digest)| {
+				digest
+					.as_ref()
+					.map(|digest| (path.to_string_lossy().into_owned(), digest.to_string()))
+			})
+			.collect();
+		write_private(file, &serde_json::to_vec(&saved)?)
 	}
 
 	/// add file digest identifies by path in the hashmap and return true if the value is new or has changed
@@ -35,12 +188,121 @@ impl Checksums {
 		T: AsRef<Path>,
 	{
 		let path = path.as_ref();
-		let digest = self.entry(path.to_owned()).or_insert(None);
-		let prev_digest = *digest;
-		let mut hasher = Sha1::default();
-		let content = read(path).await?;
-		hasher.update(&content);
-		*digest = Some(hasher.digest());
-		Ok(prev_digest != *digest)
+		let changed = match self.get(path) {
+			Some(Some(prev)) => *prev != digest_file(path, prev.algorithm).await?,
+			_ => true,
+		};
+		let new_digest = digest_file(path, self.algorithm).await?;
+		self.insert(path.to_owned(), Some(new_digest));
+		Ok(changed)
+	}
+
+	/// return the digest of a content (given in chunks) to be written at path, and true if it is new or differs
+	/// from the content previously generated there. The digest is recorded with [`Checksums::insert`] once the
+	/// file is written
+	pub fn hash_content<T>(&self, path: T, content: &[&[u8]]) -> (Digest, bool)
+	where
+		T: AsRef<Path>,
+	{
+		let new_digest = digest(content, self.algorithm);
+		// compare with the algorithm used by a previous run
+		let changed = match self.get(path.as_ref()) {
+			Some(Some(prev)) if prev.algorithm == self.algorithm => *prev != new_digest,
+			Some(Some(prev)) => *prev != digest(content, prev.algorithm),
+			_ => true,
+		};
+		(new_digest, changed)
+	}
+
+	/// return true if we generated the file with that content (given in chunks) and it still exists. The file
+	/// is not read back, modifications by someone else being detected by the tamper check
+	pub async fn is_current<T>(&self, path: T, content: &[&[u8]]) -> bool
+	where
+		T: AsRef<Path>,
+	{
+		let path = path.as_ref();
+		match self.get(path) {
+			Some(Some(prev)) => *prev == digest(content, prev.algorithm) && path.exists().await,
+			_ => false,
+		}
+	}
+
+	/// return true if we generated the file and it has been modified or removed since
+	pub async fn is_tampered<T>(&self, path: T) -> Result<bool>
+	where
+		T: AsRef<Path>,
+	{
+		let path = path.as_ref();
+		match self.get(path) {
+			Some(Some(digest)) => {
+				Ok(!path.exists().await || *digest != digest_file(path, digest.algorithm).await?)
+			}
+			_ => Ok(false),
+		}
 	}
+
+	/// return true if the file doesn't exist or is unchanged since we generated it
+	pub async fn is_owned<T>(&self, path: T) -> Result<bool>
+	where
+		T: AsRef<Path>,
+	{
+		let path = path.as_ref();
+		if !path.exists().await {
+			return Ok(true);
+		}
+		match self.get(path) {
+			Some(Some(digest)) => Ok(*digest == digest_file(path, digest.algorithm).await?),
+			_ => Ok(false),
+		}
+	}
+}
+
+/// return the digest of a content given in chunks
+fn digest(content: &[&[u8]], algorithm: Algorithm) -> Digest {
+	let mut hasher = Hasher::new(algorithm);
+	for chunk in content {
+		hasher.update(chunk);
+	}
+	hasher.finalize()
+}
+
+/// return the digest of a file content, without loading the whole file in memory
+async fn digest_file(path: &Path, algorithm: Algorithm) -> Result<Digest> {
+	let mut file = async_std::fs::File::open(path).await?;
+	let mut hasher = Hasher::new(algorithm);
+	let mut buf = vec![0u8; BUF_SIZE];
+	loop {
+		let len = file.read(&mut buf).await?;
+		if len == 0 {
+			break;
+		}
+		hasher.update(&buf[..len]);
+	}
+	Ok(hasher.finalize())
+}
+
+#[test]
+fn legacy_digest() {
+	let content: &[&[u8]] = &[b"hello ", b"world"];
+	let saved = digest(content, Algorithm::Sha1).to_string();
+	// digests saved without algorithm are sha1 digests
+	let legacy = saved
+		.strip_prefix("sha1:")
+		.unwrap()
+		.parse::<Digest>()
+		.unwrap();
+	let mut checksums = Checksums::new(Algorithm::Blake3);
+	checksums.insert(PathBuf::from("/etc/app.conf"), Some(legacy));
+	let (digest, changed) = checksums.hash_content("/etc/app.conf", content);
+	assert!(!changed);
+	assert_eq!(digest.algorithm, Algorithm::Blake3);
+}
+
+#[test]
+fn corrupted_checksums() {
+	let file = std::env::temp_dir().join(format!("rconfd-checksums-{}.json", std::process::id()));
+	std::fs::write(&file, b"{\"/etc/app.conf\": ").unwrap();
+	let checksums = Checksums::load(&file, Algorithm::Blake3).unwrap();
+	std::fs::remove_file(&file).unwrap();
+	assert!(checksums.is_empty());
 }
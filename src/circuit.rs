@@ -0,0 +1,138 @@
+use crate::{backend::Backend, message::Message};
+
+use std::convert::TryFrom;
+use vault_jwt::secret::SecretPath;
+
+/// State of the circuit breaker
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+	/// requests go through (number of consecutive failures)
+	Closed(u32),
+	/// requests are deferred
+	Open,
+	/// a single request goes through to probe vault
+	HalfOpen,
+}
+
+/// Pause the requests to vault after too many consecutive failures until a probe succeeds
+pub struct Circuit {
+	threshold: Option<u32>,
+	state: State,
+	/// messages deferred while the circuit is open
+	pending: Vec<Message>,
+}
+
+impl Circuit {
+	pub fn new(threshold: Option<u32>) -> Self {
+		Self {
+			threshold,
+			state: State::Closed(0),
+			pending: Vec::new(),
+		}
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.threshold.is_some()
+	}
+
+	/// Return true if vault is not known to be reachable
+	pub fn is_open(&self) -> bool {
+		!matches!(self.state, State::Closed(_))
+	}
+
+	/// Return true if a request can be sent to vault
+	pub fn allows(&self) -> bool {
+		self.state != State::Open
+	}
+
+	/// Keep a message to send again once the circuit is closed
+	pub fn defer(&mut self, msg: Message) {
+		self.pending.push(msg);
+	}
+
+	/// Record a successful request, returning the deferred messages if the circuit was open
+	pub fn success(&mut self) -> Option<Vec<Message>> {
+		let was_open = self.is_open();
+		self.state = State::Closed(0);
+		if was_open {
+			Some(std::mem::take(&mut self.pending))
+		} else {
+			None
+		}
+	}
+
+	/// Record a failed request, returning true if the circuit is open
+	pub fn failure(&mut self) -> bool {
+		self.state = match (self.state, self.threshold) {
+			(State::Closed(failures), Some(threshold)) if failures + 1 < threshold => {
+				State::Closed(failures + 1)
+			}
+			_ => State::Open,
+		};
+		self.state == State::Open
+	}
+
+	/// Forget the deferred messages for which keep returns false and return their number
+	pub fn retain<F>(&mut self, keep: F) -> usize
+	where
+		F: Fn(&Message) -> bool,
+	{
+		let len = self.pending.len();
+		self.pending.retain(|msg| keep(msg));
+		len - self.pending.len()
+	}
+
+	/// Let the first deferred message go through to probe vault
+	pub fn probe(&mut self) -> Option<Message> {
+		if self.pending.is_empty() {
+			self.state = State::Closed(0);
+			None
+		} else {
+			self.state = State::HalfOpen;
+			Some(self.pending.remove(0))
+		}
+	}
+}
+
+/// Return true if handling the message sends a request to vault
+pub fn is_vault(msg: &Message) -> bool {
+	match msg {
+		Message::Login(_, _) | Message::RenewToken(_, _) => true,
+		Message::GetSecret(path, _) => SecretPath::<Backend>::try_from(path.as_str())
+			.map(|secret_path| secret_path.backend == Backend::Vault)
+			.unwrap_or(false),
+		_ => false,
+	}
+}
+
+#[test]
+fn open_and_close() {
+	let mut circuit = Circuit::new(Some(2));
+	assert!(!circuit.failure());
+	assert!(circuit.allows());
+	assert!(circuit.failure());
+	assert!(!circuit.allows());
+	circuit.defer(Message::Reload);
+	assert!(matches!(circuit.probe(), Some(Message::Reload)));
+	assert!(circuit.allows() && circuit.is_open());
+	assert!(circuit.failure());
+	circuit.defer(Message::Reload);
+	circuit.probe();
+	assert_eq!(circuit.success().map(|msgs| msgs.len()), Some(0));
+	assert!(!circuit.is_open());
+}
+
+#[test]
+fn retain_deferred() {
+	let mut circuit = Circuit::new(Some(1));
+	assert!(circuit.failure());
+	circuit.defer(Message::GetSecret("vault:role:kv/old".to_owned(), true));
+	circuit.defer(Message::GetSecret("vault:role:kv/new".to_owned(), true));
+	assert_eq!(
+		circuit.retain(|msg| msg.secret() != Some("vault:role:kv/old")),
+		1
+	);
+	assert!(
+		matches!(circuit.probe(), Some(Message::GetSecret(path, _)) if path == "vault:role:kv/new")
+	);
+}
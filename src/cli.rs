@@ -0,0 +1,84 @@
+#[cfg(feature = "chaos")]
+use crate::chaos;
+#[cfg(feature = "otel")]
+use crate::telemetry;
+use crate::{
+	args::{Args, Config, SubCommand},
+	control, envfile, fixture, logger, memory, migrate, print_config,
+	redact::{self, redact},
+	result::Failure,
+	run,
+};
+
+/// Execute the rconfd command line and return the exit code of the process
+pub fn cli(args: Args) -> i32 {
+	// client mode
+	if let Some(SubCommand::Ctl(ref ctl)) = args.cmd {
+		return control::client(ctl);
+	}
+	// migration mode
+	if let Some(SubCommand::Migrate(ref migrate)) = args.cmd {
+		return migrate::run(migrate);
+	}
+	// initialize env_logger with the verbosity asked for (RUST_LOG takes precedence)
+	if let Err(e) = logger::init(&args.log_target, args.log_filter(), args.log_max_size) {
+		eprintln!("Error: {:?}", e);
+		return Failure::Config.code();
+	}
+	// variables used in config files and by the env backend
+	for env_file in args.env_file.iter() {
+		if let Err(e) = envfile::load(env_file) {
+			log::error!("{:?}", e);
+			return Failure::Config.code();
+		}
+	}
+	// resolved configuration
+	if args.print_config {
+		if let Err(e) = print_config(&args) {
+			eprintln!("Error: {:?}", e);
+			return Failure::Config.code();
+		}
+		return 0;
+	}
+	// template test mode
+	if let Some(SubCommand::Test(ref test)) = args.cmd {
+		return fixture::run(&args, test);
+	}
+	log::info!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+	redact::set_unsafe(args.unsafe_log_values);
+	if args.mlock {
+		memory::lock();
+	}
+	if args.chaos.is_some() || args.chaos_delay.is_some() {
+		#[cfg(feature = "chaos")]
+		{
+			log::warn!("Chaos mode: injecting failures and delays in secret fetches and hooks");
+			chaos::init(args.chaos.unwrap_or(0), args.chaos_delay);
+		}
+		#[cfg(not(feature = "chaos"))]
+		log::warn!("Ignoring chaos options: rconfd was built without the chaos feature");
+	}
+	if let Some(ref endpoint) = args.otlp_endpoint {
+		#[cfg(feature = "otel")]
+		if let Err(e) = telemetry::init(endpoint) {
+			log::error!("{:?}", e);
+		}
+		#[cfg(not(feature = "otel"))]
+		log::warn!(
+			"Ignoring OTLP endpoint {}: rconfd was built without the otel feature",
+			endpoint
+		);
+	}
+
+	let res = async_std::task::block_on(run(&Config::from(args)));
+	#[cfg(feature = "otel")]
+	telemetry::shutdown();
+	match res {
+		Ok(()) => 0,
+		Err(e) => {
+			eprintln!("Error: {}", redact(&format!("{:?}", e)));
+			// exit with a code depending on the class of failure
+			Failure::of(&e).map(|f| f.code()).unwrap_or(1)
+		}
+	}
+}
@@ -0,0 +1,103 @@
+use crate::{backend::Backend, kv::KV_KWARG};
+
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs};
+use vault_jwt::{client::VaultClient, secret::SecretPath};
+
+/// name of the secret parameter selecting the vault cluster
+const URL_KWARG: &str = "url";
+
+/// name of the secret parameter selecting the file containing the JWT token to log in with
+const JWT_KWARG: &str = "jwt";
+
+/// Vault cluster and JWT token a secret is fetched with (the default ones if None)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Session {
+	/// url of the cluster
+	pub url: Option<String>,
+	/// path of the file containing the JWT token
+	pub token_path: Option<String>,
+}
+
+/// Vault clients, each keeping its own sessions, by cluster url and token file
+pub struct Clients {
+	/// url of the default cluster
+	url: String,
+	login_path: String,
+	jwt: String,
+	cacert: String,
+	clients: HashMap<Session, VaultClient>,
+}
+
+impl Clients {
+	pub fn new(url: &str, login_path: &str, jwt: &str, cacert: &str) -> Result<Self> {
+		let mut clients = HashMap::new();
+		clients.insert(
+			Session::default(),
+			VaultClient::new(url, login_path, jwt, Some(cacert))?,
+		);
+		Ok(Self {
+			url: url.to_owned(),
+			login_path: login_path.to_owned(),
+			jwt: jwt.to_owned(),
+			cacert: cacert.to_owned(),
+			clients,
+		})
+	}
+
+	/// Return the client of a session, creating it if necessary
+	pub fn get(&mut self, session: &Session) -> Result<&mut VaultClient> {
+		if !self.clients.contains_key(session) {
+			let url = session.url.as_deref().unwrap_or(&self.url);
+			let jwt = match session.token_path {
+				Some(ref token_path) => fs::read_to_string(token_path)
+					.with_context(|| format!("Reading {}", token_path))?
+					.trim()
+					.to_owned(),
+				None => self.jwt.clone(),
+			};
+			let client = VaultClient::new(url, &self.login_path, &jwt, Some(&self.cacert))
+				.with_context(|| format!("Creating a client for vault server {}", url))?;
+			self.clients.insert(session.clone(), client);
+		}
+		Ok(self.clients.get_mut(session).unwrap())
+	}
+}
+
+/// return the value of a secret parameter
+pub fn kwarg(secret_path: &SecretPath<Backend>, name: &str) -> Option<String> {
+	secret_path
+		.kwargs
+		.as_ref()?
+		.iter()
+		.find_map(|(key, value)| {
+			if *key == name {
+				Some((*value).to_owned())
+			} else {
+				None
+			}
+		})
+}
+
+/// Return the cluster and the JWT token a vault secret is fetched with
+pub fn session(secret_path: &SecretPath<Backend>) -> Session {
+	Session {
+		url: kwarg(secret_path, URL_KWARG),
+		token_path: kwarg(secret_path, JWT_KWARG),
+	}
+}
+
+/// Return the parameters of a vault secret to send to the server
+pub fn params<'a>(secret_path: &SecretPath<'a, Backend>) -> Option<Vec<(&'a str, &'a str)>> {
+	secret_path
+		.kwargs
+		.as_ref()
+		.map(|kwargs| {
+			kwargs
+				.iter()
+				.filter(|(key, _)| *key != URL_KWARG && *key != JWT_KWARG && *key != KV_KWARG)
+				.copied()
+				.collect::<Vec<_>>()
+		})
+		.filter(|kwargs| !kwargs.is_empty())
+}
@@ -1,12 +1,16 @@
-use crate::{message::Message, secrets::Secrets, subst::subst_envar};
+use crate::{
+	chaos, exec::ExePolicy, message::Message, redact::redact, result::Error, rooted,
+	secrets::Secrets, subst::subst_envar, swap::Swap, validate::Format,
+};
 
 use anyhow::{Context, Result};
 use async_std::channel::Sender;
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
-	collections::HashMap,
+	collections::{BTreeMap, HashMap, HashSet},
 	fmt,
 	fs::{self, File},
+	iter::FromIterator,
 	ops::{Deref, DerefMut},
 	path::{Path, PathBuf},
 	process::Command,
@@ -14,6 +18,15 @@ use std::{
 
 pub struct TemplateConfs(HashMap<String, TemplateConf>);
 
+/// Arbitrary key/value pairs attached to a template or a secret
+pub type Labels = BTreeMap<String, String>;
+
+/// prefix of the environment variables passing the labels to hooks
+const LABEL_ENV_PREFIX: &str = "RCONFD_LABEL_";
+
+/// argument of the hooks replaced by the paths of the files written but not live yet
+const STAGED_PLACEHOLDER: &str = "%staged%";
+
 impl Deref for TemplateConfs {
 	type Target = HashMap<String, TemplateConf>;
 	fn deref(&self) -> &Self::Target {
@@ -96,22 +109,95 @@ impl TemplateConfs {
 		}
 		Ok(())
 	}
+
+	/// Return the labels declared for a secret (path) by the templates using it
+	pub fn secret_labels(&self, path: &str) -> Labels {
+		self.values()
+			.filter_map(|conf| {
+				conf.secrets
+					.get(path)
+					.and_then(|name| conf.secret_labels.get(name))
+			})
+			.flatten()
+			.map(|(key, value)| (key.clone(), value.clone()))
+			.collect()
+	}
+
+	/// Return the index of the phase of a template in phases (phases.len() for templates without phase)
+	pub fn phase(&self, tmpl: &str, phases: &[String]) -> usize {
+		self.get(tmpl)
+			.and_then(|conf| conf.phase.as_ref())
+			.and_then(|phase| phases.iter().position(|p| p == phase))
+			.unwrap_or(phases.len())
+	}
+
+	/// Return the templates of a phase sorted by name
+	pub fn in_phase(&self, phase: usize, phases: &[String]) -> Vec<(&String, &TemplateConf)> {
+		let mut confs = self
+			.iter()
+			.filter(|(tmpl, _)| self.phase(tmpl, phases) == phase)
+			.collect::<Vec<_>>();
+		confs.sort_by_key(|(tmpl, _)| tmpl.as_str());
+		confs
+	}
+
+	/// Return the critical templates not generated yet
+	pub fn critical_pending<T>(
+		&self,
+		generated: &HashMap<String, T>,
+		failed: &HashSet<String>,
+	) -> Vec<&str> {
+		self.iter()
+			.filter(|(tmpl, conf)| {
+				conf.critical && !generated.contains_key(*tmpl) && !failed.contains(*tmpl)
+			})
+			.map(|(tmpl, _)| tmpl.as_str())
+			.collect()
+	}
+
+	/// Return the files required by the templates (the critical ones only if critical) before signaling readiness
+	/// that don't exist yet
+	pub fn missing_files(&self, critical: bool) -> Vec<&str> {
+		self.values()
+			.filter(|conf| conf.critical || !critical)
+			.flat_map(|conf| conf.wait_for.iter())
+			.filter(|file| !Path::new(file).exists())
+			.map(|file| file.as_str())
+			.collect()
+	}
 }
 
 /// Define a template job
 type Conf = HashMap<String, TemplateConf>;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Hooks {
 	/// executed whenever some files have been modified
+	#[serde(default, deserialize_with = "option_envar")]
 	pub modified: Option<String>,
 	/// executed right after the first manifestation
+	#[serde(default, deserialize_with = "option_envar")]
 	pub ready: Option<String>,
+	/// executed inside the shadow directory before the files are written to their destinations
+	#[serde(default, deserialize_with = "option_envar")]
+	pub validate: Option<String>,
+	/// executed when generated files have been modified by someone else
+	#[serde(default, deserialize_with = "option_envar")]
+	pub tampered: Option<String>,
+	/// executed in daemon mode when the template is still not generated at the readiness timeout
+	#[serde(default, deserialize_with = "option_envar")]
+	pub error: Option<String>,
+	/// don't execute the modified hook on the first manifestation of the template
+	#[serde(default)]
+	pub skip_first_run: bool,
 }
 
 pub enum HookType {
 	Modified,
 	Ready,
+	Validate,
+	Tampered,
+	Error,
 }
 
 impl fmt::Display for HookType {
@@ -119,55 +205,351 @@ impl fmt::Display for HookType {
 		match self {
 			HookType::Modified => write!(f, "modified"),
 			HookType::Ready => write!(f, "ready"),
+			HookType::Validate => write!(f, "validate"),
+			HookType::Tampered => write!(f, "tampered"),
+			HookType::Error => write!(f, "error"),
 		}
 	}
 }
 
 impl Hooks {
-	pub fn trigger(&self, hook_type: HookType) {
+	/// Execute the hook of the given type if defined and return an error if it failed. The hook
+	/// can write to the writable paths only if sandboxed
+	pub fn trigger(
+		&self,
+		hook_type: HookType,
+		policy: &ExePolicy,
+		writable: &[&str],
+		template: &TemplateConf,
+	) -> Result<()> {
+		self.execute(hook_type, policy, writable, None, &[], template)
+	}
+
+	/// Execute the validate hook if defined inside the directory where the files are staged (shadow or
+	/// swapped one) and return an error if it failed. %staged% is replaced by the staged files
+	pub fn validate(
+		&self,
+		policy: &ExePolicy,
+		dir: &str,
+		staged: &[&str],
+		template: &TemplateConf,
+	) -> Result<()> {
+		self.execute(
+			HookType::Validate,
+			policy,
+			&[dir],
+			Some(dir),
+			staged,
+			template,
+		)
+	}
+
+	fn execute(
+		&self,
+		hook_type: HookType,
+		policy: &ExePolicy,
+		writable: &[&str],
+		cwd: Option<&str>,
+		staged: &[&str],
+		template: &TemplateConf,
+	) -> Result<()> {
 		let hook = match hook_type {
 			HookType::Modified => &self.modified,
 			HookType::Ready => &self.ready,
+			HookType::Validate => &self.validate,
+			HookType::Tampered => &self.tampered,
+			HookType::Error => &self.error,
 		};
 		if let Some(ref cmd_str) = hook {
-			let args: Vec<&str> = cmd_str.split_whitespace().collect();
+			let _span = tracing::info_span!("hook", hook = %hook_type).entered();
+			let args: Vec<&str> = cmd_str
+				.split_whitespace()
+				.flat_map(|arg| {
+					if arg == STAGED_PLACEHOLDER {
+						staged.to_vec()
+					} else {
+						vec![arg]
+					}
+				})
+				.collect();
 			if !args.is_empty() {
 				// enforce absolute exec path for security reason
-				if args[0].starts_with('/') {
-					let mut cmd = Command::new(&args[0]);
-					if args.len() > 1 {
-						cmd.args(&args[1..]);
-					}
-					log::info!("hook {} trigerred. Executing \"{}\"", hook_type, cmd_str);
-					let res = cmd.output();
-					if res.is_err() {
-						log::error!("Executing \"{}\"", cmd_str);
+				if !args[0].starts_with('/') {
+					let err =
+						Error::RelativePath(format!("{} hook", hook_type), args[0].to_owned());
+					// a validation that can't be executed must not pass
+					if matches!(hook_type, HookType::Validate) {
+						return Err(err.into());
 					}
-				} else {
-					log::error!(
-						"cmd \"{}\" must be absolute and start with / to be executed",
-						cmd_str
-					);
+					log::error!("{}", err);
+					return Ok(());
+				}
+				policy.check(args[0])?;
+				chaos::hook(cmd_str)?;
+				let mut cmd = Command::new(&args[0]);
+				if args.len() > 1 {
+					cmd.args(&args[1..]);
+				}
+				template.prepare(&mut cmd);
+				if let Some(cwd) = cwd {
+					cmd.current_dir(cwd);
+				}
+				policy
+					.sandbox(&mut cmd, writable)
+					.with_context(|| format!("Sandboxing \"{}\"", cmd_str))?;
+				log::info!("hook {} trigerred. Executing \"{}\"", hook_type, cmd_str);
+				let output = cmd
+					.output()
+					.with_context(|| format!("Executing \"{}\"", cmd_str))?;
+				if !output.status.success() {
+					return Err(Error::Cmd(
+						cmd_str.to_owned(),
+						output.status.code().unwrap_or(1),
+						redact(&String::from_utf8_lossy(&output.stderr)),
+					)
+					.into());
 				}
 			}
 		}
+		Ok(())
 	}
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TemplateConf {
 	/// basedir for config files with relative path in jsonnet template
 	#[serde(deserialize_with = "string_envar")]
 	pub dir: String,
 	/// mode of resulting files
+	#[serde(deserialize_with = "string_envar")]
 	pub mode: String,
+	/// mode of created directories
+	#[serde(default, deserialize_with = "option_envar")]
+	pub dir_mode: Option<String>,
 	/// owner of resulting files
+	#[serde(deserialize_with = "string_envar")]
 	pub user: String,
 	/// secrets to inject in the jsonnet engine as "secrets" extVar
 	#[serde(deserialize_with = "key_envar")]
 	pub secrets: HashMap<String, String>,
+	/// secrets (by name) deep merged in order into a new "secrets" extVar entry
+	#[serde(default, deserialize_with = "key_list_envar")]
+	pub merge: HashMap<String, Vec<String>>,
 	/// hooks to execute commands on events
 	pub hooks: Hooks,
+	/// SELinux context of resulting files
+	#[serde(default, deserialize_with = "option_envar")]
+	pub selinux_context: Option<String>,
+	/// extended attributes of resulting files
+	#[serde(default, deserialize_with = "key_envar")]
+	pub xattrs: HashMap<String, String>,
+	/// ACL entries of resulting files
+	#[serde(default, deserialize_with = "list_envar")]
+	pub acl: Vec<String>,
+	/// encrypt resulting files
+	#[serde(default)]
+	pub encrypt: Option<Encrypt>,
+	/// maximum size (bytes) of all the resulting files together
+	#[serde(default)]
+	pub max_size: Option<u64>,
+	/// how manifested strings are written to the resulting files
+	#[serde(default)]
+	pub output: Output,
+	/// syntax of resulting files checked before writing them (by root key or *.extension)
+	#[serde(default)]
+	pub formats: HashMap<String, Format>,
+	/// files that must exist before signaling readiness (produced by hooks or other containers)
+	#[serde(default, deserialize_with = "list_envar")]
+	pub wait_for: Vec<String>,
+	/// additional directories where to write the same resulting files
+	#[serde(default)]
+	pub targets: Vec<Target>,
+	/// directory where the resulting files are written and validated before being written to their destinations
+	#[serde(default, deserialize_with = "option_envar")]
+	pub shadow: Option<String>,
+	/// swap the resulting files of each destination into place together
+	#[serde(default)]
+	pub swap: Option<Swap>,
+	/// regenerate the resulting files modified by someone else (with --tamper-check)
+	#[serde(default)]
+	pub restore: bool,
+	/// files generated by other templates to inject by name in the jsonnet engine as "files" extVar
+	#[serde(default, deserialize_with = "key_envar")]
+	pub files: HashMap<String, String>,
+	/// keep the owner, group and mode of the files being replaced instead of applying user and mode
+	#[serde(default)]
+	pub inherit_owner: bool,
+	/// environment variables of the hooks and exe secrets of the template
+	#[serde(default, deserialize_with = "key_envar")]
+	pub env: HashMap<String, String>,
+	/// working directory of the hooks and exe secrets of the template
+	#[serde(default, deserialize_with = "option_envar")]
+	pub cwd: Option<String>,
+	/// labels attached to the logs, traces, audit entries and hooks of the template
+	#[serde(default, deserialize_with = "key_envar")]
+	pub labels: Labels,
+	/// labels attached to the logs, traces and audit entries of secrets (by name)
+	#[serde(default)]
+	pub secret_labels: HashMap<String, Labels>,
+	/// phase (declared with --phase) the template is generated in
+	#[serde(default, deserialize_with = "option_envar")]
+	pub phase: Option<String>,
+	/// readiness waits for the template to be generated
+	#[serde(default = "critical")]
+	pub critical: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Target {
+	/// basedir for config files with relative path in jsonnet template
+	#[serde(deserialize_with = "string_envar")]
+	pub dir: String,
+	/// mode of resulting files (mode of the template if not defined)
+	#[serde(default, deserialize_with = "option_envar")]
+	pub mode: Option<String>,
+	/// mode of created directories (dir_mode of the template if not defined)
+	#[serde(default, deserialize_with = "option_envar")]
+	pub dir_mode: Option<String>,
+	/// owner of resulting files (user of the template if not defined)
+	#[serde(default, deserialize_with = "option_envar")]
+	pub user: Option<String>,
+}
+
+/// Directory where the resulting files of a template are written, with their mode and owner
+pub struct Destination<'a> {
+	pub dir: &'a str,
+	pub mode: &'a str,
+	pub dir_mode: Option<&'a str>,
+	pub user: &'a str,
+}
+
+impl TemplateConf {
+	/// Return dir followed by the additional targets, with the settings of the template by default
+	pub fn destinations(&self) -> Vec<Destination<'_>> {
+		let dest = Destination {
+			dir: &self.dir,
+			mode: &self.mode,
+			dir_mode: self.dir_mode.as_deref(),
+			user: &self.user,
+		};
+		let targets = self.targets.iter().map(|target| Destination {
+			dir: &target.dir,
+			mode: target.mode.as_deref().unwrap_or(&self.mode),
+			dir_mode: target
+				.dir_mode
+				.as_deref()
+				.or_else(|| self.dir_mode.as_deref()),
+			user: target.user.as_deref().unwrap_or(&self.user),
+		});
+		std::iter::once(dest).chain(targets).collect()
+	}
+
+	/// Set the environment (variables and labels) and the working directory of a command executed for the
+	/// template
+	pub fn prepare(&self, cmd: &mut Command) {
+		cmd.envs(self.env.iter());
+		for (key, value) in self.labels.iter() {
+			cmd.env(label_env(key), value);
+		}
+		if let Some(ref cwd) = self.cwd {
+			cmd.current_dir(cwd);
+		}
+	}
+
+	/// Return the path of an embedded file among the files written by the templates. Absolute paths and
+	/// paths relative to a literal dir are moved inside root, paths relative to an interpolated dir match
+	/// the written files ending with them
+	fn input_path<'a>(
+		&self,
+		file: &str,
+		root: Option<&str>,
+		written: &[&'a Path],
+	) -> Option<&'a Path> {
+		let input = Path::new(file);
+		let expected = if input.is_absolute() {
+			Some(rooted(root, input.to_path_buf()))
+		} else if !self.dir.contains("%{") {
+			Some(rooted(root, Path::new(&self.dir).join(input)))
+		} else {
+			None
+		};
+		written.iter().copied().find(|path| match expected {
+			Some(ref expected) => path == expected,
+			None => path.ends_with(input),
+		})
+	}
+
+	/// Return the embedded files not written yet by other templates
+	pub fn missing_inputs(&self, root: Option<&str>, written: &[&Path]) -> Vec<&str> {
+		self.files
+			.values()
+			.filter(|file| self.input_path(file, root, written).is_none())
+			.map(String::as_str)
+			.collect()
+	}
+
+	/// Return true if the template embeds one of the given files
+	pub fn depends_on(&self, root: Option<&str>, changed: &[&Path]) -> bool {
+		self.files
+			.values()
+			.any(|file| self.input_path(file, root, changed).is_some())
+	}
+
+	/// Return the content of the files generated by other templates by name. Files which are not valid
+	/// UTF-8 (ex: written from a base64 output) are given encoded in base64
+	pub fn read_inputs(
+		&self,
+		root: Option<&str>,
+		written: &[&Path],
+	) -> Result<serde_json::Map<String, serde_json::Value>> {
+		let mut files = serde_json::Map::with_capacity(self.files.len());
+		for (name, file) in self.files.iter() {
+			let path = self
+				.input_path(file, root, written)
+				.with_context(|| format!("{} was not generated by any template", file))?;
+			let content = fs::read(path).with_context(|| format!("Reading {:?}", path))?;
+			let content =
+				String::from_utf8(content).unwrap_or_else(|e| base64::encode(e.as_bytes()));
+			files.insert(name.clone(), serde_json::Value::String(content));
+		}
+		Ok(files)
+	}
+
+	/// Return the format declared for a root key of the template, by name or by extension (*.ext)
+	pub fn format(&self, file: &str) -> Option<Format> {
+		self.formats.get(file).copied().or_else(|| {
+			self.formats.iter().find_map(|(pattern, format)| {
+				pattern
+					.strip_prefix('*')
+					.filter(|ext| file.ends_with(ext))
+					.map(|_| *format)
+			})
+		})
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+	/// string followed by a newline
+	Text,
+	/// string as is
+	Raw,
+	/// bytes encoded in base64 (ex: std.base64 of DH params or keytabs)
+	Base64,
+}
+
+impl Default for Output {
+	fn default() -> Self {
+		Output::Text
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Encrypt {
+	/// age recipients (public keys)
+	#[serde(default, deserialize_with = "list_envar")]
+	pub age: Vec<String>,
 }
 
 /// Substitute environement variables in a string
@@ -180,17 +562,78 @@ where
 	subst_envar(&s).map_err(de::Error::custom)
 }
 
-/// Substitute environement variables in the keys (path) of secrets hashmaps before serializing
-fn key_envar<'a, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+/// Return labels as a comma separated list of key=value
+pub fn fmt_labels(labels: &Labels) -> String {
+	labels
+		.iter()
+		.map(|(key, value)| format!("{}={}", key, value))
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+/// Return the name of the environment variable passing a label to hooks
+fn label_env(key: &str) -> String {
+	let key: String = key
+		.chars()
+		.map(|c| {
+			if c.is_ascii_alphanumeric() {
+				c.to_ascii_uppercase()
+			} else {
+				'_'
+			}
+		})
+		.collect();
+	format!("{}{}", LABEL_ENV_PREFIX, key)
+}
+
+/// Templates are critical by default
+fn critical() -> bool {
+	true
+}
+
+/// Substitute environement variables in an optional string
+fn option_envar<'a, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+	D: Deserializer<'a>,
+{
+	Option::<String>::deserialize(deserializer)?
+		.map(|s| subst_envar(&s).map_err(de::Error::custom))
+		.transpose()
+}
+
+/// new type to be able to define a specific deserialize_with function to apply upon
+#[derive(Deserialize, PartialEq, Eq, Hash)]
+struct Envar(#[serde(deserialize_with = "string_envar")] String);
+
+/// Substitute environement variables in the keys (path) and values (name) of secrets hashmaps before serializing
+fn key_envar<'a, D, M>(deserializer: D) -> Result<M, D::Error>
 where
 	D: Deserializer<'a>,
+	M: FromIterator<(String, String)>,
 {
-	// new type to be able to define a specific deserialize_with function to apply upon
-	#[derive(Deserialize, PartialEq, Eq, Hash)]
-	struct Wrapper(#[serde(deserialize_with = "string_envar")] String);
+	let v = HashMap::<Envar, Envar>::deserialize(deserializer)?;
+	Ok(v.into_iter().map(|(Envar(k), Envar(v))| (k, v)).collect())
+}
+
+/// Substitute environement variables in the items of a list before serializing
+fn list_envar<'a, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+	D: Deserializer<'a>,
+{
+	let v = Vec::<Envar>::deserialize(deserializer)?;
+	Ok(v.into_iter().map(|Envar(s)| s).collect())
+}
 
-	let v = HashMap::<Wrapper, String>::deserialize(deserializer)?;
-	Ok(v.into_iter().map(|(Wrapper(k), v)| (k, v)).collect())
+/// Substitute environement variables in the keys and the items of the values of a hashmap of lists before
+/// serializing
+fn key_list_envar<'a, D>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error>
+where
+	D: Deserializer<'a>,
+{
+	let v = HashMap::<Envar, Vec<Envar>>::deserialize(deserializer)?;
+	Ok(v.into_iter()
+		.map(|(Envar(k), v)| (k, v.into_iter().map(|Envar(s)| s).collect()))
+		.collect())
 }
 
 /// parse json to conf
@@ -199,14 +642,21 @@ pub fn parse_config(file: &Path) -> Result<Conf> {
 	Ok(serde_json::from_reader::<File, Conf>(reader)?)
 }
 
-/// Return the list of config files names inside dir
-/// TODO: use generics to return iterator
-pub fn config_files(dir: &str) -> Result<Vec<PathBuf>> {
-	fs::read_dir(dir)
-		.with_context(|| format!("Browsing config dir {}", dir))?
-		.map(|r| r.map_err(anyhow::Error::from).map(|d| d.path()))
-		.filter(|r| r.is_ok() && is_conffile(r.as_deref().unwrap()))
-		.collect()
+/// Return the list of config files inside dirs sorted by name, a file replacing the files with the same
+/// name inside the previous dirs
+pub fn config_files(dirs: &[&str]) -> Result<Vec<PathBuf>> {
+	let mut files = BTreeMap::new();
+	for dir in dirs.iter() {
+		for entry in fs::read_dir(dir).with_context(|| format!("Browsing config dir {}", dir))? {
+			let path = entry?.path();
+			if is_conffile(&path) {
+				if let Some(name) = path.file_name() {
+					files.insert(name.to_owned(), path.clone());
+				}
+			}
+		}
+	}
+	Ok(files.into_values().collect())
 }
 
 /// must be a regular file and have .json extension
@@ -222,3 +672,54 @@ where
 			false
 		}
 }
+
+#[test]
+fn label_env_name() {
+	assert_eq!(
+		label_env("app.kubernetes.io/team"),
+		"RCONFD_LABEL_APP_KUBERNETES_IO_TEAM"
+	);
+}
+
+#[test]
+fn input_paths() {
+	let conf: TemplateConf = serde_json::from_value(serde_json::json!({
+		"dir": "/etc/app",
+		"mode": "0644",
+		"user": "root",
+		"secrets": {},
+		"hooks": {},
+		"files": {"ca": "certs/ca.pem", "key": "/etc/ssl/key.pem"}
+	}))
+	.unwrap();
+	let written = [
+		Path::new("/root/etc/app/certs/ca.pem"),
+		Path::new("/etc/ssl/key.pem"),
+	];
+	assert_eq!(conf.missing_inputs(None, &written), vec!["certs/ca.pem"]);
+	assert_eq!(
+		conf.missing_inputs(Some("/root"), &written),
+		vec!["/etc/ssl/key.pem"]
+	);
+	assert!(conf.depends_on(Some("/root"), &written[..1]));
+	assert!(!conf.depends_on(None, &written[..1]));
+}
+
+#[test]
+fn envar_fields() {
+	std::env::set_var("RCONFD_TEST_TEAM", "infra");
+	let conf: TemplateConf = serde_json::from_value(serde_json::json!({
+		"dir": "/etc/app",
+		"mode": "0644",
+		"user": "root",
+		"secrets": {},
+		"hooks": {},
+		"acl": ["group:${RCONFD_TEST_TEAM}:r"],
+		"merge": {"all": ["${RCONFD_TEST_TEAM}"]},
+		"labels": {"team": "${RCONFD_TEST_TEAM}"}
+	}))
+	.unwrap();
+	assert_eq!(conf.acl, vec!["group:infra:r"]);
+	assert_eq!(conf.merge["all"], vec!["infra"]);
+	assert_eq!(conf.labels["team"], "infra");
+}
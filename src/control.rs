@@ -0,0 +1,111 @@
+use crate::{args::CtlArgs, message::Message, status::Status};
+
+use anyhow::{Context, Result};
+use async_std::{
+	channel::Sender,
+	io::{prelude::BufReadExt, BufReader, WriteExt},
+	os::unix::net::{UnixListener, UnixStream},
+	stream::StreamExt,
+	sync::Arc,
+	task,
+};
+use std::{
+	fs,
+	io::{Read, Write},
+	os::unix::{
+		fs::{FileTypeExt, PermissionsExt},
+		net,
+	},
+};
+
+/// Bind the control socket at path and serve the commands in the background
+pub async fn serve_control(path: &str, sender: Sender<Message>, status: Arc<Status>) -> Result<()> {
+	// remove a socket left by a previous instance, but never another kind of file
+	if let Ok(metadata) = fs::symlink_metadata(path) {
+		if !metadata.file_type().is_socket() {
+			anyhow::bail!("Not removing {}: not a socket", path);
+		}
+		fs::remove_file(path).with_context(|| format!("Removing {}", path))?;
+	}
+	let listener = UnixListener::bind(path)
+		.await
+		.with_context(|| format!("Listening on {}", path))?;
+	// only the owner can send commands
+	fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+		.with_context(|| format!("Changing permissions of {}", path))?;
+	log::info!("Accepting commands on {}", path);
+	task::spawn(async move {
+		let mut incoming = listener.incoming();
+		while let Some(stream) = incoming.next().await {
+			match stream {
+				Ok(stream) => {
+					let (sender, status) = (sender.clone(), status.clone());
+					task::spawn(async move {
+						if let Err(e) = handle(stream, &sender, &status).await {
+							log::error!("control socket: {}", e);
+						}
+					});
+				}
+				Err(e) => log::error!("control socket: {}", e),
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Execute a command and return the response
+pub async fn execute(command: &str, sender: &Sender<Message>, status: &Status) -> Result<String> {
+	let mut words = command.split_whitespace();
+	let msg = match (words.next(), words.next()) {
+		(Some("status"), None) => return Ok(serde_json::to_string(&status.to_json())?),
+		(Some("reload"), None) => Message::Reload,
+		(Some("render"), Some(tmpl)) => Message::GenerateTemplate(tmpl.to_owned()),
+		(Some("refresh"), Some(path)) => Message::Refresh(path.to_owned()),
+		_ => {
+			return Ok(format!(
+				"error: unknown command \"{}\" (status, reload, render <template>, refresh <secret>)",
+				command.trim()
+			))
+		}
+	};
+	sender.send(msg).await?;
+	Ok("ok".to_owned())
+}
+
+/// Read a command line and write the response
+async fn handle(stream: UnixStream, sender: &Sender<Message>, status: &Status) -> Result<()> {
+	let mut line = String::new();
+	BufReader::new(&stream).read_line(&mut line).await?;
+	log::info!("control command \"{}\"", line.trim());
+	let response = execute(&line, sender, status).await?;
+	let mut writer = &stream;
+	writer.write_all(response.as_bytes()).await?;
+	writer.write_all(b"\n").await?;
+	Ok(())
+}
+
+/// Send a command to a running rconfd and print the response. Return the process exit code
+pub fn client(ctl: &CtlArgs) -> i32 {
+	let res = (|| -> Result<String> {
+		let mut stream = net::UnixStream::connect(&ctl.socket)
+			.with_context(|| format!("Connecting to {}", &ctl.socket))?;
+		writeln!(stream, "{}", ctl.command.join(" "))?;
+		let mut response = String::new();
+		stream.read_to_string(&mut response)?;
+		Ok(response)
+	})();
+	match res {
+		Ok(response) => {
+			print!("{}", response);
+			if response.starts_with("error") {
+				1
+			} else {
+				0
+			}
+		}
+		Err(e) => {
+			eprintln!("Error: {:?}", e);
+			1
+		}
+	}
+}
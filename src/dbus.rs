@@ -0,0 +1,79 @@
+use crate::message::Message;
+
+use anyhow::{Context, Result};
+use async_std::channel::Sender;
+use zbus::{dbus_interface, Connection, ConnectionBuilder, SignalContext};
+
+/// well-known name and object path of the service
+const NAME: &str = "org.rconfd.Manager";
+const PATH: &str = "/org/rconfd/Manager";
+
+/// D-Bus object forwarding the method calls to the main loop
+struct Manager {
+	sender: Sender<Message>,
+}
+
+impl Manager {
+	async fn send(&self, msg: Message) -> zbus::fdo::Result<()> {
+		self.sender
+			.send(msg)
+			.await
+			.map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+	}
+}
+
+#[dbus_interface(name = "org.rconfd.Manager")]
+impl Manager {
+	/// Parse the config files again
+	async fn reload(&self) -> zbus::fdo::Result<()> {
+		self.send(Message::Reload).await
+	}
+
+	/// Regenerate a template
+	async fn render(&self, template: String) -> zbus::fdo::Result<()> {
+		self.send(Message::GenerateTemplate(template)).await
+	}
+
+	/// Files generated by a template have changed
+	#[dbus_interface(signal)]
+	async fn files_changed(
+		ctxt: &SignalContext<'_>,
+		template: &str,
+		files: Vec<String>,
+	) -> zbus::Result<()>;
+}
+
+/// Connection to the bus serving the manager object
+pub struct DBus {
+	conn: Connection,
+}
+
+impl DBus {
+	/// Register the service on the system or session bus
+	pub async fn new(bus: &str, sender: Sender<Message>) -> Result<Self> {
+		let builder = match bus {
+			"system" => ConnectionBuilder::system()?,
+			"session" => ConnectionBuilder::session()?,
+			_ => anyhow::bail!("unknown bus \"{}\" (system or session)", bus),
+		};
+		let conn = builder
+			.name(NAME)?
+			.serve_at(PATH, Manager { sender })?
+			.build()
+			.await
+			.with_context(|| format!("Registering {} on the {} bus", NAME, bus))?;
+		log::info!("Serving {} on the {} bus", NAME, bus);
+		Ok(Self { conn })
+	}
+
+	/// Emit the FilesChanged signal (a failure is only logged)
+	pub async fn files_changed(&self, template: &str, files: Vec<String>) {
+		let res = match SignalContext::new(&self.conn, PATH) {
+			Ok(ctxt) => Manager::files_changed(&ctxt, template, files).await,
+			Err(e) => Err(e),
+		};
+		if let Err(e) = res {
+			log::error!("Emitting FilesChanged signal: {}", e);
+		}
+	}
+}
@@ -0,0 +1,32 @@
+use crate::result::{Error, Result};
+
+use async_std_resolver::{resolver, ResolveError};
+use trust_dns_resolver::system_conf::read_system_conf;
+
+/// Resolve the TXT records of name with the system resolver configuration, validating the answers
+/// with DNSSEC if asked. The records are sorted and joined with a new line
+pub async fn txt(name: &str, dnssec: bool) -> Result<String> {
+	let dns_err = |e: ResolveError| Error::Dns(name.to_owned(), e.to_string());
+	if dnssec && !cfg!(feature = "dnssec") {
+		return Err(Error::Dns(
+			name.to_owned(),
+			"rconfd was built without the dnssec feature".to_owned(),
+		));
+	}
+	let (config, mut opts) = read_system_conf().map_err(dns_err)?;
+	opts.validate = dnssec;
+	let resolver = resolver(config, opts).await.map_err(dns_err)?;
+	let lookup = resolver.txt_lookup(name).await.map_err(dns_err)?;
+	// a record can be split in several character strings of at most 255 bytes
+	let mut records = lookup
+		.iter()
+		.map(|txt| {
+			txt.txt_data()
+				.iter()
+				.map(|data| String::from_utf8_lossy(data))
+				.collect::<String>()
+		})
+		.collect::<Vec<_>>();
+	records.sort();
+	Ok(records.join("\n"))
+}
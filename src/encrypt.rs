@@ -0,0 +1,37 @@
+use crate::result::{Error, Result};
+
+use std::io::Write;
+
+/// Encrypt content (given in chunks) for the given age recipients (x25519 public keys) directly
+/// into output
+#[cfg(feature = "age")]
+pub fn age<W: Write>(recipients: &[String], output: W, content: &[&[u8]]) -> Result<()> {
+	let recipients = recipients
+		.iter()
+		.map(|recipient| {
+			recipient
+				.parse::<age::x25519::Recipient>()
+				.map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+				.map_err(|e| Error::Encrypt(format!("invalid recipient {}: {}", recipient, e)))
+		})
+		.collect::<Result<Vec<_>>>()?;
+	let encryptor = age::Encryptor::with_recipients(recipients)
+		.ok_or_else(|| Error::Encrypt("no age recipient".to_owned()))?;
+	let mut writer = encryptor
+		.wrap_output(output)
+		.map_err(|e| Error::Encrypt(e.to_string()))?;
+	content
+		.iter()
+		.try_for_each(|chunk| writer.write_all(chunk))
+		.and_then(|_| writer.finish())
+		.map_err(|e| Error::Encrypt(e.to_string()))?;
+	Ok(())
+}
+
+/// Never write in clear what should have been encrypted
+#[cfg(not(feature = "age"))]
+pub fn age<W: Write>(_recipients: &[String], _output: W, _content: &[&[u8]]) -> Result<()> {
+	Err(Error::Encrypt(
+		"rconfd was built without the age feature".to_owned(),
+	))
+}
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::{env, fs};
+
+/// Parse a line of an env file (KEY=value, optionally prefixed by export and with a quoted value)
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return None;
+	}
+	let line = line.strip_prefix("export ").unwrap_or(line);
+	let (key, value) = line.split_once('=')?;
+	let value = value.trim();
+	// strip matching quotes
+	let value = ['"', '\'']
+		.iter()
+		.find_map(|&quote| {
+			value
+				.strip_prefix(quote)
+				.and_then(|value| value.strip_suffix(quote))
+		})
+		.unwrap_or(value);
+	Some((key.trim(), value))
+}
+
+/// Load the variables defined in an env file. Variables already set in the environment are kept
+pub fn load(path: &str) -> Result<()> {
+	let content = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+	for (i, line) in content.lines().enumerate() {
+		match parse_line(line) {
+			Some((key, value)) if !key.is_empty() => {
+				if env::var_os(key).is_none() {
+					env::set_var(key, value);
+				}
+			}
+			Some(_) => anyhow::bail!("{}:{}: missing variable name", path, i + 1),
+			None if !line.trim().is_empty() && !line.trim().starts_with('#') => {
+				anyhow::bail!("{}:{}: expected KEY=value", path, i + 1)
+			}
+			None => {}
+		}
+	}
+	Ok(())
+}
+
+#[test]
+fn parse_lines() {
+	assert_eq!(parse_line("# comment"), None);
+	assert_eq!(parse_line("export KEY=value"), Some(("KEY", "value")));
+	assert_eq!(parse_line("KEY=\"a value\""), Some(("KEY", "a value")));
+	assert_eq!(parse_line("KEY='a=b'"), Some(("KEY", "a=b")));
+}
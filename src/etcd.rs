@@ -0,0 +1,176 @@
+use crate::{
+	message::Message,
+	result::{Error, Result},
+};
+
+use async_std::channel::Sender;
+#[cfg(feature = "etcd")]
+use etcd_client::{Client, GetOptions, WatchOptions};
+use serde_json::{Map, Value};
+#[cfg(feature = "etcd")]
+use std::{sync::OnceLock, time::Duration};
+#[cfg(feature = "etcd")]
+use tokio::{runtime::Runtime, task::JoinHandle};
+
+/// endpoint used when none is given on the command line
+#[cfg(feature = "etcd")]
+const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:2379";
+
+/// delay before watching again a key after the stream has been interrupted
+#[cfg(feature = "etcd")]
+const WATCH_RETRY: u64 = 5;
+
+/// tokio runtime the etcd client runs on
+#[cfg(feature = "etcd")]
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Return the tokio runtime shared by the etcd requests and watches, starting it if necessary
+#[cfg(feature = "etcd")]
+fn runtime() -> Result<&'static Runtime> {
+	if let Some(runtime) = RUNTIME.get() {
+		return Ok(runtime);
+	}
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.worker_threads(1)
+		.enable_all()
+		.build()
+		.map_err(|e| Error::Etcd("runtime".to_owned(), e.to_string()))?;
+	Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+/// Connect to the first reachable etcd endpoint
+#[cfg(feature = "etcd")]
+async fn connect(endpoints: &[String]) -> std::result::Result<Client, etcd_client::Error> {
+	if endpoints.is_empty() {
+		Client::connect([DEFAULT_ENDPOINT], None).await
+	} else {
+		Client::connect(endpoints, None).await
+	}
+}
+
+/// Read a key, or all the keys starting with it if prefix is true, and return them as an object of strings
+/// indexed by the key (relative to the prefix)
+#[cfg(feature = "etcd")]
+pub async fn get(endpoints: &[String], key: &str, prefix: bool) -> Result<Map<String, Value>> {
+	let endpoints = endpoints.to_vec();
+	let owned_key = key.to_owned();
+	let etcd_err = |e: String| Error::Etcd(key.to_owned(), e);
+	let kvs = runtime()?
+		.spawn(async move {
+			let mut client = connect(&endpoints).await?;
+			let options = if prefix {
+				Some(GetOptions::new().with_prefix())
+			} else {
+				None
+			};
+			let response = client.get(owned_key.as_str(), options).await?;
+			response
+				.kvs()
+				.iter()
+				.map(|kv| Ok((kv.key_str()?.to_owned(), kv.value_str()?.to_owned())))
+				.collect::<std::result::Result<Vec<_>, etcd_client::Error>>()
+		})
+		.await
+		.map_err(|e| etcd_err(e.to_string()))?
+		.map_err(|e| etcd_err(e.to_string()))?;
+	if kvs.is_empty() {
+		return Err(etcd_err("key not found".to_owned()));
+	}
+	Ok(kvs
+		.into_iter()
+		.map(|(k, v)| {
+			let k = if prefix {
+				k.strip_prefix(key).unwrap_or(&k).to_owned()
+			} else {
+				k
+			};
+			(k, Value::String(v))
+		})
+		.collect())
+}
+
+#[cfg(not(feature = "etcd"))]
+pub async fn get(_endpoints: &[String], key: &str, _prefix: bool) -> Result<Map<String, Value>> {
+	Err(Error::Etcd(
+		key.to_owned(),
+		"rconfd was built without the etcd feature".to_owned(),
+	))
+}
+
+/// A watch of etcd keys, stopped when dropped
+pub struct Watch {
+	#[cfg(feature = "etcd")]
+	handle: JoinHandle<()>,
+}
+
+#[cfg(feature = "etcd")]
+impl Drop for Watch {
+	fn drop(&mut self) {
+		self.handle.abort();
+	}
+}
+
+/// Watch a key, or all the keys starting with it if prefix is true, and refresh the secret (path) on every
+/// change
+#[cfg(feature = "etcd")]
+pub fn watch(
+	endpoints: &[String],
+	key: &str,
+	prefix: bool,
+	path: &str,
+	sender: &Sender<Message>,
+) -> Result<Watch> {
+	let endpoints = endpoints.to_vec();
+	let key = key.to_owned();
+	let path = path.to_owned();
+	let sender = sender.clone();
+	let handle = runtime()?.spawn(async move {
+		let mut interrupted = false;
+		loop {
+			let res = async {
+				let mut client = connect(&endpoints).await?;
+				let options = if prefix {
+					Some(WatchOptions::new().with_prefix())
+				} else {
+					None
+				};
+				let (_watcher, mut stream) = client.watch(key.as_str(), options).await?;
+				// changes may have been missed while the stream was interrupted
+				if interrupted {
+					sender.send(Message::Refresh(path.clone())).await.ok();
+				}
+				while let Some(response) = stream.message().await? {
+					if !response.events().is_empty() {
+						log::info!("etcd key \"{}\" changed", &key);
+						if sender.send(Message::Refresh(path.clone())).await.is_err() {
+							return Ok(());
+						}
+					}
+				}
+				Ok::<_, etcd_client::Error>(())
+			};
+			match res.await {
+				Ok(()) if sender.is_closed() => return,
+				Ok(()) => log::warn!("Watch of etcd key \"{}\" ended", &key),
+				Err(e) => log::warn!("Watching etcd key \"{}\": {}", &key, e),
+			}
+			interrupted = true;
+			tokio::time::sleep(Duration::from_secs(WATCH_RETRY)).await;
+		}
+	});
+	Ok(Watch { handle })
+}
+
+#[cfg(not(feature = "etcd"))]
+pub fn watch(
+	_endpoints: &[String],
+	key: &str,
+	_prefix: bool,
+	_path: &str,
+	_sender: &Sender<Message>,
+) -> Result<Watch> {
+	Err(Error::Etcd(
+		key.to_owned(),
+		"rconfd was built without the etcd feature".to_owned(),
+	))
+}
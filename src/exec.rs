@@ -0,0 +1,216 @@
+use crate::result::{Error, Result};
+
+use std::{io, os::unix::process::CommandExt, path::PathBuf, process::Command};
+
+/// system paths commands can read when sandboxed
+const SYSTEM_PATHS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc", "/proc"];
+
+/// paths commands can write to when sandboxed
+const WRITABLE_PATHS: &[&str] = &["/tmp", "/dev"];
+
+/// Policy applied to the commands executed by hooks and exe secrets
+pub struct ExePolicy {
+	/// no command can be executed
+	disabled: bool,
+	/// absolute paths of allowed executables or directories (ending with /) containing them
+	allowed: Vec<String>,
+	/// extra writable paths of sandboxed commands (no sandbox if None)
+	sandbox: Option<Vec<String>>,
+}
+
+impl ExePolicy {
+	pub fn new(disabled: bool, allowed: Vec<String>, sandbox: Option<Vec<String>>) -> Self {
+		Self {
+			disabled,
+			allowed,
+			sandbox,
+		}
+	}
+
+	/// Restrict the command to read system paths and write to the writable paths only, and forbid network access
+	pub fn sandbox(&self, cmd: &mut Command, writable: &[&str]) -> io::Result<()> {
+		if let Some(ref paths) = self.sandbox {
+			let ro: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+			let rw: Vec<PathBuf> = WRITABLE_PATHS
+				.iter()
+				.chain(writable.iter())
+				.map(PathBuf::from)
+				.chain(paths.iter().map(PathBuf::from))
+				.collect();
+			// the ruleset and the filter are built before fork as the child can't allocate nor open files
+			let mut rules = path_rules(&ro, &rw)?;
+			let mut filter = network_filter();
+			// SAFETY: the closure doesn't allocate and only makes async-signal-safe syscalls (prctl,
+			// landlock_restrict_self, seccomp) restricting the child process before exec
+			unsafe {
+				cmd.pre_exec(move || {
+					restrict_paths(&mut rules)?;
+					deny_network(&mut filter)
+				});
+			}
+		}
+		Ok(())
+	}
+
+	/// Check that the executable (absolute path) of a command can be executed
+	pub fn check(&self, exe: &str) -> Result<()> {
+		if self.disabled {
+			Err(Error::ExeDisabled(exe.to_owned()))
+		} else if !self.allowed.is_empty()
+			&& !self.allowed.iter().any(|allowed| {
+				if allowed.ends_with('/') {
+					exe.starts_with(allowed.as_str()) && !exe[allowed.len()..].contains('/')
+				} else {
+					exe == allowed
+				}
+			}) {
+			Err(Error::ExeNotAllowed(exe.to_owned()))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// Landlock ruleset (read only and writable paths) enforced in the child process
+#[cfg(feature = "landlock")]
+type PathRules = Option<landlock::RulesetCreated>;
+
+#[cfg(not(feature = "landlock"))]
+type PathRules = ();
+
+/// Build a landlock ruleset allowing to read ro paths and write rw paths
+#[cfg(feature = "landlock")]
+fn path_rules(ro: &[PathBuf], rw: &[PathBuf]) -> io::Result<PathRules> {
+	use landlock::{
+		path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, ABI,
+	};
+
+	let abi = ABI::V1;
+	Ruleset::new()
+		.handle_access(AccessFs::from_all(abi))
+		.and_then(|ruleset| ruleset.create())
+		.and_then(|ruleset| ruleset.add_rules(path_beneath_rules(ro, AccessFs::from_read(abi))))
+		.and_then(|ruleset| ruleset.add_rules(path_beneath_rules(rw, AccessFs::from_all(abi))))
+		.map(Some)
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Without landlock support, only the network is restricted
+#[cfg(not(feature = "landlock"))]
+fn path_rules(_ro: &[PathBuf], _rw: &[PathBuf]) -> io::Result<PathRules> {
+	Ok(())
+}
+
+/// Restrict file system accesses of the current process with the ruleset built by path_rules (called after fork)
+#[cfg(feature = "landlock")]
+fn restrict_paths(rules: &mut PathRules) -> io::Result<()> {
+	match rules.take() {
+		// the error is not converted as it would allocate
+		Some(ruleset) => ruleset
+			.restrict_self()
+			.map(|_| ())
+			.map_err(|_| io::Error::from_raw_os_error(libc::EPERM)),
+		None => Ok(()),
+	}
+}
+
+#[cfg(not(feature = "landlock"))]
+fn restrict_paths(_rules: &mut PathRules) -> io::Result<()> {
+	Ok(())
+}
+
+// classic BPF instructions used by seccomp filters
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+// syscalls of the x32 abi (x86_64 only)
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+// offsets of the fields of struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0: u32 = 16;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+fn bpf(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+	libc::sock_filter { code, jt, jf, k }
+}
+
+/// Seccomp filter forbidding the creation of sockets other than unix sockets
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn network_filter() -> Vec<libc::sock_filter> {
+	vec![
+		bpf(BPF_LD_W_ABS, SECCOMP_DATA_ARCH, 0, 0),
+		// foreign architecture (i386 on x86_64 or arm on aarch64): kill
+		bpf(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+		bpf(BPF_RET_K, SECCOMP_RET_KILL_PROCESS, 0, 0),
+		bpf(BPF_LD_W_ABS, SECCOMP_DATA_NR, 0, 0),
+		// x32 syscalls: kill
+		bpf(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 8, 0),
+		// io_uring operations would bypass the filter: report io_uring as not implemented
+		bpf(BPF_JMP_JEQ_K, libc::SYS_io_uring_setup as u32, 6, 0),
+		// other syscalls than socket and socketpair: allow
+		bpf(BPF_JMP_JEQ_K, libc::SYS_socket as u32, 1, 0),
+		bpf(BPF_JMP_JEQ_K, libc::SYS_socketpair as u32, 0, 3),
+		bpf(BPF_LD_W_ABS, SECCOMP_DATA_ARG0, 0, 0),
+		// unix sockets: allow
+		bpf(BPF_JMP_JEQ_K, libc::AF_UNIX as u32, 1, 0),
+		bpf(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EACCES as u32, 0, 0),
+		bpf(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0),
+		bpf(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32, 0, 0),
+		bpf(BPF_RET_K, SECCOMP_RET_KILL_PROCESS, 0, 0),
+	]
+}
+
+/// The network is not restricted on other architectures
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn network_filter() -> Vec<libc::sock_filter> {
+	Vec::new()
+}
+
+/// Load the seccomp filter built by network_filter in the current process (called after fork)
+fn deny_network(filter: &mut [libc::sock_filter]) -> io::Result<()> {
+	let prog = libc::sock_fprog {
+		len: filter.len() as libc::c_ushort,
+		filter: filter.as_mut_ptr(),
+	};
+	// SAFETY: this is standard call to libc with a valid filter program
+	unsafe {
+		if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+			|| !filter.is_empty()
+				&& libc::prctl(
+					libc::PR_SET_SECCOMP,
+					libc::SECCOMP_MODE_FILTER,
+					&prog as *const libc::sock_fprog,
+				) != 0
+		{
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+#[test]
+fn allowlist() {
+	let policy = ExePolicy::new(
+		false,
+		vec!["/usr/bin/date".to_owned(), "/sbin/".to_owned()],
+		None,
+	);
+	assert!(policy.check("/usr/bin/date").is_ok());
+	assert!(policy.check("/sbin/reboot").is_ok());
+	assert!(policy.check("/sbin/sub/cmd").is_err());
+	assert!(policy.check("/usr/bin/nproc").is_err());
+}
+
+#[test]
+fn disabled() {
+	let policy = ExePolicy::new(true, vec!["/usr/bin/date".to_owned()], None);
+	assert!(policy.check("/usr/bin/date").is_err());
+}
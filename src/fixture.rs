@@ -0,0 +1,190 @@
+use crate::{
+	args::{Args, TestArgs},
+	conf::{config_files, parse_config, TemplateConf},
+	render::TemplateEngine,
+	result::Error,
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::{
+	fs::{self, File},
+	io::BufReader,
+	path::{Component, Path, PathBuf},
+};
+
+/// Return the name under which fixtures of a template are stored
+fn fixture_name(tmpl: &str) -> String {
+	Path::new(tmpl)
+		.file_name()
+		.map(|s| s.to_string_lossy().into_owned())
+		.unwrap_or_else(|| tmpl.to_owned())
+}
+
+/// Load an extVar object of a template from <fixtures>/<tmpl>.<var>.json (empty if missing)
+fn load_ext_var(fixtures: &Path, name: &str, var: &str) -> Result<Value> {
+	let path = fixtures.join(format!("{}.{}.json", name, var));
+	if !path.exists() {
+		return Ok(Value::Object(Default::default()));
+	}
+	let reader = BufReader::new(File::open(&path).with_context(|| format!("Opening {:?}", path))?);
+	serde_json::from_reader(reader).with_context(|| format!("Parsing {:?}", path))
+}
+
+/// Return the differing lines between expected and actual
+fn diff(expected: &str, actual: &str) -> Vec<String> {
+	let expected: Vec<&str> = expected.lines().collect();
+	let actual: Vec<&str> = actual.lines().collect();
+	// longest common subsequence lengths of the suffixes
+	let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+	for i in (0..expected.len()).rev() {
+		for j in (0..actual.len()).rev() {
+			lcs[i][j] = if expected[i] == actual[j] {
+				lcs[i + 1][j + 1] + 1
+			} else {
+				lcs[i + 1][j].max(lcs[i][j + 1])
+			};
+		}
+	}
+	let mut res = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < expected.len() || j < actual.len() {
+		if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+			i += 1;
+			j += 1;
+		} else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+			res.push(format!("{:>4} +{}", j + 1, actual[j]));
+			j += 1;
+		} else {
+			res.push(format!("{:>4} -{}", i + 1, expected[i]));
+			i += 1;
+		}
+	}
+	res
+}
+
+/// Return the path of the expected output of a generated file inside the fixtures of a template, refusing
+/// paths escaping it with ..
+fn expected_path(expected_dir: &Path, file: &str) -> Result<PathBuf> {
+	let relative = Path::new(file.trim_start_matches('/'));
+	if relative.components().any(|c| c == Component::ParentDir) {
+		return Err(Error::OutsideFixtures(
+			file.to_owned(),
+			expected_dir.to_string_lossy().into_owned(),
+		)
+		.into());
+	}
+	Ok(expected_dir.join(relative))
+}
+
+/// Render a template with its fixture secrets and compare the result with the expected files (or
+/// overwrite them if update is true). Return the list of problems found
+fn check(
+	renderer: &TemplateEngine,
+	fixtures: &Path,
+	tmpl: &str,
+	conf: &TemplateConf,
+	update: bool,
+) -> Result<Vec<String>> {
+	let name = fixture_name(tmpl);
+	let secrets = load_ext_var(fixtures, &name, "secrets")?;
+	let secrets_meta = load_ext_var(fixtures, &name, "secrets_meta")?;
+	let files = load_ext_var(fixtures, &name, "files")?;
+	let manifests = renderer.render(tmpl, conf, secrets, secrets_meta, files)?;
+	let expected_dir = fixtures.join(&name);
+	let mut problems = Vec::new();
+	for (file, data) in manifests.iter() {
+		let expected_path = expected_path(&expected_dir, file as &str)?;
+		let actual = format!("{}\n", data.as_str());
+		if update {
+			if let Some(dir) = expected_path.parent() {
+				fs::create_dir_all(dir).with_context(|| format!("Creating {:?}", dir))?;
+			}
+			fs::write(&expected_path, &actual)
+				.with_context(|| format!("Writing {:?}", expected_path))?;
+			continue;
+		}
+		match fs::read_to_string(&expected_path) {
+			Ok(expected) if expected == actual => {}
+			Ok(expected) => {
+				problems.push(format!("{} differs from {:?}:", file, expected_path));
+				problems.extend(diff(&expected, &actual));
+			}
+			Err(_) => problems.push(format!(
+				"{} has no expected output {:?}",
+				file, expected_path
+			)),
+		}
+	}
+	Ok(problems)
+}
+
+/// Render all templates with fixture secrets and compare them with expected outputs
+pub fn run(args: &Args, test: &TestArgs) -> i32 {
+	let mut renderer = TemplateEngine::new(&args.dirs(), args.jpath.as_deref());
+	renderer.set_max_stack(args.max_stack);
+	renderer.set_trace(args.max_trace, args.trace_padding, args.trace_absolute);
+	let fixtures = PathBuf::from(&test.fixtures);
+	let mut failures = 0;
+	let files = match config_files(&args.dirs()) {
+		Ok(files) => files,
+		Err(e) => {
+			eprintln!("Error: {:?}", e);
+			return 1;
+		}
+	};
+	for file in files.iter() {
+		let confs = match parse_config(file) {
+			Ok(confs) => confs,
+			Err(e) => {
+				eprintln!("Error: parsing {:?}: {:?}", file, e);
+				return 1;
+			}
+		};
+		let mut tmpls: Vec<&String> = confs.keys().collect();
+		tmpls.sort();
+		for tmpl in tmpls {
+			match check(&renderer, &fixtures, tmpl, &confs[tmpl], test.update) {
+				Ok(_) if test.update => println!("{} ... updated", tmpl),
+				Ok(problems) if problems.is_empty() => println!("{} ... ok", tmpl),
+				Ok(problems) => {
+					failures += 1;
+					println!("{} ... FAILED", tmpl);
+					for problem in problems {
+						println!("    {}", problem);
+					}
+				}
+				Err(e) => {
+					failures += 1;
+					println!("{} ... FAILED", tmpl);
+					println!("    {:#}", e);
+				}
+			}
+		}
+	}
+	if failures > 0 {
+		println!("{} template(s) failed", failures);
+		1
+	} else {
+		0
+	}
+}
+
+#[test]
+fn diff_lines() {
+	assert_eq!(
+		diff("a\nb\nc\n", "a\nx\nc\nd\n"),
+		vec!["   2 +x", "   2 -b", "   4 +d"]
+	);
+	assert!(diff("a\nb\n", "a\nb\n").is_empty());
+}
+
+#[test]
+fn expected_paths() {
+	let dir = Path::new("fixtures/app");
+	assert_eq!(
+		expected_path(dir, "/etc/app/config.yml").unwrap(),
+		dir.join("etc/app/config.yml")
+	);
+	assert!(expected_path(dir, "/etc/../../../outside").is_err());
+}
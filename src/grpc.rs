@@ -0,0 +1,83 @@
+use crate::{message::Message, status::Status};
+
+use anyhow::{Context, Result};
+use async_std::{channel::Sender, sync::Arc};
+use std::{net::SocketAddr, thread};
+use tonic::{transport::Server, Request, Response};
+
+pub mod proto {
+	tonic::include_proto!("rconfd");
+}
+
+use proto::{
+	control_server::{Control, ControlServer},
+	Empty, RefreshRequest, RenderRequest, StatusReply,
+};
+
+/// gRPC control service forwarding the commands to the main loop
+struct ControlService {
+	sender: Sender<Message>,
+	status: Arc<Status>,
+}
+
+impl ControlService {
+	async fn send(&self, msg: Message) -> Result<Response<Empty>, tonic::Status> {
+		self.sender
+			.send(msg)
+			.await
+			.map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+		Ok(Response::new(Empty {}))
+	}
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+	async fn status(&self, _: Request<Empty>) -> Result<Response<StatusReply>, tonic::Status> {
+		Ok(Response::new(StatusReply {
+			alive: self.status.is_alive(),
+			ready: self.status.is_ready(),
+			templates: self.status.templates() as u64,
+			failed: self.status.failed() as u64,
+		}))
+	}
+
+	async fn reload(&self, _: Request<Empty>) -> Result<Response<Empty>, tonic::Status> {
+		self.send(Message::Reload).await
+	}
+
+	async fn render(
+		&self,
+		request: Request<RenderRequest>,
+	) -> Result<Response<Empty>, tonic::Status> {
+		self.send(Message::GenerateTemplate(request.into_inner().template))
+			.await
+	}
+
+	async fn refresh(
+		&self,
+		request: Request<RefreshRequest>,
+	) -> Result<Response<Empty>, tonic::Status> {
+		self.send(Message::Refresh(request.into_inner().secret))
+			.await
+	}
+}
+
+/// Serve the gRPC control service on addr in a dedicated thread running a tokio runtime
+pub fn serve_grpc(addr: &str, sender: Sender<Message>, status: Arc<Status>) -> Result<()> {
+	let addr: SocketAddr = addr
+		.parse()
+		.with_context(|| format!("Parsing address {}", addr))?;
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.worker_threads(1)
+		.enable_all()
+		.build()
+		.context("Starting tokio runtime")?;
+	log::info!("Serving gRPC control service on {}", addr);
+	thread::spawn(move || {
+		let service = ControlServer::new(ControlService { sender, status });
+		if let Err(e) = runtime.block_on(Server::builder().add_service(service).serve(addr)) {
+			log::error!("gRPC control service: {}", e);
+		}
+	});
+	Ok(())
+}
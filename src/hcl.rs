@@ -0,0 +1,288 @@
+use crate::result::{Error, Result};
+
+use serde_json::{Map, Value};
+
+/// Attribute or block of an HCL body
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+	Attr(String, Value),
+	Block(String, Vec<String>, Vec<Entry>),
+}
+
+/// Minimal HCL parser supporting what daemon configuration files use: attributes, blocks with
+/// optional labels, strings, heredocs, numbers, booleans, lists and comments. Expressions are not
+/// supported and interpolations (${...}) are kept verbatim in strings
+struct Parser {
+	chars: Vec<char>,
+	pos: usize,
+	line: usize,
+}
+
+impl Parser {
+	fn error(&self, msg: &str) -> Error {
+		Error::Hcl(self.line, msg.to_owned())
+	}
+
+	fn peek(&self) -> Option<char> {
+		self.chars.get(self.pos).copied()
+	}
+
+	fn peek_next(&self) -> Option<char> {
+		self.chars.get(self.pos + 1).copied()
+	}
+
+	fn next(&mut self) -> Option<char> {
+		let c = self.peek();
+		if c == Some('\n') {
+			self.line += 1;
+		}
+		self.pos += 1;
+		c
+	}
+
+	/// skip spaces, new lines, commas and comments
+	fn skip_blanks(&mut self) {
+		while let Some(c) = self.peek() {
+			match c {
+				c if c.is_whitespace() || c == ',' => {
+					self.next();
+				}
+				'#' => self.skip_line(),
+				'/' if self.peek_next() == Some('/') => self.skip_line(),
+				'/' if self.peek_next() == Some('*') => {
+					self.pos += 2;
+					while self.peek().is_some()
+						&& !(self.peek() == Some('*') && self.peek_next() == Some('/'))
+					{
+						self.next();
+					}
+					self.pos += 2;
+				}
+				_ => break,
+			}
+		}
+	}
+
+	fn skip_line(&mut self) {
+		while let Some(c) = self.next() {
+			if c == '\n' {
+				break;
+			}
+		}
+	}
+
+	fn ident(&mut self) -> Result<String> {
+		let mut ident = String::new();
+		while let Some(c) = self.peek() {
+			if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' {
+				ident.push(c);
+				self.next();
+			} else {
+				break;
+			}
+		}
+		if ident.is_empty() {
+			Err(self.error("expected identifier"))
+		} else {
+			Ok(ident)
+		}
+	}
+
+	fn string(&mut self) -> Result<String> {
+		// skip the opening quote
+		self.next();
+		let mut s = String::new();
+		loop {
+			match self.next() {
+				Some('"') => return Ok(s),
+				Some('\\') => match self.next() {
+					Some('n') => s.push('\n'),
+					Some('t') => s.push('\t'),
+					Some('r') => s.push('\r'),
+					Some(c) => s.push(c),
+					None => break,
+				},
+				Some(c) => s.push(c),
+				None => break,
+			}
+		}
+		Err(self.error("unterminated string"))
+	}
+
+	fn heredoc(&mut self) -> Result<String> {
+		// skip <<
+		self.pos += 2;
+		let indented = self.peek() == Some('-');
+		if indented {
+			self.next();
+		}
+		let marker = self.ident()?;
+		self.skip_line();
+		let mut s = String::new();
+		let mut line = String::new();
+		while let Some(c) = self.next() {
+			if c == '\n' {
+				if line.trim() == marker {
+					return Ok(s);
+				}
+				s.push_str(if indented { line.trim_start() } else { &line });
+				s.push('\n');
+				line.clear();
+			} else {
+				line.push(c);
+			}
+		}
+		if line.trim() == marker {
+			Ok(s)
+		} else {
+			Err(self.error("unterminated heredoc"))
+		}
+	}
+
+	fn value(&mut self) -> Result<Value> {
+		self.skip_blanks();
+		match self.peek() {
+			Some('"') => self.string().map(Value::String),
+			Some('<') if self.peek_next() == Some('<') => self.heredoc().map(Value::String),
+			Some('[') => {
+				self.next();
+				let mut values = Vec::new();
+				loop {
+					self.skip_blanks();
+					if self.peek() == Some(']') {
+						self.next();
+						return Ok(Value::Array(values));
+					}
+					values.push(self.value()?);
+				}
+			}
+			Some('{') => {
+				self.next();
+				self.body(true)
+					.map(|entries| Value::Object(to_object(&entries)))
+			}
+			Some(_) => {
+				let word = self.ident()?;
+				match word.as_str() {
+					"true" => Ok(Value::Bool(true)),
+					"false" => Ok(Value::Bool(false)),
+					"null" => Ok(Value::Null),
+					// keep numbers with leading zeros (file modes) verbatim
+					_ if word.starts_with('0') && word.len() > 1 => Ok(Value::String(word)),
+					_ => serde_json::from_str::<serde_json::Number>(&word)
+						.map(Value::Number)
+						.map_err(|_| self.error(&format!("unsupported value {}", word))),
+				}
+			}
+			None => Err(self.error("expected value")),
+		}
+	}
+
+	fn body(&mut self, in_block: bool) -> Result<Vec<Entry>> {
+		let mut entries = Vec::new();
+		loop {
+			self.skip_blanks();
+			match self.peek() {
+				None if in_block => return Err(self.error("expected }")),
+				None => return Ok(entries),
+				Some('}') if in_block => {
+					self.next();
+					return Ok(entries);
+				}
+				_ => {}
+			}
+			let name = if self.peek() == Some('"') {
+				self.string()?
+			} else {
+				self.ident()?
+			};
+			self.skip_blanks();
+			let mut labels = Vec::new();
+			while self.peek() == Some('"') {
+				labels.push(self.string()?);
+				self.skip_blanks();
+			}
+			match self.next() {
+				Some('=') | Some(':') if labels.is_empty() => {
+					entries.push(Entry::Attr(name, self.value()?));
+				}
+				Some('{') => entries.push(Entry::Block(name, labels, self.body(true)?)),
+				_ => return Err(self.error(&format!("expected = or {{ after {}", name))),
+			}
+		}
+	}
+}
+
+/// Parse an HCL document
+pub fn parse(text: &str) -> Result<Vec<Entry>> {
+	Parser {
+		chars: text.chars().collect(),
+		pos: 0,
+		line: 1,
+	}
+	.body(false)
+}
+
+/// Convert a body to a JSON object (the last of repeated attributes or blocks wins)
+pub fn to_object(entries: &[Entry]) -> Map<String, Value> {
+	let mut map = Map::new();
+	for entry in entries {
+		match entry {
+			Entry::Attr(name, value) => {
+				map.insert(name.clone(), value.clone());
+			}
+			Entry::Block(name, labels, body) => {
+				let mut value = Value::Object(to_object(body));
+				for label in labels.iter().rev() {
+					let mut labeled = Map::new();
+					labeled.insert(label.clone(), value);
+					value = Value::Object(labeled);
+				}
+				map.insert(name.clone(), value);
+			}
+		}
+	}
+	map
+}
+
+#[test]
+fn parse_blocks() {
+	let entries = parse(
+		r#"
+# vault access
+vault {
+  address = "https://vault:8200"
+}
+template {
+  source      = "/etc/tpl/app.ctmpl" // inline comment
+  destination = "/etc/app.conf"
+  perms       = 0600
+  command     = ["/bin/kill", "-HUP", "1"]
+}
+"#,
+	)
+	.unwrap();
+	assert_eq!(entries.len(), 2);
+	match &entries[1] {
+		Entry::Block(name, labels, body) => {
+			assert_eq!(name, "template");
+			assert!(labels.is_empty());
+			let obj = to_object(body);
+			assert_eq!(obj["perms"], "0600");
+			assert_eq!(obj["command"][2], "1");
+		}
+		_ => panic!("expected a block"),
+	}
+}
+
+#[test]
+fn parse_heredoc() {
+	let entries = parse("contents = <<EOF\nkey = {{ key \"a\" }}\nEOF\n").unwrap();
+	assert_eq!(
+		entries,
+		vec![Entry::Attr(
+			"contents".to_owned(),
+			Value::String("key = {{ key \"a\" }}\n".to_owned())
+		)]
+	);
+}
@@ -0,0 +1,64 @@
+use crate::status::Status;
+
+use anyhow::{Context, Result};
+use async_std::{
+	io::{prelude::BufReadExt, BufReader, WriteExt},
+	net::{TcpListener, TcpStream},
+	stream::StreamExt,
+	sync::Arc,
+	task,
+};
+
+/// Bind the health and readiness endpoints to addr and serve them in the background
+pub async fn serve_probes(addr: &str, status: Arc<Status>) -> Result<()> {
+	let listener = TcpListener::bind(addr)
+		.await
+		.with_context(|| format!("Listening on {}", addr))?;
+	log::info!("Serving /healthz and /readyz on {}", addr);
+	task::spawn(async move {
+		let mut incoming = listener.incoming();
+		while let Some(stream) = incoming.next().await {
+			match stream {
+				Ok(stream) => {
+					let status = status.clone();
+					task::spawn(async move {
+						if let Err(e) = handle(stream, &status).await {
+							log::debug!("http probe: {}", e);
+						}
+					});
+				}
+				Err(e) => log::error!("http probe: {}", e),
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Answer a single request on /healthz or /readyz
+async fn handle(stream: TcpStream, status: &Status) -> std::io::Result<()> {
+	let mut line = String::new();
+	BufReader::new(&stream).read_line(&mut line).await?;
+	// request line is "METHOD PATH VERSION"
+	let check = match line.split_whitespace().nth(1) {
+		Some("/healthz") => Some(status.is_alive()),
+		Some("/readyz") => Some(status.is_ready()),
+		_ => None,
+	};
+	let (code, body) = match check {
+		Some(true) => ("200 OK", "ok"),
+		Some(false) => ("503 Service Unavailable", "not ok"),
+		None => ("404 Not Found", "not found"),
+	};
+	let mut writer = &stream;
+	writer
+		.write_all(
+			format!(
+				"HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+				code,
+				body.len(),
+				body
+			)
+			.as_bytes(),
+		)
+		.await
+}
@@ -0,0 +1,49 @@
+use crate::result::{Error, Result};
+
+use isahc::{
+	config::{CaCertificate, Configurable},
+	AsyncReadResponseExt, Request, RequestExt,
+};
+use serde_json::{Map, Value};
+use std::{env, time::Duration};
+
+/// directory of the service account credentials mounted in pods
+const SA_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Read a Secret from the kubernetes API of the cluster rconfd runs in, authenticating with the service
+/// account token, and return its data keys decoded from base64
+pub async fn secret(namespace: &str, name: &str, jwt: &str) -> Result<Map<String, Value>> {
+	let k8s_err = |e: String| Error::K8s(format!("{}/{}", namespace, name), e);
+	let host = env::var("KUBERNETES_SERVICE_HOST")
+		.map_err(|_| k8s_err("not running inside a kubernetes cluster".to_owned()))?;
+	let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+	let url = format!(
+		"https://{}:{}/api/v1/namespaces/{}/secrets/{}",
+		host, port, namespace, name
+	);
+	let mut response = Request::get(&url)
+		.header("Authorization", format!("Bearer {}", jwt))
+		.ssl_ca_certificate(CaCertificate::file(format!("{}/ca.crt", SA_DIR)))
+		.timeout(Duration::from_secs(10))
+		.body(())
+		.map_err(|e| k8s_err(e.to_string()))?
+		.send_async()
+		.await
+		.map_err(|e| k8s_err(e.to_string()))?;
+	if !response.status().is_success() {
+		return Err(k8s_err(format!("status {}", response.status())));
+	}
+	let body = response.text().await.map_err(|e| k8s_err(e.to_string()))?;
+	let mut secret: Value = serde_json::from_str(&body).map_err(|e| k8s_err(e.to_string()))?;
+	let mut data = Map::new();
+	if let Value::Object(keys) = secret["data"].take() {
+		for (key, value) in keys.into_iter() {
+			let decoded = base64::decode(value.as_str().unwrap_or_default())
+				.map_err(|e| k8s_err(format!("{}: {}", key, e)))?;
+			let decoded = String::from_utf8(decoded)
+				.map_err(|_| k8s_err(format!("{}: invalid UTF-8", key)))?;
+			data.insert(key, Value::String(decoded));
+		}
+	}
+	Ok(data)
+}
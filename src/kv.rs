@@ -0,0 +1,150 @@
+use crate::{
+	backend::Backend,
+	clients::{kwarg, Session},
+};
+
+use std::collections::HashMap;
+use vault_jwt::{client::VaultClient, secret::SecretPath};
+
+/// name of the secret parameter giving the version of the KV engine instead of detecting it
+pub const KV_KWARG: &str = "kv";
+
+/// path telling the mount of a path, readable by anyone having a permission on the path
+const MOUNTS_PATH: &str = "sys/internal/ui/mounts";
+
+/// Secrets engine mounted at a path, with its version if it's a KV engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+	/// path of the mount with a trailing /
+	pub path: String,
+	/// version of the KV engine (None if not a KV engine)
+	pub version: Option<u64>,
+}
+
+impl Mount {
+	/// Return the part of a path after the mount
+	fn rest<'a>(&self, path: &'a str) -> Option<&'a str> {
+		path.strip_prefix(self.path.as_str())
+	}
+
+	/// Return true if the data of a KV v2 secret must be unwrapped (path not written with data/)
+	pub fn unwrap(&self, path: &str) -> bool {
+		self.version == Some(2)
+			&& self
+				.rest(path)
+				.map(|rest| !rest.starts_with("data/") && !rest.starts_with("metadata/"))
+				.unwrap_or(false)
+	}
+
+	/// Return the path to read the data of a secret
+	pub fn data_path(&self, path: &str) -> String {
+		match self.rest(path) {
+			Some(rest) if self.unwrap(path) => format!("{}data/{}", self.path, rest),
+			_ => path.to_owned(),
+		}
+	}
+
+	/// Return the path to read the metadata of a secret, or None if it's not a KV v2 secret
+	pub fn metadata_path(&self, path: &str) -> Option<String> {
+		match self.rest(path) {
+			Some(rest) if self.version == Some(2) => Some(format!(
+				"{}metadata/{}",
+				self.path,
+				rest.strip_prefix("data/").unwrap_or(rest)
+			)),
+			_ => None,
+		}
+	}
+}
+
+/// Mounts detected by cluster, to ask vault only once by mount
+#[derive(Default)]
+pub struct KvMounts(HashMap<Session, Vec<Mount>>);
+
+impl KvMounts {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Return the mount of a vault secret, given by the kv parameter or asked to vault. Return None
+	/// if it can't be detected
+	pub async fn detect(
+		&mut self,
+		client: &mut VaultClient,
+		role: &str,
+		session: &Session,
+		secret_path: &SecretPath<'_, Backend>,
+	) -> Option<Mount> {
+		let path = secret_path.full_path;
+		// the mount is the first component of the path
+		if let Some(version) = kwarg(secret_path, KV_KWARG) {
+			return Some(Mount {
+				path: format!("{}/", path.split('/').next().unwrap_or_default()),
+				version: version.parse().ok(),
+			});
+		}
+		let mounts = self.0.entry(session.clone()).or_default();
+		if let Some(mount) = mounts.iter().find(|mount| path.starts_with(&mount.path)) {
+			return Some(mount.clone());
+		}
+		let mounts_path = format!("{}/{}", MOUNTS_PATH, path);
+		match client
+			.get_secret_async(role, "GET", &mounts_path, None)
+			.await
+		{
+			Ok(res) => {
+				let mount = Mount {
+					path: res.value["path"].as_str()?.to_owned(),
+					version: if res.value["type"].as_str() == Some("kv") {
+						// options are null for KV v1 engines
+						Some(
+							res.value["options"]["version"]
+								.as_str()
+								.and_then(|version| version.parse().ok())
+								.unwrap_or(1),
+						)
+					} else {
+						None
+					},
+				};
+				log::debug!("  {} is mounted on {:?}", path, &mount);
+				mounts.push(mount.clone());
+				Some(mount)
+			}
+			Err(e) => {
+				// detected again next time
+				log::debug!("  detecting the mount of {} failed: {:#}", path, e);
+				None
+			}
+		}
+	}
+}
+
+#[test]
+fn kv2_paths() {
+	let mount = Mount {
+		path: "secret/".to_owned(),
+		version: Some(2),
+	};
+	assert_eq!(mount.data_path("secret/app/db"), "secret/data/app/db");
+	assert_eq!(mount.data_path("secret/data/app/db"), "secret/data/app/db");
+	assert!(!mount.unwrap("secret/data/app/db"));
+	assert_eq!(
+		mount.metadata_path("secret/app/db").as_deref(),
+		Some("secret/metadata/app/db")
+	);
+	assert_eq!(
+		mount.metadata_path("secret/data/app/db").as_deref(),
+		Some("secret/metadata/app/db")
+	);
+}
+
+#[test]
+fn kv1_paths() {
+	let mount = Mount {
+		path: "kv/".to_owned(),
+		version: Some(1),
+	};
+	assert_eq!(mount.data_path("kv/data/app"), "kv/data/app");
+	assert_eq!(mount.metadata_path("kv/data/app"), None);
+}
@@ -0,0 +1,2471 @@
+//! Generate config files from jsonnet templates and keep them in sync with secrets fetched from a vault
+//! server. [`run`] is the whole pipeline used by the `rconfd` binary ([`cli`]): it fetches the secrets
+//! declared in the config files found in the directories of a [`Config`], renders the templates with a
+//! [`TemplateEngine`] and keeps leased secrets and generated files up to date in daemon mode. [`run_with`]
+//! does the same while letting the embedding service drive the pipeline with a [`SecretBroker`].
+
+mod acl;
+pub mod args;
+mod audit;
+mod backend;
+mod broker;
+mod chaos;
+mod checksum;
+mod circuit;
+mod cli;
+mod clients;
+pub mod conf;
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod dns;
+mod encrypt;
+mod envfile;
+mod etcd;
+mod exec;
+mod fixture;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hcl;
+mod http;
+mod k8s;
+mod kv;
+mod libc;
+mod logger;
+mod memory;
+mod message;
+mod migrate;
+mod pkcs11;
+mod redact;
+pub mod render;
+mod report;
+pub mod result;
+mod s6;
+pub mod secrets;
+mod sources;
+mod standby;
+mod state;
+mod status;
+mod subst;
+mod summary;
+mod swap;
+mod sys;
+mod task;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tpm;
+mod trigger;
+mod url;
+mod validate;
+mod window;
+mod x509;
+
+use anyhow::Context;
+use async_std::{
+	channel::{unbounded, Sender},
+	sync::Arc,
+};
+use futures::future::join_all;
+use serde_json::{json, Map, Value};
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	convert::TryFrom,
+	env,
+	fs::{self, create_dir_all, DirBuilder, File, OpenOptions},
+	io::{Read, Write},
+	os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+	path::{Path, PathBuf},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::Instrument;
+use vault_jwt::secret::{Secret, SecretPath};
+use zeroize::Zeroizing;
+
+pub use crate::{
+	args::{Args, Config},
+	broker::SecretBroker,
+	cli::cli,
+	render::TemplateEngine,
+	result::{Error, Failure},
+	secrets::Secrets,
+};
+
+use crate::{
+	audit::{Audit, Entry},
+	backend::Backend,
+	broker::Broker,
+	checksum::{Algorithm, Checksums},
+	circuit::{is_vault, Circuit},
+	clients::{params, session, Clients, Session},
+	conf::{config_files, fmt_labels, parse_config, HookType, Output, TemplateConfs},
+	control::serve_control,
+	etcd,
+	exec::ExePolicy,
+	http::serve_probes,
+	kv::KvMounts,
+	libc::{is_memory_fs, set_times, set_umask, set_xattr, try_lock, User},
+	logger,
+	memory::zeroize_value,
+	message::{send_message, Message},
+	redact::{redact, Redacted},
+	report::Reporter,
+	s6::s6_ready,
+	secrets::{get_nested, insert_nested, merge_values},
+	standby::Standbys,
+	state::State,
+	status::Status,
+	subst::subst_path,
+	summary::{self, Summary},
+	swap::{self, Swap},
+	task::{delay_task, Tasks},
+	trigger::watch_trigger,
+	window::Window,
+};
+
+/// vault path extending the ttl of the token used
+const TOKEN_RENEW_PATH: &str = "auth/token/renew-self";
+
+/// vault path returning the properties of the token used
+const TOKEN_LOOKUP_PATH: &str = "auth/token/lookup-self";
+
+/// vault path revoking all the leases issued on a path
+const LEASES_REVOKE_PATH: &str = "sys/leases/revoke-prefix";
+
+/// name of the file holding the checksums inside the state directory
+const CHECKSUMS_FILE: &str = "checksums.json";
+
+/// name of the lock file inside the state directory
+const LOCK_FILE: &str = "rconfd.lock";
+
+/// minimum delay (s) between two fetches of a secret containing a certificate about to expire
+const CERT_MIN_DELAY: u64 = 60;
+
+/// delay (s) given to a scheduled renewal before warning that a secret is about to expire
+const EXPIRY_MARGIN: u64 = 60;
+
+/// delay (s) between two checks of the files required before signaling readiness
+const READY_POLL: u64 = 1;
+
+/// Return path moved inside the root directory if any
+pub(crate) fn rooted(root: Option<&str>, path: PathBuf) -> PathBuf {
+	match root {
+		Some(root) => Path::new(root).join(path.strip_prefix("/").unwrap_or(&path)),
+		None => path,
+	}
+}
+
+/// Files of the shadow directory, removed once validated (or not)
+struct ShadowFiles(Vec<PathBuf>);
+
+impl Drop for ShadowFiles {
+	fn drop(&mut self) {
+		for path in self.0.iter() {
+			if let Err(e) = fs::remove_file(path) {
+				log::warn!("Removing shadow file {:?}: {}", path, e);
+			}
+		}
+	}
+}
+
+/// Return the content of a file in chunks according to the output mode, decoded holding the bytes of
+/// base64 encoded strings
+fn chunks<'a>(
+	output: Output,
+	file: &str,
+	data: &'a str,
+	decoded: &'a mut Option<Zeroizing<Vec<u8>>>,
+) -> result::Result<Vec<&'a [u8]>> {
+	Ok(match output {
+		Output::Text => vec![data.as_bytes(), &b"\n"[..]],
+		Output::Raw => vec![data.as_bytes()],
+		Output::Base64 => {
+			let bytes = decoded.insert(Zeroizing::new(
+				base64::decode(data.trim())
+					.map_err(|e| Error::InvalidBase64(file.to_owned(), e))?,
+			));
+			vec![bytes.as_slice()]
+		}
+	})
+}
+
+/// Revoke all the leases issued on the path of a vault secret
+async fn revoke_leases(clients: &mut Clients, path: &str) -> anyhow::Result<()> {
+	let secret_path =
+		SecretPath::<Backend>::try_from(path).with_context(|| format!("Parsing \"{}\"", path))?;
+	if secret_path.backend != Backend::Vault {
+		return Ok(());
+	}
+	let role = secret_path
+		.args
+		.get(0)
+		.ok_or_else(|| Error::MissingRole(path.to_owned()))?;
+	let revoke_path = format!("{}/{}", LEASES_REVOKE_PATH, secret_path.full_path);
+	clients
+		.get(&session(&secret_path))?
+		.get_secret_async(role, "PUT", &revoke_path, None)
+		.await?;
+	log::info!("  revoked the leases issued on {}", secret_path.full_path);
+	Ok(())
+}
+
+/// Parse the config files and ask the broker to fetch the secrets not already known
+async fn load_confs(
+	args: &Args,
+	secrets: &mut Secrets,
+	state_dir: Option<&State>,
+	sender: &Sender<Message>,
+	tasks: &mut Tasks,
+) -> anyhow::Result<TemplateConfs> {
+	// map template name to template conf
+	let mut confs = TemplateConfs::new();
+	// for each .json files in the conf directory
+	// entries are sorted by lexicographic order so we can influence order of config processing
+	let entries = config_files(&args.dirs()).context(Failure::Config)?;
+	for entry in entries.into_iter() {
+		// parse config files
+		log::info!("Loading {:?}", entry);
+		let path = entry.as_path();
+		let conf = parse_config(path)
+			.with_context(|| format!("Parsing {:?}", path))
+			.context(Failure::Config)?;
+		for (tmpl, conf) in conf {
+			log::info!("  Parsing {:?}", &tmpl);
+			if let Some(phase) = conf.phase.as_ref().filter(|p| !args.phase.contains(p)) {
+				return Err(
+					anyhow::Error::from(Error::UnknownPhase(tmpl, phase.clone()))
+						.context(Failure::Config),
+				);
+			}
+			// move conf to dedicated hashmap
+			confs.insert(tmpl.clone(), conf);
+
+			let secrets_map = &confs.get(&tmpl).unwrap().secrets;
+			if secrets_map.is_empty() {
+				// if no secrets generate template straight away
+				log::debug!("empty GenerateTemplate({})", tmpl);
+				sender.send(Message::GenerateTemplate(tmpl.clone())).await?;
+			} else {
+				// otherwise fetch all the secrets defined in the template config
+				for (path, _) in secrets_map.iter() {
+					// if we didn't already ask to get the secret
+					if secrets.get(path).is_none() {
+						// parse the secret
+						let secret = SecretPath::<Backend>::try_from(path.as_str())
+							.with_context(|| format!("Parsing \"{}\"", path))
+							.context(Failure::Config)?;
+						// reject bad arguments before fetching anything
+						if let Some(source) = sources::get(secret.backend) {
+							source.check(&secret).context(Failure::Config)?;
+						}
+						// resume a leased secret saved by a previous run instead of issuing a new one
+						if let Some((prev, renew_delay)) = state_dir.and_then(|s| s.restore(path)) {
+							if args.require_static {
+								return Err(anyhow::Error::from(Error::NotStatic(path.clone()))
+									.context(Failure::Secret));
+							}
+							log::info!("  Restoring secret \"{}\"", path);
+							// renew at the time planned by the previous run even if the restored lease is still
+							// considered valid then
+							let (sender, role, url, refresh) = (
+								sender.clone(),
+								secret.args[0].to_owned(),
+								session(&secret),
+								path.clone(),
+							);
+							let handle = delay_task(
+								async move {
+									send_message(sender.clone(), Message::Login(role, url)).await?;
+									send_message(sender, Message::Refresh(refresh)).await
+								},
+								renew_delay,
+							);
+							tasks.insert(Message::Refresh(path.clone()), handle).await;
+							// registers the value to be redacted
+							secrets.replace(path, prev);
+							continue;
+						}
+						if secret.backend == Backend::Vault {
+							// ask the broker to login first
+							sender
+								.send(Message::Login(secret.args[0].to_owned(), session(&secret)))
+								.await?;
+						}
+						// intialize secret to None
+						secrets.insert(path.clone(), None);
+						// ask the broker to get the secret initial value without triggering manifestation
+						sender
+							.send(Message::GetSecret(path.to_owned(), false))
+							.await?
+					}
+				}
+			}
+		}
+	}
+	Ok(confs)
+}
+
+/// Print the configuration the daemon would act on (config files overlaid, environment variables substituted
+/// and secrets checked) as JSON with sorted keys
+fn print_config(args: &Args) -> anyhow::Result<()> {
+	let mut confs = BTreeMap::new();
+	for entry in config_files(&args.dirs())?.into_iter() {
+		let path = entry.as_path();
+		let conf = parse_config(path).with_context(|| format!("Parsing {:?}", path))?;
+		for (tmpl, conf) in conf {
+			for path in conf.secrets.keys() {
+				let secret = SecretPath::<Backend>::try_from(path.as_str())
+					.with_context(|| format!("Parsing \"{}\" in {}", path, &tmpl))?;
+				if let Some(source) = sources::get(secret.backend) {
+					source.check(&secret)?;
+				}
+			}
+			confs.insert(tmpl, conf);
+		}
+	}
+	// maps are sorted once converted to values
+	let value = serde_json::to_value(&confs)?;
+	println!("{}", serde_json::to_string_pretty(&value)?);
+	Ok(())
+}
+
+/// Generate all the templates declared in the config directories, and keep them in sync with leased
+/// secrets in daemon mode
+pub async fn run(config: &Config) -> anyhow::Result<()> {
+	run_with(config, &SecretBroker::new()).await
+}
+
+/// Same as [`run`], the embedding service driving the pipeline through secret_broker
+pub async fn run_with(config: &Config, secret_broker: &SecretBroker) -> anyhow::Result<()> {
+	let args = config.args();
+	// restrict permissions of everything created from now on
+	if let Some(umask) = &args.umask {
+		let umask = u32::from_str_radix(umask, 8)
+			.with_context(|| format!("Parsing umask {}", umask))
+			.context(Failure::Config)?;
+		set_umask(umask);
+	}
+	// timestamp of generated files in deterministic mode
+	let deterministic = if args.deterministic {
+		Some(match env::var("SOURCE_DATE_EPOCH") {
+			Ok(epoch) => epoch
+				.parse::<i64>()
+				.with_context(|| format!("Parsing SOURCE_DATE_EPOCH {}", epoch))
+				.context(Failure::Config)?,
+			Err(_) => 0,
+		})
+	} else {
+		None
+	};
+	// variables defining the state inside the main loop
+	// if token given as argument, get the value from an envar with given name, or just use the string if it fails
+	let jwt = if let Some(jwt) = &args.token {
+		env::var(jwt).ok().or_else(|| Some(jwt.to_owned())).unwrap()
+	// otherwise read from a file
+	} else {
+		let mut jwt = String::new();
+		File::open(&args.token_path)
+			.with_context(|| format!("Opening {}", args.token_path))
+			.context(Failure::Auth)?
+			.read_to_string(&mut jwt)
+			.with_context(|| format!("Reading {}", args.token_path))
+			.context(Failure::Auth)?;
+		jwt
+	};
+	// trim jwt on both ends
+	let jwt = jwt.trim();
+	// initialize the vault clients
+	let mut clients =
+		Clients::new(&args.url, &args.login_path, jwt, &args.cacert).context(Failure::Config)?;
+	// performance standbys where to send the reads
+	let mut standbys = Standbys::new(&args.read_url);
+	// map secret path to secret value
+	let mut secrets = Secrets::new(args.compare_leases);
+	// number of generated templates
+	let mut generated = 0;
+	// current user
+	let mut current_user = User::current();
+	// unprivileged user executing the exe secrets when running as root
+	let exe_user = User::from_spec(&args.exe_user)
+		.ok_or_else(|| Error::UnknownUser(args.exe_user.clone()))
+		.context(Failure::Config)?;
+	// unprivileged user to switch to once initialized
+	let mut run_as = match args.run_as {
+		Some(ref spec) => Some(
+			User::from_spec(spec)
+				.ok_or_else(|| Error::UnknownUser(spec.to_owned()))
+				.context(Failure::Config)?,
+		),
+		None => None,
+	};
+	// jsonnet templates evaluation
+	let mut renderer = TemplateEngine::new(&args.dirs(), args.jpath.as_deref());
+	renderer.set_max_stack(args.max_stack);
+	renderer.set_trace(args.max_trace, args.trace_padding, args.trace_absolute);
+	// commands allowed for hooks and exe secrets
+	let policy = ExePolicy::new(
+		args.no_exe,
+		args.allow_exe.clone(),
+		if args.sandbox {
+			Some(args.sandbox_path.clone())
+		} else {
+			None
+		},
+	);
+	// status shared with the probes
+	let status = Arc::new(Status::default());
+	if let Some(ref addr) = args.http_listen {
+		serve_probes(addr, status.clone())
+			.await
+			.context(Failure::Config)?;
+	}
+	// report failures to an error tracking service
+	let reporter = Reporter::new(args.error_webhook.clone(), args.sentry_dsn.as_deref());
+	// log of secrets accesses
+	let mut audit = match args.audit_log {
+		Some(ref target) => Some(Audit::new(target).context(Failure::Config)?),
+		None => None,
+	};
+	// leased secrets saved by a previous run
+	let mut state_dir = match args.state_dir {
+		Some(ref dir) => Some(State::new(dir)?),
+		None => None,
+	};
+	// prevent another instance from writing the same files
+	let lock_path = args.lock_file.as_ref().map(PathBuf::from).or_else(|| {
+		state_dir
+			.as_ref()
+			.map(|state_dir| state_dir.path(LOCK_FILE))
+	});
+	let _lock = match lock_path {
+		Some(ref lock_path) => {
+			let lock = OpenOptions::new()
+				.write(true)
+				.create(true)
+				.mode(0o600)
+				.custom_flags(::libc::O_NOFOLLOW)
+				.open(lock_path)
+				.with_context(|| format!("Opening lock file {:?}", lock_path))
+				.context(Failure::Config)?;
+			if !try_lock(&lock)
+				.with_context(|| format!("Locking {:?}", lock_path))
+				.context(Failure::Config)?
+			{
+				return Err(anyhow::Error::from(Error::Locked(
+					lock_path.to_string_lossy().into_owned(),
+				))
+				.context(Failure::Config));
+			}
+			Some(lock)
+		}
+		None => None,
+	};
+	// map path to checksums
+	// format of the summary printed at the end of one-shot runs
+	let format = args
+		.output
+		.parse::<summary::Format>()
+		.context(Failure::Config)?;
+	// statistics of the run
+	let mut summary = Summary::new();
+	let algorithm = args
+		.checksum
+		.parse::<Algorithm>()
+		.context(Failure::Config)?;
+	let mut checksums = match state_dir {
+		Some(ref state_dir) => Checksums::load(&state_dir.path(CHECKSUMS_FILE), algorithm)?,
+		None => Checksums::new(algorithm),
+	};
+	// before first generate (a previous run that saved checksums already generated the files)
+	let mut first_run = checksums.is_empty();
+
+	// requests from outside the actor loop, whose senders wait while the channel is full
+	let (external, mut requests) = secret_broker.channel();
+	// messages sent by the actor loop to itself and by the tasks it schedules, which can't wait on the loop
+	let (sender, mut receiver) = unbounded();
+	// pending messages, deduplicated and by priority
+	let mut broker = Broker::new();
+	// scheduled renewals and checks of secrets and tokens
+	let mut tasks = Tasks::new();
+
+	// map template name to template conf
+	let mut confs = load_confs(args, &mut secrets, state_dir.as_ref(), &sender, &mut tasks).await?;
+	// trigger manifestation now we asked the broker to fetch all secrets
+	confs.generate_all_templates(&secrets, &sender).await?;
+	status.set_templates(confs.len(), 0);
+	if let Some(timeout) = args.ready_timeout {
+		delay_task(send_message(sender.clone(), Message::ReadyTimeout), timeout);
+	}
+	if let Some(ref path) = args.control_socket {
+		serve_control(path, external.clone(), status.clone())
+			.await
+			.context(Failure::Config)?;
+	}
+	if let Some(interval) = args.reconcile {
+		if args.daemon {
+			delay_task(send_message(sender.clone(), Message::Reconcile), interval);
+		}
+	}
+	if let Some(ref path) = args.trigger {
+		if args.daemon {
+			watch_trigger(path, external.clone());
+		}
+	}
+	if let Some(interval) = args.tamper_check {
+		if args.daemon {
+			delay_task(
+				send_message(sender.clone(), Message::CheckTampered),
+				interval,
+			);
+		}
+	}
+	// D-Bus service
+	#[cfg(feature = "dbus")]
+	let dbus = match args.dbus {
+		Some(ref bus) => Some(
+			dbus::DBus::new(bus, external.clone())
+				.await
+				.context(Failure::Config)?,
+		),
+		None => None,
+	};
+	#[cfg(not(feature = "dbus"))]
+	if let Some(ref bus) = args.dbus {
+		log::warn!(
+			"Ignoring {} bus: rconfd was built without the dbus feature",
+			bus
+		);
+	}
+	if let Some(ref addr) = args.grpc_listen {
+		#[cfg(feature = "grpc")]
+		grpc::serve_grpc(addr, external.clone(), status.clone()).context(Failure::Config)?;
+		#[cfg(not(feature = "grpc"))]
+		log::warn!(
+			"Ignoring gRPC address {}: rconfd was built without the grpc feature",
+			addr
+		);
+	}
+
+	// templates skipped in keep-going mode
+	let mut failed = HashSet::<String>::new();
+	// last generated template
+	let mut last_tmpl: Option<String> = None;
+	// output directories of the last generated template, with secrets interpolated
+	let mut last_dirs: Vec<String> = Vec::new();
+	// files and output directories of each generated template
+	let mut outputs = HashMap::<String, (Vec<PathBuf>, Vec<String>)>::new();
+	// time of the pending expiry check of secrets containing certificates
+	let mut cert_checks = HashMap::<String, SystemTime>::new();
+	// windows during which secrets can be rotated
+	let windows = args
+		.rotation_window
+		.iter()
+		.map(|window| window.parse::<Window>())
+		.collect::<result::Result<Vec<_>>>()
+		.context(Failure::Config)?;
+	// earliest expiry time (lease or certificate) of the fetched secrets
+	let mut expiries = HashMap::<String, SystemTime>::new();
+	// lease and expiry of the fetched secrets given to templates
+	let mut metas = HashMap::<String, Value>::new();
+	// fetched version of polled KV v2 secrets
+	let mut kv_versions = HashMap::<String, u64>::new();
+	// ttl of the tokens at login by role and session
+	let mut token_ttls = HashMap::<(String, Session), Duration>::new();
+	// KV engines versions by mount
+	let mut kv_mounts = KvMounts::new();
+	// secrets fetched along with another one (--batch-reads) until their own message is handled
+	let mut prefetched = HashMap::<String, Secret>::new();
+	// watches of the etcd secrets by path (daemon mode)
+	let mut watches = HashMap::<String, etcd::Watch>::new();
+	// failed logins by role and session, shared with the requests of the same burst of messages
+	let mut login_errors = HashMap::<(String, Session), String>::new();
+	// all templates generated but readiness not signaled yet
+	let mut ready_pending = false;
+	// a check of the files required for readiness is scheduled
+	let mut waiting_files = false;
+	// phase (index in --phase) whose templates are being generated, the next ones waiting
+	let mut phase = 0;
+	// fd where to signal readiness, closed once signaled
+	let mut ready_fd = args.ready_fd;
+	// a modified hook failed since readiness was last signaled
+	let mut hook_failed = false;
+	// pause vault requests during outages
+	let mut circuit = Circuit::new(if args.daemon {
+		args.circuit_threshold
+	} else {
+		None
+	});
+
+	// actor loop
+	status.set_alive(true);
+	while let Some(msg) = broker.next(&mut receiver, &mut requests).await {
+		// retry the failed logins on new events only
+		if broker.waited() {
+			login_errors.clear();
+		}
+		// keep a copy of the message to be able to react to a failure
+		let msg_copy = msg.clone();
+		// a secret has been fetched from its backend
+		let mut fetched = false;
+		// a vault request has been deferred because the circuit is open
+		let mut deferred = false;
+		// labels of the template or secret concerned, attached to the logs, traces and reports
+		let labels = match &msg {
+			Message::GenerateTemplate(tmpl) => confs
+				.get(tmpl)
+				.map(|conf| conf.labels.clone())
+				.unwrap_or_default(),
+			msg => msg
+				.secret()
+				.map(|path| confs.secret_labels(path))
+				.unwrap_or_default(),
+		};
+		logger::set_labels(fmt_labels(&labels));
+		let res = async {
+			match msg {
+				Message::Login(role, session) => {
+					if !circuit.allows() {
+						circuit.defer(Message::Login(role, session));
+						deferred = true;
+						return Ok(());
+					}
+					let client = clients.get(&session)?;
+					// log in if not already logged in with that role
+					if !client.is_logged(&role) {
+						log::debug!("  Login({})", &role);
+						let auth = client
+							.login_async(&role)
+							.instrument(tracing::info_span!("login", role = %role))
+							.await
+							.with_context(|| format!("Login to vault server {}", &client.url))
+							.map_err(|e| {
+								login_errors.insert(
+									(role.clone(), session.clone()),
+									redact(&format!("{:#}", e)),
+								);
+								e
+							})?;
+						// schedule a token renewal at 2/3 of the lease_duration time
+						if let Some(renew_delay) = auth.renew_delay() {
+							log::debug!(
+								"  logged in {} with role {}. Renew the token within {:?}",
+								&client.url,
+								&role,
+								renew_delay
+							);
+							token_ttls.insert((role.clone(), session.clone()), renew_delay * 3 / 2);
+							tasks
+								.schedule(&sender, Message::RenewToken(role, session), renew_delay)
+								.await;
+						}
+					}
+				}
+
+				Message::RenewToken(role, session) => {
+					if !circuit.allows() {
+						circuit.defer(Message::RenewToken(role, session));
+						deferred = true;
+						return Ok(());
+					}
+					let client = clients.get(&session)?;
+					// extending the token keeps its accessor and spares a login on the auth mount
+					let renewed = async {
+						client
+							.get_secret_async(&role, "POST", TOKEN_RENEW_PATH, None)
+							.await?;
+						let lookup = client
+							.get_secret_async(&role, "GET", TOKEN_LOOKUP_PATH, None)
+							.await?;
+						Ok::<_, anyhow::Error>(
+							lookup.value["ttl"].as_u64().map(Duration::from_secs),
+						)
+					}
+					.instrument(tracing::info_span!("renew", role = %role))
+					.await;
+					let login_ttl = token_ttls.get(&(role.clone(), session.clone())).copied();
+					// a ttl shorter than half the initial one means the max ttl of the token is near
+					let ttl = match renewed {
+						Ok(Some(ttl))
+							if login_ttl
+								.map(|login_ttl| ttl * 2 >= login_ttl)
+								.unwrap_or(false) =>
+						{
+							Some(ttl)
+						}
+						Ok(_) => None,
+						Err(e) => {
+							log::debug!("  renewing the token of role {} failed: {:#}", &role, e);
+							None
+						}
+					};
+					let renew_delay = match ttl {
+						Some(ttl) => {
+							log::debug!("  renewed the token of role {} for {:?}", &role, ttl);
+							Some(ttl * 2 / 3)
+						}
+						None => {
+							log::debug!("  Login({})", &role);
+							let auth = client
+								.login_async(&role)
+								.instrument(tracing::info_span!("login", role = %role))
+								.await
+								.with_context(|| format!("Login to vault server {}", &client.url))
+								.map_err(|e| {
+									login_errors.insert(
+										(role.clone(), session.clone()),
+										redact(&format!("{:#}", e)),
+									);
+									e
+								})?;
+							auth.renew_delay().map(|renew_delay| {
+								token_ttls
+									.insert((role.clone(), session.clone()), renew_delay * 3 / 2);
+								renew_delay
+							})
+						}
+					};
+					if let Some(renew_delay) = renew_delay {
+						tasks
+							.schedule(&sender, Message::RenewToken(role, session), renew_delay)
+							.await;
+					}
+				}
+
+				Message::GetSecret(path, gen_tmpl) => {
+					// a renewal sent before the secret was forgotten
+					if !secrets.contains_key(&path) {
+						log::debug!("  Ignoring GetSecret({}) of an unused secret", &path);
+						return Ok(());
+					}
+					// parse the secret again ? (yes it's cheap and contains only reference from path)
+					let secret_path = SecretPath::<Backend>::try_from(path.as_str())
+						.with_context(|| format!("Parsing \"{}\"", path))?;
+					// get the secret if not already fetched or if it's not valid or it it needs to be renewed
+					let get_secret = secrets
+						.get(secret_path.path)
+						.filter(|o| {
+							o.as_ref()
+								.filter(|s| s.is_valid() && !s.to_renew())
+								.is_some()
+						})
+						.is_none();
+					if get_secret {
+						// leave the files as is until vault is reachable again
+						if secret_path.backend == Backend::Vault && !circuit.allows() {
+							log::debug!("  Deferring GetSecret({}, {})", &path, gen_tmpl);
+							circuit.defer(Message::GetSecret(path.clone(), gen_tmpl));
+							deferred = true;
+							return Ok(());
+						}
+						// defer the rotation of a secret until a rotation window opens unless it's urgent
+						if gen_tmpl && matches!(secrets.get(&path), Some(Some(_))) {
+							let now = SystemTime::now();
+							let delay = window::until_open(&windows, now);
+							let urgent = expiries
+								.get(&path)
+								.map(|expiry| {
+									*expiry <= now + delay + Duration::from_secs(EXPIRY_MARGIN)
+								})
+								.unwrap_or(true);
+							if !delay.is_zero() && !urgent {
+								log::info!(
+									"Deferring the rotation of \"{}\" within {:?}",
+									&path,
+									delay
+								);
+								tasks
+									.schedule(
+										&sender,
+										Message::GetSecret(path.clone(), true),
+										delay,
+									)
+									.await;
+								return Ok(());
+							}
+						}
+						fetched = true;
+						summary.fetched(&secret_path.backend.to_string());
+						log::debug!("  GetSecret({}, {})", &path, gen_tmpl);
+						chaos::fetch(&path).await?;
+						let span = tracing::info_span!(
+							"secret",
+							backend = %secret_path.backend,
+							path = %secret_path.full_path,
+							labels = %fmt_labels(&labels)
+						);
+						async {
+							match secret_path.backend {
+								Backend::Vault => {
+									let role = secret_path
+										.args
+										.get(0)
+										.ok_or_else(|| Error::MissingRole(path.to_string()))?;
+									let method = secret_path
+										.args
+										.get(1)
+										.unwrap_or(&"get")
+										.to_ascii_uppercase();
+									let session = session(&secret_path);
+									// the login of the role already failed during this burst
+									if let Some(e) =
+										login_errors.get(&(role.to_string(), session.clone()))
+									{
+										return Err(anyhow::Error::from(Error::LoginFailed(
+											role.to_string(),
+											e.clone(),
+										))
+										.context(Failure::Auth));
+									}
+									let params = params(&secret_path);
+									let client = clients.get(&session)?;
+									// read the data of KV v2 secrets without having to write data/ in the path
+									let mount = if method == "GET" {
+										kv_mounts.detect(client, role, &session, &secret_path).await
+									} else {
+										None
+									};
+									let full_path = match mount {
+										Some(ref mount) => mount.data_path(secret_path.full_path),
+										None => secret_path.full_path.to_owned(),
+									};
+									// fetch the secret from a standby of the default cluster if it's a read, and
+									// from the active node otherwise or if the standby fails
+									let mut standby = match session.url {
+										Some(_) => None,
+										None => standbys.url(&method).map(str::to_owned),
+									};
+									// fetched along with another secret
+									let mut batched = prefetched.remove(&path);
+									// fetch the other secrets of the role never fetched yet at the same time
+									if let (None, Some(batch), "GET") = (
+										&batched,
+										args.batch_reads.filter(|n| *n > 1),
+										method.as_str(),
+									) {
+										let others = broker
+											.pending()
+											.filter_map(|msg| match msg {
+												Message::GetSecret(other, _)
+													if *other != path
+														&& matches!(
+															secrets.get(other),
+															Some(None)
+														) && !prefetched.contains_key(other) =>
+												{
+													Some(other.clone())
+												}
+												_ => None,
+											})
+											.collect::<Vec<_>>();
+										let others = others
+											.iter()
+											.filter_map(|other| {
+												SecretPath::<Backend>::try_from(other.as_str()).ok()
+											})
+											.filter(|other| {
+												other.backend == Backend::Vault
+													&& other.args.get(0) == Some(role) && other
+													.args
+													.get(1)
+													.map(|method| {
+														method.eq_ignore_ascii_case("get")
+													})
+													.unwrap_or(true) && session(other) == session
+											})
+											.take(batch - 1)
+											.collect::<Vec<_>>();
+										if !others.is_empty() {
+											let mut reads = vec![(
+												path.as_str(),
+												full_path.clone(),
+												params.clone(),
+											)];
+											for other in others.iter() {
+												let other_path = match kv_mounts
+													.detect(client, role, &session, other)
+													.await
+												{
+													Some(mount) => mount.data_path(other.full_path),
+													None => other.full_path.to_owned(),
+												};
+												reads.push((
+													other.path,
+													other_path,
+													clients::params(other),
+												));
+											}
+											log::debug!(
+												"  fetching {} secrets of role {} at once",
+												reads.len(),
+												role
+											);
+											let active = standby.as_ref().map(|url| {
+												std::mem::replace(&mut client.url, url.clone())
+											});
+											let client_ref = &*client;
+											let results = join_all(reads.iter().map(
+												|(_, full_path, params)| {
+													client_ref.get_secret_async(
+														role,
+														"GET",
+														full_path,
+														params.as_ref(),
+													)
+												},
+											))
+											.await;
+											if let Some(active) = active {
+												client.url = active;
+											}
+											// failed reads are done again one by one
+											for ((read_path, read_full_path, _), res) in
+												reads.iter().zip(results)
+											{
+												match res {
+													Ok(secret) if *read_path == path => {
+														batched = Some(secret)
+													}
+													Ok(secret) => {
+														prefetched.insert(
+															(*read_path).to_owned(),
+															secret,
+														);
+													}
+													Err(e) => log::debug!(
+														"  batched read of \"{}\" failed: {}",
+														read_full_path,
+														e
+													),
+												}
+											}
+										}
+									}
+									let mut secret = match batched {
+										Some(secret) => secret,
+										None => loop {
+											let active = standby.as_ref().map(|url| {
+												std::mem::replace(&mut client.url, url.clone())
+											});
+											let res = client
+												.get_secret_async(
+													role,
+													&method,
+													&full_path,
+													params.as_ref(),
+												)
+												.await;
+											if let Some(active) = active {
+												client.url = active;
+											}
+											match res {
+												Err(e) if standby.is_some() => log::warn!(
+													"{} failed on {}, trying the active node: {}",
+													&full_path,
+													standby.take().unwrap_or_default(),
+													e
+												),
+												res => {
+													break res.with_context(|| {
+														format!(
+															"Getting the secret \"{}\"",
+															&full_path
+														)
+													})?
+												}
+											}
+										},
+									};
+									// version of a KV v2 secret before keeping only its data
+									let kv_version = secret.value["metadata"]["version"].as_u64();
+									if mount
+										.map(|mount| mount.unwrap(secret_path.full_path))
+										.unwrap_or(false)
+									{
+										secret.value = secret.value["data"].take();
+									}
+
+									// schedule the newewal of the secret which can trigger template generation
+									if let Some(renew_delay) = secret.renew_delay() {
+										log::debug!("  Renew secret within {:?}", renew_delay);
+										tasks
+											.schedule(
+												&sender,
+												Message::GetSecret(path.clone(), true),
+												renew_delay,
+											)
+											.await;
+									}
+
+									// save the lease to be able to resume it after a restart
+									if let Some(ref mut state_dir) = state_dir {
+										state_dir.save(&path, &secret)?;
+									}
+
+									// poll the metadata of static KV v2 secrets to detect new versions
+									if let (Some(interval), Some(version), false) =
+										(args.kv_poll, kv_version, secret.has_lease())
+									{
+										if kv_versions.insert(path.clone(), version).is_none() {
+											tasks
+												.schedule(
+													&sender,
+													Message::CheckVersion(path.clone()),
+													Duration::from_secs(interval),
+												)
+												.await;
+										}
+									}
+
+									// replace secret value an regenerate template if necessary
+									if secrets.replace(&path, secret) && gen_tmpl {
+										confs.generate_templates(&secrets, &path, &sender).await?;
+									}
+								}
+
+								backend => {
+									let source = sources::get(backend).ok_or_else(|| {
+										Error::ExpectedArg(
+											"a known backend".to_owned(),
+											path.to_string(),
+										)
+									})?;
+									let ctx = sources::Context {
+										max_secret_size: args.max_secret_size,
+										policy: &policy,
+										current_user: &current_user,
+										exe_user: &exe_user,
+										jwt,
+										etcd: &args.etcd_endpoint,
+										// the first template declaring the secret by name
+										template: confs
+											.iter()
+											.filter(|(_, conf)| conf.secrets.contains_key(&path))
+											.min_by_key(|(tmpl, _)| tmpl.as_str())
+											.map(|(_, conf)| conf),
+									};
+									let lease = source.lease(&secret_path)?;
+									let value = source.fetch(&secret_path, &ctx).await?;
+									// fetch leased secrets again when their lease expires
+									if let (true, Some(lease)) =
+										(args.daemon, lease.filter(|lease| !lease.is_zero()))
+									{
+										log::debug!("  Refresh secret within {:?}", lease);
+										tasks
+											.schedule(
+												&sender,
+												Message::Refresh(path.clone()),
+												lease,
+											)
+											.await;
+									}
+									// regenerate the templates as soon as the etcd keys change
+									if backend == Backend::Etcd
+										&& args.daemon && !watches.contains_key(&path)
+									{
+										let (key, prefix) = sources::etcd_key(&secret_path)?;
+										let watch = etcd::watch(
+											&args.etcd_endpoint,
+											key,
+											prefix,
+											&path,
+											&external,
+										)?;
+										log::debug!("  Watching etcd key \"{}\"", key);
+										watches.insert(path.clone(), watch);
+									}
+									if secrets.replace(&path, Secret::new(value, lease)) && gen_tmpl
+									{
+										confs.generate_templates(&secrets, &path, &sender).await?;
+									}
+								}
+							}
+							Ok::<_, anyhow::Error>(())
+						}
+						.instrument(span)
+						.await?;
+
+						// credentials expiring after a one-shot run are refused
+						if args.require_static {
+							if let Some(Some(secret)) = secrets.get(&path) {
+								if secret.has_lease() || secret.renew_delay().is_some() {
+									return Err(Error::NotStatic(path.clone()).into());
+								}
+							}
+						}
+
+						// fetch certificates again before they expire, independently of any lease
+						let not_after = secrets
+							.get(&path)
+							.and_then(|s| s.as_ref())
+							.and_then(|s| x509::not_after(&s.value));
+						// the secret doesn't hold a certificate anymore
+						if not_after.is_none() {
+							cert_checks.remove(&path);
+						}
+						if let (Some(threshold), Some(expiry)) =
+							(args.cert_threshold.map(Duration::from_secs), not_after)
+						{
+							let now = SystemTime::now();
+							if cert_checks
+								.get(&path)
+								.filter(|&&check| check > now)
+								.is_none()
+							{
+								let delay = expiry
+									.checked_sub(threshold)
+									.and_then(|t| t.duration_since(now).ok())
+									.unwrap_or_default()
+									.max(Duration::from_secs(CERT_MIN_DELAY));
+								log::debug!("  Refresh certificate within {:?}", delay);
+								cert_checks.insert(path.clone(), now + delay);
+								tasks
+									.schedule(&sender, Message::Refresh(path.clone()), delay)
+									.await;
+							}
+						}
+
+						// warn before the secret expires if it isn't renewed in time
+						if let Some(secret) = secrets.get(&path).and_then(|s| s.as_ref()) {
+							let now = SystemTime::now();
+							// leases are renewed at 2/3 of their duration
+							let lease_expiry = secret
+								.renew_delay()
+								.filter(|_| secret.has_lease())
+								.map(|delay| now + delay * 3 / 2);
+							let expiry = match (lease_expiry, x509::not_after(&secret.value)) {
+								(Some(lease), Some(cert)) => Some(lease.min(cert)),
+								(lease, cert) => lease.or(cert),
+							};
+							let renewable = secret.has_lease() && secret.renew_delay().is_some();
+							metas.insert(
+								path.clone(),
+								json!({
+									"lease_duration": lease_expiry
+										.and_then(|t| t.duration_since(now).ok())
+										.map(|d| d.as_secs())
+										.unwrap_or(0),
+									"expire_time": expiry
+										.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+										.map(|d| d.as_secs()),
+									"renewable": renewable,
+								}),
+							);
+							if let Some(expiry) = expiry {
+								expiries.insert(path.clone(), expiry);
+								status.set_expiring(&path, None);
+								if args.daemon {
+									// give the scheduled renewals a chance first
+									let renew_at = [
+										secret.renew_delay().map(|delay| now + delay),
+										cert_checks.get(&path).copied(),
+									]
+									.iter()
+									.flatten()
+									.min()
+									.map(|t| *t + Duration::from_secs(EXPIRY_MARGIN));
+									let check_at = expiry
+										.checked_sub(args.expiry_warning)
+										.unwrap_or(now)
+										.max(renew_at.unwrap_or(now));
+									tasks
+										.schedule(
+											&sender,
+											Message::CheckExpiry(path.clone()),
+											check_at.duration_since(now).unwrap_or_default(),
+										)
+										.await;
+								} else if let Ok(left) = expiry.duration_since(now) {
+									if left <= args.expiry_warning {
+										log::warn!(
+											"Secret \"{}\" expires within {:?}",
+											&path,
+											left
+										);
+									}
+								}
+							}
+						}
+					}
+				}
+
+				Message::Refresh(path) => {
+					// invalidate the secret so that it is fetched again
+					if let Some(secret) = secrets.get_mut(&path) {
+						log::info!("Refreshing secret \"{}\"", &path);
+						if let Some(mut prev) = secret.take() {
+							zeroize_value(&mut prev.value);
+						}
+						sender.send(Message::GetSecret(path, true)).await?;
+					} else {
+						log::warn!("Unknown secret \"{}\"", &path);
+					}
+				}
+
+				Message::CheckVersion(path) => {
+					// stop polling secrets not used anymore
+					if !secrets.contains_key(&path) {
+						kv_versions.remove(&path);
+						return Ok(());
+					}
+					let check = async {
+						let secret_path = SecretPath::<Backend>::try_from(path.as_str())
+							.with_context(|| format!("Parsing \"{}\"", path))?;
+						let role = secret_path
+							.args
+							.get(0)
+							.ok_or_else(|| Error::MissingRole(path.to_string()))?;
+						let session = session(&secret_path);
+						let client = clients.get(&session)?;
+						// only KV v2 engines have versions
+						let metadata_path = match kv_mounts
+							.detect(client, role, &session, &secret_path)
+							.await
+							.and_then(|mount| mount.metadata_path(secret_path.full_path))
+						{
+							Some(metadata_path) => metadata_path,
+							None => return Ok(None),
+						};
+						// a stale read from a standby is fine as it is checked again later
+						let active = match session.url {
+							Some(_) => None,
+							None => standbys.url("GET"),
+						}
+						.map(|url| std::mem::replace(&mut client.url, url.to_owned()));
+						let metadata = client
+							.get_secret_async(role, "GET", &metadata_path, None)
+							.await;
+						if let Some(active) = active {
+							client.url = active;
+						}
+						let metadata = metadata.with_context(|| {
+							format!("Getting the metadata \"{}\"", metadata_path)
+						})?;
+						Ok::<_, anyhow::Error>(metadata.value["current_version"].as_u64())
+					};
+					// a failed check is not fatal: the secret is still valid
+					match check.await {
+						Ok(Some(version)) if kv_versions.get(&path) != Some(&version) => {
+							// the new version is detected again by the next checks until a window opens
+							if window::until_open(&windows, SystemTime::now()).is_zero() {
+								log::info!("New version {} of secret \"{}\"", version, &path);
+								kv_versions.insert(path.clone(), version);
+								sender.send(Message::Refresh(path.clone())).await?;
+							} else {
+								log::debug!(
+									"  New version {} of secret \"{}\" deferred",
+									version,
+									&path
+								);
+							}
+						}
+						Ok(Some(_)) => {}
+						Ok(None) => {
+							log::debug!(
+								"  \"{}\" is not a KV v2 secret, stop polling its version",
+								&path
+							);
+							kv_versions.remove(&path);
+							return Ok(());
+						}
+						Err(e) => log::warn!("{}", redact(&format!("{:#}", e))),
+					}
+					if let Some(interval) = args.kv_poll {
+						tasks
+							.schedule(
+								&sender,
+								Message::CheckVersion(path),
+								Duration::from_secs(interval),
+							)
+							.await;
+					}
+				}
+
+				Message::Probe => {
+					if let Some(msg) = circuit.probe() {
+						log::info!("Probing vault");
+						sender.send(msg).await?;
+					}
+				}
+
+				Message::CheckExpiry(path) => {
+					if let Some(expiry) = expiries.get(&path) {
+						let left = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+						if left <= args.expiry_warning {
+							log::warn!(
+								"Secret \"{}\" expires within {:?} and hasn't been renewed",
+								&path,
+								left
+							);
+							status.set_expiring(&path, Some(*expiry));
+						}
+					}
+				}
+
+				Message::RenderAll => {
+					log::info!("Regenerating all templates");
+					confs.generate_all_templates(&secrets, &sender).await?;
+				}
+
+				Message::Reconcile => {
+					log::info!("Reconciling all templates and secrets");
+					// rewrite the files modified out of band with the current secrets first
+					confs.generate_all_templates(&secrets, &sender).await?;
+					// then regenerate the templates whose secrets changed
+					for path in secrets.keys() {
+						sender.send(Message::Refresh(path.clone())).await?;
+					}
+					if let Some(interval) = args.reconcile {
+						delay_task(send_message(sender.clone(), Message::Reconcile), interval);
+					}
+				}
+
+				Message::CheckReady => {
+					// the readiness is checked after the message is processed
+					waiting_files = false;
+				}
+
+				Message::ReadyTimeout => {
+					if !status.is_ready() {
+						// templates neither generated nor skipped, and missing files
+						let pending: Vec<&str> = confs
+							.critical_pending(&outputs, &failed)
+							.into_iter()
+							.chain(confs.missing_files(true))
+							.collect();
+						let e = anyhow::Error::from(Error::ReadyTimeout(
+							args.ready_timeout.unwrap_or_default(),
+							pending.join(", "),
+						))
+						.context(Failure::Timeout);
+						if !args.daemon {
+							return Err(e);
+						}
+						// a daemon keeps on trying
+						log::error!("{:#}", e);
+						reporter.report(&Failure::Timeout.to_string(), &[], &e);
+						// let the pending templates handle their failure
+						for tmpl in confs.critical_pending(&outputs, &failed) {
+							if let Some(conf) = confs.get(tmpl) {
+								if let Err(e) =
+									conf.hooks.trigger(HookType::Error, &policy, &[], conf)
+								{
+									log::error!("{}", redact(&format!("{:#}", e)));
+									reporter.report(
+										"hook",
+										&[("template", tmpl), ("hook", "error")],
+										&e,
+									);
+								}
+							}
+						}
+					}
+				}
+
+				Message::CheckTampered => {
+					for (tmpl, (files, dirs)) in outputs.iter() {
+						let mut tampered = Vec::new();
+						for path in files.iter() {
+							if checksums
+								.is_tampered(path)
+								.await
+								.with_context(|| format!("Calculating checksum of {:?}", path))?
+							{
+								tampered.push(path.to_string_lossy());
+							}
+						}
+						if tampered.is_empty() {
+							continue;
+						}
+						log::warn!("{} modified by someone else", tampered.join(", "));
+						if let Some(conf) = confs.get(tmpl) {
+							let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+							if let Err(e) =
+								conf.hooks.trigger(HookType::Tampered, &policy, &dirs, conf)
+							{
+								log::error!("{}", redact(&format!("{:#}", e)));
+								reporter.report(
+									"hook",
+									&[("template", tmpl.as_str()), ("hook", "tampered")],
+									&e,
+								);
+							}
+							// rewrite the files with the last rendered content
+							if conf.restore {
+								log::info!("Restoring the files of {}", tmpl);
+								sender.send(Message::GenerateTemplate(tmpl.clone())).await?;
+							}
+						}
+					}
+					if let Some(interval) = args.tamper_check {
+						delay_task(
+							send_message(sender.clone(), Message::CheckTampered),
+							interval,
+						);
+					}
+				}
+
+				Message::Reload => {
+					log::info!("Reloading config files");
+					// a bad config doesn't stop a running daemon
+					match load_confs(args, &mut secrets, state_dir.as_ref(), &sender, &mut tasks)
+						.await
+					{
+						Ok(new_confs) => {
+							// forget the secrets not used anymore
+							let used = new_confs
+								.values()
+								.flat_map(|conf| conf.secrets.keys().cloned())
+								.collect::<HashSet<_>>();
+							let unused = secrets
+								.keys()
+								.filter(|path| !used.contains(*path))
+								.cloned()
+								.collect::<Vec<_>>();
+							for path in unused.iter() {
+								log::info!("Forgetting secret \"{}\"", path);
+								// revoke the leases nobody renews anymore
+								let leased = secrets
+									.get(path)
+									.and_then(Option::as_ref)
+									.map(Secret::has_lease)
+									.unwrap_or(false);
+								if args.revoke_unused && leased {
+									if let Err(e) = revoke_leases(&mut clients, path).await {
+										log::warn!("Revoking the leases of \"{}\": {:#}", path, e);
+									}
+								}
+								secrets.forget(path);
+								kv_versions.remove(path);
+								cert_checks.remove(path);
+								expiries.remove(path);
+								metas.remove(path);
+								prefetched.remove(path);
+								watches.remove(path);
+								status.set_expiring(path, None);
+								if let Some(ref mut state_dir) = state_dir {
+									state_dir.remove(path)?;
+								}
+							}
+							// roles and sessions still needed by vault secrets
+							let logins = used
+								.iter()
+								.filter_map(|path| {
+									SecretPath::<Backend>::try_from(path.as_str()).ok()
+								})
+								.filter(|secret| secret.backend == Backend::Vault)
+								.filter_map(|secret| {
+									secret
+										.args
+										.get(0)
+										.map(|role| ((*role).to_owned(), session(&secret)))
+								})
+								.collect::<HashSet<_>>();
+							token_ttls.retain(|login, _| logins.contains(login));
+							login_errors.retain(|login, _| logins.contains(login));
+							// stop renewing and checking what nobody needs anymore
+							let keep = |msg: &Message| match msg {
+								Message::Login(role, session)
+								| Message::RenewToken(role, session) => {
+									let login = (role.clone(), session.clone());
+									logins.contains(&login)
+								}
+								msg => msg.secret().map(|path| used.contains(path)).unwrap_or(true),
+							};
+							let cancelled = tasks.retain(keep).await;
+							if cancelled > 0 {
+								log::debug!("  cancelled {} scheduled tasks", cancelled);
+							}
+							// nor replay them once vault is reachable again
+							let dropped = circuit.retain(keep);
+							if dropped > 0 {
+								log::debug!("  dropped {} deferred requests", dropped);
+							}
+							confs = new_confs;
+							generated = 0;
+							failed.clear();
+							status.set_templates(confs.len(), 0);
+							confs.generate_all_templates(&secrets, &sender).await?;
+						}
+						Err(e) => {
+							log::error!("Keeping current config: {}", redact(&format!("{:#}", e)))
+						}
+					}
+				}
+
+				Message::GenerateTemplate(tmpl) => {
+					// the template is generated again when its phase starts
+					if confs.phase(&tmpl, &args.phase) > phase {
+						log::debug!(
+							"  GenerateTemplate({}) waiting for phase {}",
+							&tmpl,
+							&args.phase[phase]
+						);
+						return Ok(());
+					}
+					log::info!(
+						"Manifestations of {} ({}/{})",
+						&tmpl,
+						generated + 1,
+						confs.len()
+					);
+					let span = tracing::info_span!(
+						"render",
+						template = %tmpl,
+						labels = %fmt_labels(&labels)
+					);
+					async {
+						let conf = confs.get(&tmpl);
+						if let Some(conf) = conf {
+							// the template is generated again once the files it embeds are generated
+							let written: Vec<&Path> = outputs
+								.values()
+								.flat_map(|(files, _)| files.iter().map(PathBuf::as_path))
+								.collect();
+							let missing = conf.missing_inputs(args.root.as_deref(), &written);
+							if !missing.is_empty() {
+								log::info!("  waiting for {}", missing.join(", "));
+								return Ok(());
+							}
+							// file_key: content of the files generated by other templates to inject in "files" extVar
+							let inputs_val = conf
+								.read_inputs(args.root.as_deref(), &written)
+								.with_context(|| format!("Reading the files embedded by {}", &tmpl))
+								.context(Failure::Template)?;
+							// secret_key: secret_value to inject in "secrets" extVar
+							let mut secrets_val = Map::with_capacity(secrets.len());
+							// secret_key: lease and expiry of the secret to inject in "secrets_meta" extVar
+							let mut metas_val = Map::new();
+							for (path, secret) in secrets.iter() {
+								// all secrets should have been fetched at that point
+								if let Some(secret) = secret {
+									// add only the secrets declared in the template config
+									if let Some(name) = conf.secrets.get(path) {
+										insert_nested(&mut secrets_val, name, secret.value.clone());
+										if let Some(meta) = metas.get(path) {
+											insert_nested(&mut metas_val, name, meta.clone());
+										}
+									}
+								}
+							}
+							// deep merge secrets into new entries, the last ones taking precedence
+							for (name, sources) in conf.merge.iter() {
+								let mut merged = Value::Object(Map::new());
+								for source in sources.iter() {
+									match get_nested(&secrets_val, source) {
+										Some(value) => merge_values(&mut merged, value),
+										None => log::warn!(
+											"Unknown secret {} merged into {}",
+											source,
+											name
+										),
+									}
+								}
+								insert_nested(&mut secrets_val, name, merged);
+							}
+							let start = Instant::now();
+							// the contents are scrubbed once the files are written and manifests dropped
+							let mut manifests = renderer.render(
+								&tmpl,
+								conf,
+								Value::Object(secrets_val),
+								Value::Object(metas_val),
+								Value::Object(inputs_val),
+							)?;
+							let render_time = start.elapsed();
+
+							// parse ACL entries
+							let acl = conf
+								.acl
+								.iter()
+								.map(|entry| entry.parse::<acl::Entry>())
+								.collect::<result::Result<Vec<_>>>()
+								.context(Failure::Config)?;
+
+							// encryption is not reproducible
+							if deterministic.is_some() && conf.encrypt.is_some() {
+								return Err(anyhow::Error::from(Error::NotDeterministic(
+									"encrypt".to_owned(),
+								))
+								.context(Failure::Config));
+							}
+
+							// files whose content changed
+							let mut changed = Vec::new();
+							// all the files generated
+							let mut files = Vec::new();
+							if deterministic.is_some() {
+								manifests.sort_by(|a, b| (&a.0 as &str).cmp(&b.0 as &str));
+							}
+							// value of a secret declared in the template config by its name
+							let lookup = |name: &str| {
+								conf.secrets
+									.iter()
+									.find(|(_, secret_name)| secret_name.as_str() == name)
+									.and_then(|(path, _)| secrets.get(path))
+									.and_then(|secret| secret.as_ref())
+									.map(|secret| &secret.value)
+							};
+							// write the files in the shadow directory first, and validate them there before
+							// touching the destinations
+							if let Some(ref shadow) = conf.shadow {
+								// the rendered secrets don't outlive the validation
+								let mut shadow_files = ShadowFiles(Vec::new());
+								for (file, data) in manifests.iter() {
+									let path = rooted(
+										Some(shadow.as_str()),
+										PathBuf::from(
+											subst_path(file, lookup)
+												.with_context(|| {
+													format!("Interpolating file \"{}\"", file)
+												})
+												.context(Failure::Template)?,
+										),
+									);
+									if let Some(dir) = path.parent() {
+										create_dir_all(dir)
+											.with_context(|| format!("Creating {:?}", dir))
+											.context(Failure::Write)?;
+										// shadow files are in clear, even those of encrypted templates
+										if (args.require_tmpfs || conf.encrypt.is_some())
+											&& !is_memory_fs(dir)
+												.with_context(|| {
+													format!("Checking filesystem of {:?}", dir)
+												})
+												.context(Failure::Write)?
+										{
+											return Err(anyhow::Error::from(Error::NotMemoryFs(
+												dir.to_string_lossy().into_owned(),
+											))
+											.context(Failure::Write));
+										}
+									}
+									let mut decoded = None;
+									let content = chunks(conf.output, file, data, &mut decoded)
+										.context(Failure::Template)?;
+									let shadow_file = OpenOptions::new()
+										.write(true)
+										.create(true)
+										.truncate(true)
+										.mode(0o600)
+										.open(&path)
+										.with_context(|| format!("Creating {:?}", &path))
+										.context(Failure::Write)?;
+									shadow_files.0.push(path.clone());
+									content
+										.iter()
+										.try_for_each(|chunk| (&shadow_file).write_all(chunk))
+										.with_context(|| format!("Writing {:?}", &path))
+										.context(Failure::Write)?;
+								}
+								let staged: Vec<String> = shadow_files
+									.0
+									.iter()
+									.map(|path| path.to_string_lossy().into_owned())
+									.collect();
+								let staged: Vec<&str> = staged.iter().map(String::as_str).collect();
+								conf.hooks
+									.validate(&policy, shadow, &staged, conf)
+									.with_context(|| {
+										format!("Validating the files of {} in {}", &tmpl, shadow)
+									})
+									.context(Failure::Template)?;
+							}
+
+							// output directories with %{...} expressions substituted
+							let mut dirs = Vec::new();
+							// write the files in dir and in the additional targets
+							for dest in conf.destinations() {
+								// parse file mode
+								let mode = u32::from_str_radix(dest.mode, 8);
+								if mode.is_err() {
+									log::error!("Unable to parse file mode: {}", dest.mode);
+								}
+								// parse directory mode
+								let dir_mode = dest.dir_mode.and_then(|dir_mode| {
+									u32::from_str_radix(dir_mode, 8)
+										.map_err(|_| {
+											log::error!(
+												"Unable to parse directory mode: {}",
+												dir_mode
+											)
+										})
+										.ok()
+								});
+
+								// get user
+								let user = User::new(dest.user);
+								if let Some(ref user) = user {
+									if &current_user != user && current_user.gid != 0 {
+										log::warn!(
+											"user \"{}\" is not the unprivileged rconfd user",
+											dest.user
+										)
+									}
+								}
+
+								// output directory with %{...} expressions substituted
+								let conf_dir = subst_path(dest.dir, lookup)
+									.with_context(|| format!("Interpolating dir \"{}\"", dest.dir))
+									.context(Failure::Template)?;
+								let live_dir =
+									rooted(args.root.as_deref(), PathBuf::from(&conf_dir));
+								// new version of the directory where all files are written before the symlink flip
+								let version_dir = match conf.swap {
+									Some(Swap::Symlink) => Some(swap::version(&live_dir)),
+									_ => None,
+								};
+								// files written (staged or not) with their final path
+								let mut staged = Vec::new();
+								let mut stale = false;
+								// generate files from template top keys
+								for (file, data) in manifests.iter() {
+									let mut path = PathBuf::from(&conf_dir);
+									path.push(
+										subst_path(file, lookup)
+											.with_context(|| {
+												format!("Interpolating file \"{}\"", file)
+											})
+											.context(Failure::Template)?,
+									);
+									let path = rooted(args.root.as_deref(), path);
+									let span = tracing::info_span!("write", path = ?path);
+									async {
+										files.push(path.clone());
+										// keep the owner and mode of the file being replaced if managed by other tooling
+										let inherited = fs::metadata(&path)
+											.ok()
+											.filter(|_| conf.inherit_owner)
+											.map(|meta| {
+												(
+													meta.mode() & 0o7777,
+													User {
+														uid: meta.uid(),
+														gid: meta.gid(),
+													},
+												)
+											});
+										let mode = match inherited {
+											Some((mode, _)) => Ok(mode),
+											None => mode.clone(),
+										};
+										let user = match inherited {
+											Some((_, ref user)) => Some(user),
+											None => user.as_ref(),
+										};
+										// where the file is written before being swapped into place
+										let target = match version_dir {
+											Some(ref version_dir) => {
+												swap::versioned(&live_dir, version_dir, &path)
+													.context(Failure::Write)?
+											}
+											None if conf.swap.is_some() => swap::staged(&path),
+											None => path.clone(),
+										};
+										// dirname after joining the output directory and file
+										let mut dir = target.clone();
+										dir.pop();
+										if let Some(dir_mode) = dir_mode {
+											DirBuilder::new()
+												.recursive(true)
+												.mode(dir_mode)
+												.create(&dir)
+										} else {
+											create_dir_all(&dir)
+										}
+										.with_context(|| format!("Creating {:?}", &dir))
+										.context(Failure::Write)?;
+										// secrets must not end up on disk
+										if args.require_tmpfs
+											&& !is_memory_fs(&dir)
+												.with_context(|| {
+													format!("Checking filesystem of {:?}", &dir)
+												})
+												.context(Failure::Write)?
+										{
+											return Err(anyhow::Error::from(Error::NotMemoryFs(
+												dir.to_string_lossy().into_owned(),
+											))
+											.context(Failure::Write));
+										}
+
+										// if path exists then it's not really first run
+										if first_run && path.exists() {
+											first_run = false;
+										}
+
+										// file content in chunks, to avoid copying big manifests
+										let mut decoded = None;
+										let content = chunks(conf.output, file, data, &mut decoded)
+											.context(Failure::Template)?;
+										// only rewrite the files whose content changed
+										let unchanged = conf.encrypt.is_none()
+											&& checksums.is_current(&path, &content).await;
+										// a new version of the directory needs all the files
+										if unchanged && version_dir.is_none() {
+											log::info!(
+												"  {} unchanged",
+												path.to_str().expect("path")
+											);
+											summary.unchanged += 1;
+											// ownership and permissions may have changed though
+											if let Ok(mode) = mode {
+												fs::set_permissions(
+													&path,
+													fs::Permissions::from_mode(mode),
+												)
+												.with_context(|| {
+													format!("Setting permissions of {:?}", &path)
+												})
+												.context(Failure::Write)?;
+											}
+											if let Some(ref user) = user {
+												user.chown(&path);
+											}
+											return Ok(());
+										}
+
+										stale |= !unchanged;
+
+										// protect files we don't own
+										if !unchanged
+											&& args.no_clobber && !checksums
+											.is_owned(&path)
+											.await
+											.with_context(|| {
+												format!("Calculating checksum of {:?}", &path)
+											})
+											.context(Failure::Write)?
+										{
+											return Err(anyhow::Error::from(Error::Clobber(
+												path.to_string_lossy().into_owned(),
+											))
+											.context(Failure::Write));
+										}
+
+										// create the file with its final permissions before writing anything
+										let mut options = OpenOptions::new();
+										options.write(true).create(true).truncate(true);
+										if let Ok(mode) = mode {
+											options.mode(mode);
+										}
+										let file = options
+											.open(&target)
+											.with_context(|| format!("Creating {:?}", &target))
+											.context(Failure::Write)?;
+										// set file permissions (the file may already exist and the umask may be in the way)
+										if let Ok(mode) = mode {
+											fs::set_permissions(
+												&target,
+												fs::Permissions::from_mode(mode),
+											)
+											.with_context(|| {
+												format!("Setting permissions of {:?}", &path)
+											})
+											.context(Failure::Write)?;
+										}
+										// label the file
+										if let Some(ref context) = conf.selinux_context {
+											// the context is expected to be nul terminated
+											set_xattr(
+												&file,
+												"security.selinux",
+												format!("{}\0", context).as_bytes(),
+											)
+											.with_context(|| {
+												format!("Setting SELinux context of {:?}", &path)
+											})
+											.context(Failure::Write)?;
+										}
+										for (name, value) in conf.xattrs.iter() {
+											set_xattr(&file, name, value.as_bytes())
+												.with_context(|| {
+													format!(
+														"Setting attribute {} of {:?}",
+														name, &path
+													)
+												})
+												.context(Failure::Write)?;
+										}
+										// give access to other users and groups
+										if !acl.is_empty() {
+											let mode = file.metadata()?.permissions().mode();
+											set_xattr(
+												&file,
+												acl::ACL_XATTR,
+												&acl::encode(mode, &acl),
+											)
+											.with_context(|| format!("Setting ACL of {:?}", &path))
+											.context(Failure::Write)?;
+										}
+										// write file (encrypted if asked to) chunk by chunk
+										if let Some(ref encrypt) = conf.encrypt {
+											encrypt::age(&encrypt.age, &file, &content)
+												.with_context(|| format!("Encrypting {:?}", &path))
+												.context(Failure::Write)?;
+										} else {
+											content
+												.iter()
+												.try_for_each(|chunk| (&file).write_all(chunk))
+												.with_context(|| format!("Writing {:?}", &path))
+												.context(Failure::Write)?;
+										}
+										// pin the modification time
+										if let Some(epoch) = deterministic {
+											set_times(&file, epoch)
+												.with_context(|| {
+													format!("Setting times of {:?}", &path)
+												})
+												.context(Failure::Write)?;
+										}
+										if unchanged {
+											log::info!(
+												"  {} unchanged",
+												path.to_str().expect("path")
+											);
+											summary.unchanged += 1;
+										} else {
+											log::info!(
+												"  {} generated",
+												path.to_str().expect("path")
+											);
+											summary.written += 1;
+										}
+										// set file group and owner
+										if let Some(ref user) = user {
+											user.chown(&target);
+										}
+										// the content of encrypted files is only known once written
+										let hashed = conf
+											.encrypt
+											.is_none()
+											.then(|| checksums.hash_content(&path, &content));
+										staged.push((target, path, hashed));
+										Ok::<_, anyhow::Error>(())
+									}
+									.instrument(span)
+									.await?;
+								}
+								// validate the staged files before swapping them into place
+								let staged_files = staged
+									.iter()
+									.filter(|(target, path, _)| target != path)
+									.map(|(target, _, _)| target.to_string_lossy().into_owned())
+									.collect::<Vec<_>>();
+								if stale
+									&& conf.shadow.is_none() && conf.hooks.validate.is_some()
+									&& !staged_files.is_empty()
+								{
+									let staged_dir =
+										version_dir.as_ref().unwrap_or(&live_dir).to_string_lossy();
+									let staged_files: Vec<&str> =
+										staged_files.iter().map(String::as_str).collect();
+									if let Err(e) = conf.hooks.validate(
+										&policy,
+										&staged_dir,
+										&staged_files,
+										conf,
+									) {
+										// leave the live files untouched
+										match version_dir {
+											Some(ref version_dir) => {
+												fs::remove_dir_all(version_dir).ok()
+											}
+											None => staged_files
+												.iter()
+												.try_for_each(fs::remove_file)
+												.ok(),
+										};
+										return Err(e
+											.context(format!(
+												"Validating the files of {} in {}",
+												&tmpl, staged_dir
+											))
+											.context(Failure::Template));
+									}
+								}
+								// swap the staged files into place together
+								if let Some(ref version_dir) = version_dir {
+									if stale {
+										swap::flip(&live_dir, version_dir)
+											.with_context(|| format!("Swapping {:?}", &live_dir))
+											.context(Failure::Write)?;
+									} else {
+										fs::remove_dir_all(version_dir)
+											.with_context(|| format!("Removing {:?}", version_dir))
+											.context(Failure::Write)?;
+									}
+								} else {
+									for (target, path, _) in
+										staged.iter().filter(|(target, path, _)| target != path)
+									{
+										fs::rename(target, path)
+											.with_context(|| format!("Renaming {:?}", target))
+											.context(Failure::Write)?;
+									}
+								}
+								// save checksums and compare with previous ones
+								for (_, path, hashed) in staged.into_iter() {
+									let modified = match hashed {
+										Some((digest, modified)) => {
+											checksums.insert(path.clone().into(), Some(digest));
+											modified
+										}
+										None => checksums
+											.hash_file(&path)
+											.await
+											.with_context(|| {
+												format!("Calculating checksum of \"{:?}\"", &path)
+											})
+											.context(Failure::Write)?,
+									};
+									if modified {
+										changed.push(path.to_string_lossy().into_owned());
+									}
+								}
+								dirs.push(live_dir.to_string_lossy().into_owned());
+							}
+							let changes = !changed.is_empty();
+
+							// generate again the templates embedding the changed files, or waiting for the files
+							// generated for the first time
+							let first_output = !outputs.contains_key(&tmpl);
+							if changes || first_output {
+								let produced: Vec<&Path> = if first_output {
+									files.iter().map(PathBuf::as_path).collect()
+								} else {
+									changed.iter().map(Path::new).collect()
+								};
+								for (dependent, dependent_conf) in confs.iter() {
+									if *dependent != tmpl
+										&& dependent_conf
+											.depends_on(args.root.as_deref(), &produced)
+									{
+										sender
+											.send(Message::GenerateTemplate(dependent.clone()))
+											.await?;
+									}
+								}
+							}
+
+							// measure the template cost for capacity planning
+							let write_time = start.elapsed() - render_time;
+							log::debug!(
+								"  evaluated in {:?}, written in {:?}",
+								render_time,
+								write_time
+							);
+							if let Some(threshold) = args.slow_template {
+								if render_time + write_time > Duration::from_millis(threshold) {
+									log::warn!(
+										"Slow template {}: evaluated in {:?}, written in {:?}",
+										&tmpl,
+										render_time,
+										write_time
+									);
+								}
+							}
+							status.set_timings(&tmpl, render_time, write_time);
+
+							// save the checksums to recognize unchanged files after a restart
+							if changes {
+								if let Some(ref state_dir) = state_dir {
+									checksums
+										.save(&state_dir.path(CHECKSUMS_FILE))
+										.context(Failure::Write)?;
+								}
+							}
+
+							// notify the other daemons
+							#[cfg(feature = "dbus")]
+							if changes {
+								if let Some(ref dbus) = dbus {
+									dbus.files_changed(&tmpl, changed).await;
+								}
+							}
+
+							// if checksums changed and not on first run, then trigger modified hook
+							// a template migrated onto a running host doesn't reload its services right away
+							let skip_hook =
+								conf.hooks.skip_first_run && !outputs.contains_key(&tmpl);
+							if changes && !first_run && skip_hook {
+								log::info!(
+									"Skipping the modified hook of \"{}\" on its first run",
+									&tmpl
+								);
+							} else if changes && !first_run && circuit.is_open() {
+								log::warn!(
+									"Skipping the modified hook of \"{}\": vault is unreachable",
+									&tmpl
+								);
+							} else if changes && !first_run {
+								let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+								if let Err(e) =
+									conf.hooks.trigger(HookType::Modified, &policy, &dirs, conf)
+								{
+									log::error!("{}", redact(&format!("{:#}", e)));
+									reporter.report(
+										"hook",
+										&[("template", tmpl.as_str()), ("hook", "modified")],
+										&e,
+									);
+									hook_failed = true;
+								}
+							}
+
+							// increment generated counter
+							generated += 1;
+							summary.templates += 1;
+							failed.remove(&tmpl);
+							last_tmpl = Some(tmpl.clone());
+							outputs.insert(tmpl.clone(), (files, dirs.clone()));
+							last_dirs = dirs;
+						}
+						Ok::<_, anyhow::Error>(())
+					}
+					.instrument(span)
+					.await?;
+				}
+			}
+			Ok::<(), anyhow::Error>(())
+		}
+		.await;
+
+		// record the secret access
+		if let (Some(audit), Message::GetSecret(path, _)) = (&mut audit, &msg_copy) {
+			if fetched || res.is_err() {
+				let secret_path = SecretPath::<Backend>::try_from(path.as_str()).ok();
+				let backend = secret_path
+					.as_ref()
+					.map(|s| s.backend.to_string())
+					.unwrap_or_default();
+				audit.record(&Entry {
+					path,
+					backend: &backend,
+					role: secret_path
+						.as_ref()
+						.and_then(|s| s.args.get(0).copied())
+						.unwrap_or(""),
+					templates: confs
+						.iter()
+						.filter(|(_, conf)| conf.secrets.contains_key(path))
+						.map(|(tmpl, _)| tmpl.as_str())
+						.collect(),
+					labels: &labels,
+					result: match &res {
+						Ok(_) => "ok".to_owned(),
+						Err(e) => redact(&format!("{:#}", e)),
+					},
+				});
+			}
+		}
+
+		// classify the failure according to the message if not already done
+		let res = res.map_err(|e| match Failure::of(&e) {
+			Some(_) => e,
+			None => e.context(match &msg_copy {
+				Message::Login(_, _) | Message::RenewToken(_, _) => Failure::Auth,
+				Message::GetSecret(_, _) => Failure::Secret,
+				Message::Refresh(_)
+				| Message::CheckVersion(_)
+				| Message::CheckExpiry(_)
+				| Message::Probe => Failure::Secret,
+				Message::GenerateTemplate(_) | Message::RenderAll | Message::Reconcile => {
+					Failure::Template
+				}
+				Message::Reload | Message::CheckReady => Failure::Config,
+				Message::CheckTampered => Failure::Write,
+				Message::ReadyTimeout => Failure::Timeout,
+			}),
+		});
+		// report the failure tagged with the template or secret concerned
+		if let Err(ref e) = res {
+			let kind = Failure::of(e).map(|f| f.to_string()).unwrap_or_default();
+			let mut tags = match &msg_copy {
+				Message::Login(role, _) | Message::RenewToken(role, _) => {
+					vec![("role", role.as_str())]
+				}
+				Message::GetSecret(path, _)
+				| Message::Refresh(path)
+				| Message::CheckVersion(path)
+				| Message::CheckExpiry(path) => vec![("secret", path.as_str())],
+				Message::GenerateTemplate(tmpl) => vec![("template", tmpl.as_str())],
+				Message::Reload
+				| Message::RenderAll
+				| Message::Reconcile
+				| Message::CheckReady
+				| Message::CheckTampered
+				| Message::ReadyTimeout
+				| Message::Probe => vec![],
+			};
+			tags.extend(
+				labels
+					.iter()
+					.map(|(key, value)| (key.as_str(), value.as_str())),
+			);
+			reporter.report(&kind, &tags, e);
+		}
+		logger::set_labels(String::new());
+		// once the files have been generated, a vault outage opens the circuit instead of aborting
+		if circuit.is_enabled() && !first_run && is_vault(&msg_copy) {
+			match &res {
+				Ok(_) if deferred => continue,
+				Ok(_) => {
+					if let Some(msgs) = circuit.success() {
+						log::info!("Vault is reachable again: closing the circuit");
+						status.set_degraded(false);
+						for msg in msgs {
+							sender.send(msg).await?;
+						}
+					}
+				}
+				Err(e) => {
+					log::warn!("{}", redact(&format!("{:?}", e)));
+					if circuit.failure() {
+						if !status.is_degraded() {
+							log::error!("Too many vault failures: opening the circuit");
+							status.set_degraded(true);
+						}
+						circuit.defer(msg_copy);
+						delay_task(
+							send_message(sender.clone(), Message::Probe),
+							args.circuit_probe,
+						);
+					} else {
+						delay_task(send_message(sender.clone(), msg_copy), args.circuit_probe);
+					}
+					continue;
+				}
+			}
+		}
+		// in keep-going mode, skip the templates concerned by a failure instead of aborting
+		if let Err(e) = res {
+			if !args.skips_failures() {
+				return Err(e);
+			}
+			log::warn!("{}", redact(&format!("{:?}", e)));
+			match msg_copy {
+				Message::GetSecret(path, _) | Message::Refresh(path) => {
+					for (tmpl, conf) in confs.iter() {
+						if conf.secrets.contains_key(&path) && failed.insert(tmpl.clone()) {
+							log::warn!("skipping template \"{}\" due to failed secret", tmpl);
+						}
+					}
+				}
+				Message::GenerateTemplate(tmpl) => {
+					failed.insert(tmpl);
+				}
+				Message::Login(role, login) | Message::RenewToken(role, login) => {
+					// the secrets of the templates using that login can't be fetched
+					for (tmpl, conf) in confs.iter() {
+						let uses_login = conf
+							.secrets
+							.keys()
+							.filter_map(|path| SecretPath::<Backend>::try_from(path.as_str()).ok())
+							.any(|secret| {
+								secret.backend == Backend::Vault
+									&& secret.args.get(0) == Some(&role.as_str())
+									&& session(&secret) == login
+							});
+						if uses_login && failed.insert(tmpl.clone()) {
+							log::warn!("skipping template \"{}\" due to failed login", tmpl);
+						}
+					}
+				}
+				_ => {}
+			}
+		} else if !matches!(msg_copy, Message::GenerateTemplate(_) | Message::CheckReady) {
+			continue;
+		}
+
+		// start the next phase once all the templates of the current one are generated and their ready
+		// hooks executed
+		while phase < args.phase.len()
+			&& confs
+				.in_phase(phase, &args.phase)
+				.iter()
+				.all(|(tmpl, _)| outputs.contains_key(*tmpl) || failed.contains(*tmpl))
+		{
+			for (tmpl, conf) in confs.in_phase(phase, &args.phase) {
+				if let Some((_, dirs)) = outputs.get(tmpl) {
+					let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+					if let Err(e) = conf.hooks.trigger(HookType::Ready, &policy, &dirs, conf) {
+						reporter.report(
+							"hook",
+							&[("template", tmpl.as_str()), ("hook", "ready")],
+							&e,
+						);
+						return Err(e
+							.context(format!("Phase {}", &args.phase[phase]))
+							.context(Failure::Hook));
+					}
+				}
+			}
+			log::info!("Phase {} complete", &args.phase[phase]);
+			phase += 1;
+			for (tmpl, conf) in confs.in_phase(phase, &args.phase) {
+				if conf
+					.secrets
+					.keys()
+					.all(|path| matches!(secrets.get(path), Some(Some(_))))
+				{
+					sender.send(Message::GenerateTemplate(tmpl.clone())).await?;
+				}
+			}
+		}
+
+		// signal readiness once the critical templates are generated, the other ones going on in the background
+		if !status.is_ready()
+			&& !hook_failed
+			&& generated + failed.len() < confs.len()
+			&& confs.values().any(|conf| !conf.critical)
+			&& confs.critical_pending(&outputs, &failed).is_empty()
+		{
+			let missing = confs.missing_files(true);
+			// with --ready-after-hooks, the ready hook of the template completing the critical ones must succeed
+			// first (the ready hooks of the templates with a phase are executed when it completes)
+			let ready_tmpl = last_tmpl
+				.as_ref()
+				.filter(|_| args.ready_after_hooks && missing.is_empty())
+				.filter(|tmpl| confs.phase(tmpl, &args.phase) == args.phase.len())
+				.and_then(|tmpl| confs.get(tmpl).map(|conf| (tmpl, conf)));
+			if let Some((tmpl, conf)) = ready_tmpl {
+				let dirs: Vec<&str> = last_dirs.iter().map(String::as_str).collect();
+				if let Err(e) = conf.hooks.trigger(HookType::Ready, &policy, &dirs, conf) {
+					log::error!("{}", redact(&format!("{:#}", e)));
+					reporter.report(
+						"hook",
+						&[("template", tmpl.as_str()), ("hook", "ready")],
+						&e,
+					);
+					log::error!("Not ready: a hook failed");
+					hook_failed = true;
+				}
+			}
+			if missing.is_empty() && !hook_failed {
+				log::info!("Ready: the non critical templates are still being generated");
+				s6_ready(ready_fd.take());
+				status.set_ready(true);
+				status.set_templates(confs.len(), failed.len());
+			} else if !missing.is_empty() && !waiting_files {
+				log::info!("Waiting for {}", missing.join(", "));
+				delay_task(
+					send_message(sender.clone(), Message::CheckReady),
+					Duration::from_secs(READY_POLL),
+				);
+				waiting_files = true;
+			}
+		}
+
+		// if all templates have been generated or skipped
+		if generated + failed.len() >= confs.len() {
+			// reset generated
+			generated = 0;
+			// first_run complete
+			first_run = false;
+			ready_pending = true;
+		}
+		if ready_pending {
+			// wait for the files required by the templates
+			let missing = confs.missing_files(false);
+			if !missing.is_empty() {
+				if !waiting_files {
+					log::info!("Waiting for {}", missing.join(", "));
+					delay_task(
+						send_message(sender.clone(), Message::CheckReady),
+						Duration::from_secs(READY_POLL),
+					);
+					waiting_files = true;
+				}
+				continue;
+			}
+			ready_pending = false;
+			if !failed.is_empty() {
+				log::warn!(
+					"Ready in degraded mode: {} template(s) skipped",
+					failed.len()
+				);
+			}
+			// quit if not in daemon mode or no dynamic secrets used among templates
+			let exit = !args.daemon
+				|| (!secrets.any_leased()
+					&& cert_checks.is_empty()
+					&& kv_versions.is_empty()
+					&& args.reconcile.is_none()
+					&& args.trigger.is_none()
+					&& args.tamper_check.is_none());
+			// trigger ready hook if defined and return false if it failed
+			let trigger_ready = || {
+				// the ready hooks of the templates with a phase are executed when it completes
+				if let Some((tmpl, conf)) = last_tmpl
+					.as_ref()
+					.filter(|tmpl| confs.phase(tmpl, &args.phase) == args.phase.len())
+					.and_then(|tmpl| confs.get(tmpl).map(|conf| (tmpl, conf)))
+				{
+					let dirs: Vec<&str> = last_dirs.iter().map(String::as_str).collect();
+					if let Err(e) = conf.hooks.trigger(HookType::Ready, &policy, &dirs, conf) {
+						log::error!("{}", redact(&format!("{:#}", e)));
+						reporter.report(
+							"hook",
+							&[("template", tmpl.as_str()), ("hook", "ready")],
+							&e,
+						);
+						return false;
+					}
+				}
+				true
+			};
+			if args.ready_after_hooks {
+				// signal readiness only if the hooks succeeded
+				let hooks_ok = trigger_ready() && !hook_failed;
+				hook_failed = false;
+				if !hooks_ok {
+					if exit {
+						return Err(anyhow::Error::from(Error::HookFailed).context(Failure::Hook));
+					}
+					log::error!("Not ready: a hook failed");
+					continue;
+				}
+				s6_ready(ready_fd.take());
+				status.set_ready(true);
+				status.set_templates(confs.len(), failed.len());
+			} else {
+				// signal s6 readiness that all config files have been generated
+				s6_ready(ready_fd.take());
+				status.set_ready(true);
+				status.set_templates(confs.len(), failed.len());
+				trigger_ready();
+			}
+			if exit {
+				if args.daemon {
+					log::info!("Exiting daemon mode: no leased secrets used");
+				}
+				break;
+			}
+			// drop privileges for the long running loop
+			if let Some(user) = run_as.take() {
+				if let Some(ref state_dir) = state_dir {
+					state_dir.chown(&user)?;
+				}
+				// keep writing the logs once unprivileged
+				logger::chown(&user);
+				if let Some(ref audit) = audit {
+					audit.chown(&user);
+				}
+				user.switch().with_context(|| {
+					format!("Switching to user {}", args.run_as.as_ref().unwrap())
+				})?;
+				log::info!("Running as {}", args.run_as.as_ref().unwrap());
+				// the exe secrets are now executed as that user
+				current_user = User::current();
+			}
+		}
+	}
+	status.set_alive(false);
+
+	if !args.daemon {
+		summary.print(format)?;
+	}
+	if !failed.is_empty() {
+		return Err(anyhow::Error::from(Error::Skipped(failed.len())).context(Failure::Degraded));
+	}
+	Ok(())
+}
@@ -1,6 +1,265 @@
 use libc::{geteuid, getgid, gid_t, uid_t};
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::ptr;
+
+/// Set the umask of the process and return the previous one
+pub fn set_umask(mask: u32) -> u32 {
+	// SAFETY: this is standard call to libc
+	unsafe { libc::umask(mask as libc::mode_t) as u32 }
+}
+
+/// Filesystems keeping their files in memory only
+const MEMORY_FS: [i64; 2] = [
+	// tmpfs
+	0x0102_1994,
+	// ramfs
+	0x8584_58f6,
+];
+
+/// Check that path lives on a memory backed filesystem (tmpfs or ramfs)
+pub fn is_memory_fs<T>(path: T) -> io::Result<bool>
+where
+	T: AsRef<Path>,
+{
+	let c_path = CString::new(path.as_ref().to_string_lossy().as_bytes())
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	// SAFETY: this is standard call to libc
+	unsafe {
+		let mut buf: libc::statfs = std::mem::zeroed();
+		if libc::statfs(c_path.as_ptr(), &mut buf) != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(MEMORY_FS.contains(&(buf.f_type as i64)))
+	}
+}
+
+/// Set an extended attribute on an opened file
+pub fn set_xattr(file: &File, name: &str, value: &[u8]) -> io::Result<()> {
+	let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	// SAFETY: this is standard call to libc
+	let res = unsafe {
+		libc::fsetxattr(
+			file.as_raw_fd(),
+			c_name.as_ptr(),
+			value.as_ptr() as *const libc::c_void,
+			value.len(),
+			0,
+		)
+	};
+	if res == 0 {
+		Ok(())
+	} else {
+		Err(io::Error::last_os_error())
+	}
+}
+
+/// Set the access and modification times of an opened file (seconds since the epoch)
+pub fn set_times(file: &File, secs: i64) -> io::Result<()> {
+	let time = libc::timespec {
+		tv_sec: secs as libc::time_t,
+		tv_nsec: 0,
+	};
+	// SAFETY: this is standard call to libc
+	let res = unsafe { libc::futimens(file.as_raw_fd(), [time, time].as_ptr()) };
+	if res == 0 {
+		Ok(())
+	} else {
+		Err(io::Error::last_os_error())
+	}
+}
+
+/// Try to take an exclusive lock on an opened file without waiting. Return false if the lock is
+/// already held by another process
+pub fn try_lock(file: &File) -> io::Result<bool> {
+	// SAFETY: this is standard call to libc
+	let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+	if res == 0 {
+		Ok(true)
+	} else {
+		let e = io::Error::last_os_error();
+		if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+			Ok(false)
+		} else {
+			Err(e)
+		}
+	}
+}
+
+/// Return the id of a group given its name
+pub fn group_id(name: &str) -> Option<gid_t> {
+	let c_name = CString::new(name).ok()?;
+	// SAFETY: this is standard call to libc
+	unsafe {
+		let grp = libc::getgrnam(c_name.as_ptr());
+		if grp.is_null() {
+			log::error!("Can't find group {}", name);
+			None
+		} else {
+			Some((*grp).gr_gid)
+		}
+	}
+}
+
+/// Return the host name
+pub fn hostname() -> io::Result<String> {
+	let mut buf = [0u8; 256];
+	// SAFETY: this is standard call to libc
+	if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+	Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Return the canonical name of a host as resolved by the system
+pub fn canonical_name(host: &str) -> Option<String> {
+	let c_host = CString::new(host).ok()?;
+	// SAFETY: this is standard call to libc and res is freed once read
+	unsafe {
+		let mut hints: libc::addrinfo = std::mem::zeroed();
+		hints.ai_flags = libc::AI_CANONNAME;
+		let mut res: *mut libc::addrinfo = ptr::null_mut();
+		if libc::getaddrinfo(c_host.as_ptr(), ptr::null(), &hints, &mut res) != 0 {
+			return None;
+		}
+		let name = if (*res).ai_canonname.is_null() {
+			None
+		} else {
+			Some(
+				CStr::from_ptr((*res).ai_canonname)
+					.to_string_lossy()
+					.into_owned(),
+			)
+		};
+		libc::freeaddrinfo(res);
+		name
+	}
+}
+
+/// Return the IP addresses of the network interfaces, except loopback ones
+pub fn addresses() -> io::Result<Vec<IpAddr>> {
+	let mut addrs = Vec::new();
+	// SAFETY: this is standard call to libc and the list is freed once read
+	unsafe {
+		let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+		if libc::getifaddrs(&mut ifaddrs) != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut cur = ifaddrs;
+		while !cur.is_null() {
+			let addr = (*cur).ifa_addr;
+			if !addr.is_null() {
+				match (*addr).sa_family as i32 {
+					libc::AF_INET => {
+						let sin = &*(addr as *const libc::sockaddr_in);
+						addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+							sin.sin_addr.s_addr,
+						))));
+					}
+					libc::AF_INET6 => {
+						let sin6 = &*(addr as *const libc::sockaddr_in6);
+						addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+					}
+					_ => {}
+				}
+			}
+			cur = (*cur).ifa_next;
+		}
+		libc::freeifaddrs(ifaddrs);
+	}
+	addrs.retain(|addr| !addr.is_loopback());
+	addrs.dedup();
+	Ok(addrs)
+}
+
+/// Return the total memory of the host in bytes
+pub fn total_memory() -> io::Result<u64> {
+	// SAFETY: this is standard call to libc
+	unsafe {
+		let mut info: libc::sysinfo = std::mem::zeroed();
+		if libc::sysinfo(&mut info) != 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(info.totalram as u64 * info.mem_unit as u64)
+	}
+}
+
+/// Return the number of seconds elapsed since midnight in local time
+pub fn local_seconds(secs: i64) -> u32 {
+	let time = secs as libc::time_t;
+	// SAFETY: this is standard call to libc
+	unsafe {
+		let mut tm: libc::tm = std::mem::zeroed();
+		if libc::localtime_r(&time, &mut tm).is_null() {
+			return (secs.rem_euclid(86400)) as u32;
+		}
+		(tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+	}
+}
+
+/// keyctl operations
+const KEYCTL_SEARCH: libc::c_long = 10;
+const KEYCTL_READ: libc::c_long = 11;
+
+/// lookup list for special keyrings ids
+const KEYRINGS: &[(&str, i32)] = &[
+	("thread", -1),
+	("process", -2),
+	("session", -3),
+	("user", -4),
+	("user-session", -5),
+];
+
+/// Return the id of a special keyring given its name
+pub fn keyring_id(name: &str) -> Option<i32> {
+	KEYRINGS
+		.iter()
+		.find_map(|(keyring, id)| if *keyring == name { Some(*id) } else { None })
+}
+
+/// Read the payload of the user key with the given description found in a keyring
+pub fn read_key(keyring: i32, description: &str) -> io::Result<Vec<u8>> {
+	let c_type = CString::new("user").unwrap();
+	let c_desc =
+		CString::new(description).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	// SAFETY: this is standard call to libc with buffers of the announced size
+	unsafe {
+		let key = libc::syscall(
+			libc::SYS_keyctl,
+			KEYCTL_SEARCH,
+			keyring as libc::c_long,
+			c_type.as_ptr(),
+			c_desc.as_ptr(),
+			0 as libc::c_long,
+		);
+		if key < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		// ask the size first
+		let size = libc::syscall(libc::SYS_keyctl, KEYCTL_READ, key, ptr::null_mut::<u8>(), 0);
+		if size < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut buf = vec![0u8; size as usize];
+		let size = libc::syscall(
+			libc::SYS_keyctl,
+			KEYCTL_READ,
+			key,
+			buf.as_mut_ptr(),
+			buf.len(),
+		);
+		if size < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		buf.truncate(size as usize);
+		Ok(buf)
+	}
+}
 
 /// Encapsulate libc uid and gid
 #[derive(PartialEq, Eq)]
@@ -28,6 +287,36 @@ impl User {
 		None
 	}
 
+	/// Try to create a User from a "user[:group]" or "uid[:gid]" specification (gid being uid by default),
+	/// numeric ids not needing to exist in the user and group databases
+	pub fn from_spec(spec: &str) -> Option<Self> {
+		let mut parts = spec.splitn(2, ':');
+		let name = parts.next()?;
+		let mut user = match name.parse::<uid_t>() {
+			Ok(uid) => User { uid, gid: uid },
+			Err(_) => Self::new(name)?,
+		};
+		if let Some(group) = parts.next() {
+			user.gid = group.parse::<gid_t>().ok().or_else(|| group_id(group))?;
+		}
+		Some(user)
+	}
+
+	/// Switch the process to this user, dropping all supplementary groups
+	pub fn switch(&self) -> io::Result<()> {
+		// SAFETY: this is standard call to libc. The group must be changed before the user
+		let res = unsafe {
+			libc::setgroups(1, &self.gid) == 0
+				&& libc::setgid(self.gid) == 0
+				&& libc::setuid(self.uid) == 0
+		};
+		if res {
+			Ok(())
+		} else {
+			Err(io::Error::last_os_error())
+		}
+	}
+
 	/// Return current user
 	pub fn current() -> Self {
 		// SAFETY: this is standard call to libc
@@ -0,0 +1,175 @@
+use crate::libc::User;
+
+use anyhow::{Context, Result};
+use env_logger::{Builder, Env, Target};
+use std::{
+	ffi::CString,
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	os::unix::fs::OpenOptionsExt,
+	path::PathBuf,
+	sync::Mutex,
+};
+
+/// labels of the template or secret being processed, appended to the log lines
+static LABELS: Mutex<String> = Mutex::new(String::new());
+
+/// path of the log file when logging to a file
+static LOG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Give the log file (if any) to the user the process is about to switch to
+pub fn chown(user: &User) {
+	if let Ok(path) = LOG_FILE.lock() {
+		if let Some(ref path) = *path {
+			user.chown(path);
+		}
+	}
+}
+
+/// Set the labels (formatted as key=value,...) appended to the next log lines, or none if empty
+pub fn set_labels(labels: String) {
+	if let Ok(mut current) = LABELS.lock() {
+		*current = labels;
+	}
+}
+
+/// return the current labels as a log line suffix
+fn labels_suffix() -> String {
+	match LABELS.lock() {
+		Ok(labels) if !labels.is_empty() => format!(" {{{}}}", labels),
+		_ => String::new(),
+	}
+}
+
+/// Send log lines to syslog with the priority given as a "<N>" prefix
+struct Syslog;
+
+impl Write for Syslog {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let line = String::from_utf8_lossy(buf);
+		let line = line.trim_end();
+		let (priority, msg) = match line.strip_prefix('<').and_then(|s| s.split_once('>')) {
+			Some((priority, msg)) => (priority.parse().unwrap_or(libc::LOG_INFO), msg),
+			None => (libc::LOG_INFO, line),
+		};
+		if let Ok(msg) = CString::new(msg) {
+			// SAFETY: format and message are nul terminated strings
+			unsafe {
+				libc::syslog(
+					libc::LOG_DAEMON | priority,
+					b"%s\0".as_ptr() as *const libc::c_char,
+					msg.as_ptr(),
+				)
+			};
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Log file renamed with a .1 suffix when it reaches a maximum size
+struct RotatingFile {
+	path: PathBuf,
+	file: File,
+	size: u64,
+	max_size: Option<u64>,
+}
+
+impl RotatingFile {
+	fn open(path: PathBuf, max_size: Option<u64>) -> io::Result<Self> {
+		// append mode plays well with logrotate copytruncate
+		let file = OpenOptions::new()
+			.append(true)
+			.create(true)
+			.mode(0o640)
+			.open(&path)?;
+		let size = file.metadata()?.len();
+		Ok(Self {
+			path,
+			file,
+			size,
+			max_size,
+		})
+	}
+
+	fn rotate(&mut self) -> io::Result<()> {
+		let mut rotated = self.path.clone().into_os_string();
+		rotated.push(".1");
+		fs::rename(&self.path, rotated)?;
+		*self = Self::open(self.path.clone(), self.max_size)?;
+		Ok(())
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if let Some(max_size) = self.max_size {
+			if self.size > 0 && self.size + buf.len() as u64 > max_size {
+				self.rotate()?;
+			}
+		}
+		let len = self.file.write(buf)?;
+		self.size += len as u64;
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+/// Initialize the logger with the given default filter, sending logs to "stderr", "syslog" or
+/// "file:<path>" (rotated when reaching max_size bytes)
+pub fn init(target: &str, filter: &str, max_size: Option<u64>) -> Result<()> {
+	let mut builder = Builder::from_env(Env::new().default_filter_or(filter));
+	if target == "syslog" {
+		// SAFETY: ident is a static nul terminated string
+		unsafe {
+			libc::openlog(
+				b"rconfd\0".as_ptr() as *const libc::c_char,
+				libc::LOG_PID,
+				libc::LOG_DAEMON,
+			)
+		};
+		// syslog adds its own timestamp
+		builder
+			.format(|buf, record| {
+				let priority = match record.level() {
+					log::Level::Error => libc::LOG_ERR,
+					log::Level::Warn => libc::LOG_WARNING,
+					log::Level::Info => libc::LOG_INFO,
+					log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+				};
+				writeln!(buf, "<{}>{}{}", priority, record.args(), labels_suffix())
+			})
+			.target(Target::Pipe(Box::new(Syslog)));
+	} else {
+		if let Some(path) = target.strip_prefix("file:") {
+			let file = RotatingFile::open(PathBuf::from(path), max_size)
+				.with_context(|| format!("Opening log file {}", path))?;
+			builder.target(Target::Pipe(Box::new(file)));
+			if let Ok(mut log_file) = LOG_FILE.lock() {
+				*log_file = Some(PathBuf::from(path));
+			}
+		} else if target != "stderr" {
+			anyhow::bail!("Unknown log target {}", target);
+		}
+		// same as the default format with the labels appended
+		builder.format(|buf, record| {
+			writeln!(
+				buf,
+				"[{} {} {}] {}{}",
+				buf.timestamp(),
+				buf.default_styled_level(record.level()),
+				record.target(),
+				record.args(),
+				labels_suffix()
+			)
+		});
+	}
+	builder.init();
+	Ok(())
+}
@@ -0,0 +1,38 @@
+use serde_json::Value;
+use zeroize::Zeroize;
+
+/// Lock the process memory so that secrets are never swapped, and prevent core dumps
+pub fn lock() {
+	// SAFETY: this is standard call to libc
+	unsafe {
+		if libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) != 0 {
+			log::warn!(
+				"Can't lock memory (missing CAP_IPC_LOCK or RLIMIT_MEMLOCK too low): {}",
+				std::io::Error::last_os_error()
+			);
+		}
+		if libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) != 0 {
+			log::warn!(
+				"Can't disable core dumps: {}",
+				std::io::Error::last_os_error()
+			);
+		}
+	}
+}
+
+/// Overwrite all the strings contained in a value with zeros
+pub fn zeroize_value(value: &mut Value) {
+	match value {
+		Value::String(s) => s.zeroize(),
+		Value::Array(values) => values.iter_mut().for_each(zeroize_value),
+		Value::Object(map) => map.values_mut().for_each(zeroize_value),
+		_ => {}
+	}
+}
+
+#[test]
+fn zeroize_nested() {
+	let mut value = serde_json::json!({ "data": ["secret"] });
+	zeroize_value(&mut value);
+	assert_eq!(value, serde_json::json!({ "data": [""] }));
+}
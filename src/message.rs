@@ -1,15 +1,52 @@
+use crate::clients::Session;
+
 use anyhow::Result;
 use async_std::channel::Sender;
 
 /// Message sent by tasks to main_loop
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Message {
-	/// log in and re log in (role)
-	Login(String),
+	/// log in and re log in (role, cluster and token if not the default ones)
+	Login(String, Session),
+	// extend the token of a role (cluster and token if not the default ones) or log in again
+	RenewToken(String, Session),
 	// get/refresh a secret (path) and trigger generate template or not
 	GetSecret(String, bool),
 	// generate template (config name)
 	GenerateTemplate(String),
+	// fetch a secret (path) again even if still valid and regenerate templates
+	Refresh(String),
+	// parse the config files again
+	Reload,
+	// generate all templates again
+	RenderAll,
+	// generate all templates again and fetch all secrets again
+	Reconcile,
+	// compare the version of a KV v2 secret (path) with the fetched one
+	CheckVersion(String),
+	// check again the files required before signaling readiness
+	CheckReady,
+	// warn if a secret (path) is about to expire without having been renewed
+	CheckExpiry(String),
+	// check that the generated files have not been modified by someone else
+	CheckTampered,
+	// fail if readiness has not been signaled yet
+	ReadyTimeout,
+	// let a deferred request go through to check if vault is reachable again
+	Probe,
+}
+
+impl Message {
+	/// Return the path of the secret the message is about
+	pub fn secret(&self) -> Option<&str> {
+		match self {
+			Message::GetSecret(path, _)
+			| Message::Refresh(path)
+			| Message::CheckVersion(path)
+			| Message::CheckExpiry(path) => Some(path),
+			_ => None,
+		}
+	}
 }
 
 /// convert the error in the return signature of sender.send to anyhow::Error
@@ -0,0 +1,532 @@
+use crate::{
+	args::{ConfdArgs, ConsulTemplateArgs, MigrateArgs, MigrateFrom},
+	hcl,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+/// Part of a go template
+#[derive(Debug, PartialEq)]
+enum Piece {
+	Text(String),
+	Action(String),
+}
+
+/// Split a go template in text and actions ({{ ... }}), applying the whitespace trimming markers
+fn pieces(text: &str) -> Vec<Piece> {
+	let mut pieces = Vec::new();
+	let mut remainder = text;
+	let mut trim_next = false;
+	while !remainder.is_empty() {
+		let (chunk, rest) = match remainder.find("{{") {
+			Some(start) => remainder.split_at(start),
+			None => (remainder, ""),
+		};
+		let mut chunk = if trim_next { chunk.trim_start() } else { chunk };
+		if rest.is_empty() {
+			if !chunk.is_empty() {
+				pieces.push(Piece::Text(chunk.to_owned()));
+			}
+			break;
+		}
+		let end = match rest.find("}}") {
+			Some(end) => end,
+			None => {
+				pieces.push(Piece::Text(format!("{}{}", chunk, rest)));
+				break;
+			}
+		};
+		let mut action = &rest[2..end];
+		if let Some(a) = action.strip_prefix('-') {
+			chunk = chunk.trim_end();
+			action = a;
+		}
+		trim_next = false;
+		if let Some(a) = action.strip_suffix('-') {
+			trim_next = true;
+			action = a;
+		}
+		if !chunk.is_empty() {
+			pieces.push(Piece::Text(chunk.to_owned()));
+		}
+		pieces.push(Piece::Action(action.trim().to_owned()));
+		remainder = &rest[end + 2..];
+	}
+	pieces
+}
+
+/// Return the quoted strings of an action
+fn quoted(action: &str) -> Vec<&str> {
+	action.split('"').skip(1).step_by(2).collect()
+}
+
+/// Scope opened by a with, range or if action
+enum Scope {
+	Secret(String),
+	Verbatim,
+}
+
+/// A go template converted to a jsonnet expression
+struct Converted {
+	/// jsonnet expression of the file content
+	expr: String,
+	/// secrets used: (rconfd path, name)
+	secrets: Vec<(String, String)>,
+	/// actions that couldn't be converted
+	warnings: Vec<String>,
+}
+
+impl Converted {
+	/// Return the name of the secret at rconfd path, registering it if necessary. The name is derived
+	/// from the last segment of key
+	fn register(&mut self, path: String, key: &str) -> String {
+		if let Some((_, name)) = self.secrets.iter().find(|(p, _)| p == &path) {
+			return name.clone();
+		}
+		let base: String = key
+			.rsplit('/')
+			.next()
+			.unwrap_or_default()
+			.chars()
+			.map(|c| {
+				if c.is_ascii_alphanumeric() {
+					c.to_ascii_lowercase()
+				} else {
+					'_'
+				}
+			})
+			.collect();
+		let base = if base.is_empty() || base.starts_with(|c: char| c.is_ascii_digit()) {
+			format!("s_{}", base)
+		} else {
+			base
+		};
+		let mut name = base.clone();
+		let mut i = 1;
+		while self.secrets.iter().any(|(_, n)| n == &name) {
+			i += 1;
+			name = format!("{}{}", base, i);
+		}
+		self.secrets.push((path, name.clone()));
+		name
+	}
+
+	/// Return the name of the vault secret at consul-template path (with its arguments)
+	fn vault_secret(&mut self, args: &[&str]) -> String {
+		let path = match args {
+			[path] => format!("vault:${{VAULT_ROLE}}:{}", path),
+			[path, kwargs @ ..] => {
+				format!("vault:${{VAULT_ROLE}},POST,{}:{}", kwargs.join(","), path)
+			}
+			[] => "vault:${VAULT_ROLE}:".to_owned(),
+		};
+		self.register(path, args.first().unwrap_or(&"secret"))
+	}
+
+	fn unconverted(&mut self, action: &str) {
+		self.warnings
+			.push(format!("unconverted action {{{{ {} }}}}", action));
+	}
+}
+
+/// Return the jsonnet expression accessing the fields (.Data.a.b) of a secret
+fn fields(name: &str, fields: &str) -> Option<String> {
+	let mut expr = format!("secrets.{}", name);
+	let mut fields = fields.split('.').skip(1);
+	if fields.next() != Some("Data") {
+		return None;
+	}
+	for field in fields {
+		if !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+			expr.push('.');
+			expr.push_str(field);
+		} else {
+			expr.push_str(&format!("[{}]", Value::String(field.to_owned())));
+		}
+	}
+	Some(format!("std.toString({})", expr))
+}
+
+/// Convert a go template into a jsonnet expression. Consul-template secret actions are always
+/// converted, while confd getv actions are converted only if getv maps keys to rconfd paths
+fn convert(text: &str, getv: Option<&dyn Fn(&str) -> String>) -> Converted {
+	let mut converted = Converted {
+		expr: String::new(),
+		secrets: Vec::new(),
+		warnings: Vec::new(),
+	};
+	let mut scopes: Vec<Scope> = Vec::new();
+	let mut exprs: Vec<String> = Vec::new();
+	for piece in pieces(text) {
+		let action = match piece {
+			Piece::Text(text) => {
+				// one string per line for readability
+				exprs.extend(
+					text.split_inclusive('\n')
+						.map(|line| Value::String(line.to_owned()).to_string()),
+				);
+				continue;
+			}
+			Piece::Action(action) => action,
+		};
+		let verbatim = Value::String(format!("{{{{ {} }}}}", action)).to_string();
+		if action == "end" {
+			match scopes.pop() {
+				Some(Scope::Secret(_)) => {}
+				_ => exprs.push(verbatim),
+			}
+		} else if action.starts_with("with secret ") {
+			let name = converted.vault_secret(&quoted(&action));
+			scopes.push(Scope::Secret(name));
+		} else if action.starts_with("with ")
+			|| action.starts_with("range ")
+			|| action.starts_with("if ")
+		{
+			converted.unconverted(&action);
+			scopes.push(Scope::Verbatim);
+			exprs.push(verbatim);
+		} else if let Some(rest) = action.strip_prefix("(secret ") {
+			let (args, rest) = rest.split_at(rest.find(')').unwrap_or(rest.len()));
+			let name = converted.vault_secret(&quoted(args));
+			match fields(&name, rest.trim_start_matches(')')) {
+				Some(expr) => exprs.push(expr),
+				None => {
+					converted.unconverted(&action);
+					exprs.push(verbatim);
+				}
+			}
+		} else if let (Some(Scope::Secret(name)), true) =
+			(scopes.last(), action.starts_with(".Data"))
+		{
+			match fields(name, &action) {
+				Some(expr) => exprs.push(expr),
+				None => exprs.push(verbatim),
+			}
+		} else if let (Some(getv), Some(key)) = (
+			getv,
+			action
+				.strip_prefix("getv ")
+				.and_then(|args| quoted(args).first().copied()),
+		) {
+			let name = converted.register(getv(key), key);
+			exprs.push(format!("std.toString(secrets.{})", name));
+		} else {
+			converted.unconverted(&action);
+			exprs.push(verbatim);
+		}
+	}
+	converted.expr = if exprs.is_empty() {
+		"\"\"".to_owned()
+	} else {
+		exprs.join("\n\t\t+ ")
+	};
+	converted
+}
+
+/// rconfd config and templates being generated
+struct Migration {
+	/// directory where to write the files
+	output: PathBuf,
+	/// rconfd config
+	confs: Map<String, Value>,
+}
+
+impl Migration {
+	fn new(output: &str) -> Result<Self> {
+		let output = PathBuf::from(output);
+		fs::create_dir_all(&output).with_context(|| format!("Creating {:?}", output))?;
+		Ok(Self {
+			output,
+			confs: Map::new(),
+		})
+	}
+
+	/// Write the jsonnet template generating destination and add its config
+	fn add(
+		&mut self,
+		stem: &str,
+		destination: &Path,
+		converted: Converted,
+		mode: String,
+		user: &str,
+		command: Option<String>,
+	) -> Result<()> {
+		// find a file name not already used by another template
+		let mut name = format!("{}.jsonnet", stem);
+		let mut i = 1;
+		while self.confs.contains_key(&name) {
+			i += 1;
+			name = format!("{}{}.jsonnet", stem, i);
+		}
+		for warning in converted.warnings.iter() {
+			eprintln!("warning: {}: {}", name, warning);
+		}
+
+		let file = destination
+			.file_name()
+			.map(|s| s.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		let jsonnet = format!(
+			"// migrated template: secrets.<name> is the value of the secret\nlocal secrets = std.extVar('secrets');\n\n{{\n\t{}:\n\t\t{},\n}}\n",
+			Value::String(file),
+			converted.expr
+		);
+		let path = self.output.join(&name);
+		fs::write(&path, jsonnet).with_context(|| format!("Writing {:?}", path))?;
+
+		let mut hooks = Map::new();
+		if let Some(command) = command {
+			if !command.starts_with('/') {
+				eprintln!(
+					"warning: {}: the modified hook \"{}\" must be an absolute path",
+					name, command
+				);
+			}
+			hooks.insert("modified".to_owned(), Value::String(command));
+		}
+		let secrets: Map<String, Value> = converted
+			.secrets
+			.into_iter()
+			.map(|(path, name)| (path, Value::String(name)))
+			.collect();
+		self.confs.insert(
+			name,
+			json!({
+				"dir": destination.parent().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default(),
+				"mode": mode,
+				"user": user,
+				"secrets": secrets,
+				"hooks": hooks,
+			}),
+		);
+		Ok(())
+	}
+
+	/// Write the rconfd config
+	fn save(self, name: &str) -> Result<()> {
+		let path = self.output.join(name);
+		fs::write(&path, serde_json::to_string_pretty(&self.confs)?)
+			.with_context(|| format!("Writing {:?}", path))?;
+		println!(
+			"{} template(s) migrated to {:?}",
+			self.confs.len(),
+			self.output
+		);
+		Ok(())
+	}
+}
+
+/// Convert consul-template template stanzas into an rconfd config file and jsonnet templates
+fn consul_template(args: &ConsulTemplateArgs) -> Result<()> {
+	let text =
+		fs::read_to_string(&args.config).with_context(|| format!("Reading {}", args.config))?;
+	let config_dir = Path::new(&args.config)
+		.parent()
+		.unwrap_or_else(|| Path::new("."));
+	let entries = hcl::parse(&text).with_context(|| format!("Parsing {}", args.config))?;
+	let mut migration = Migration::new(&args.output)?;
+	for entry in entries.iter() {
+		let stanza = match entry {
+			hcl::Entry::Block(name, _, body) if name == "template" => hcl::to_object(body),
+			_ => continue,
+		};
+		let destination = match stanza.get("destination").and_then(Value::as_str) {
+			Some(destination) => PathBuf::from(destination),
+			None => {
+				eprintln!("warning: skipping template without destination");
+				continue;
+			}
+		};
+		// template content is either inline or in a source file
+		let (content, source) = match (stanza.get("contents"), stanza.get("source")) {
+			(Some(Value::String(contents)), _) => (contents.clone(), None),
+			(_, Some(Value::String(source))) => {
+				let path = config_dir.join(source);
+				let content =
+					fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?;
+				(content, Some(source))
+			}
+			_ => {
+				eprintln!(
+					"warning: skipping template {:?} without source or contents",
+					destination
+				);
+				continue;
+			}
+		};
+		let stem = source
+			.map(Path::new)
+			.unwrap_or_else(|| destination.as_path())
+			.file_stem()
+			.map(|s| s.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		let mode = match stanza.get("perms") {
+			Some(Value::String(perms)) => perms.clone(),
+			Some(Value::Number(perms)) => format!("0{}", perms),
+			_ => "0644".to_owned(),
+		};
+		let command = match stanza.get("command") {
+			Some(Value::String(command)) => Some(command.clone()),
+			Some(Value::Array(command)) => Some(
+				command
+					.iter()
+					.filter_map(Value::as_str)
+					.collect::<Vec<_>>()
+					.join(" "),
+			),
+			_ => None,
+		};
+		migration.add(
+			&stem,
+			&destination,
+			convert(&content, None),
+			mode,
+			stanza.get("user").and_then(Value::as_str).unwrap_or("root"),
+			command,
+		)?;
+	}
+	migration.save("consul-template.json")
+}
+
+/// A confd template resource
+#[derive(Deserialize)]
+struct ConfdResource {
+	template: ConfdTemplate,
+}
+
+#[derive(Deserialize)]
+struct ConfdTemplate {
+	src: String,
+	dest: String,
+	#[serde(default)]
+	prefix: String,
+	mode: Option<String>,
+	uid: Option<u32>,
+	reload_cmd: Option<String>,
+}
+
+/// Return the rconfd path of a confd key for the given backend
+fn confd_key(backend: &str, key: &str) -> String {
+	let key = key.trim_start_matches('/');
+	if backend == "vault" {
+		format!("vault:${{VAULT_ROLE}}:{}", key)
+	} else {
+		// same mapping as the confd env backend
+		format!("env:str:{}", key.replace('/', "_").to_uppercase())
+	}
+}
+
+/// Convert confd template resources (conf.d/*.toml and templates/*.tmpl) into an rconfd config file
+/// and jsonnet templates
+fn confd(args: &ConfdArgs) -> Result<()> {
+	if args.backend != "env" && args.backend != "vault" {
+		anyhow::bail!("Unsupported backend {}", args.backend);
+	}
+	let confdir = PathBuf::from(&args.confdir);
+	let mut resources: Vec<PathBuf> = fs::read_dir(confdir.join("conf.d"))
+		.with_context(|| format!("Browsing {:?}", confdir.join("conf.d")))?
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().map(|ext| ext == "toml").unwrap_or(false))
+		.collect();
+	resources.sort();
+	let mut migration = Migration::new(&args.output)?;
+	for resource in resources.iter() {
+		let text =
+			fs::read_to_string(resource).with_context(|| format!("Reading {:?}", resource))?;
+		let resource: ConfdResource =
+			toml::from_str(&text).with_context(|| format!("Parsing {:?}", resource))?;
+		let tmpl = resource.template;
+		let path = confdir.join("templates").join(&tmpl.src);
+		let content = fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?;
+		let prefix = tmpl.prefix.trim_end_matches('/').to_owned();
+		let getv = |key: &str| {
+			confd_key(
+				&args.backend,
+				&format!("{}/{}", prefix, key.trim_start_matches('/')),
+			)
+		};
+		let stem = Path::new(&tmpl.src)
+			.file_stem()
+			.map(|s| s.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		if tmpl.uid.is_some() {
+			eprintln!(
+				"warning: {}: set the user owning {} by name",
+				stem, tmpl.dest
+			);
+		}
+		migration.add(
+			&stem,
+			Path::new(&tmpl.dest),
+			convert(&content, Some(&getv)),
+			tmpl.mode.unwrap_or_else(|| "0644".to_owned()),
+			"root",
+			tmpl.reload_cmd,
+		)?;
+	}
+	migration.save("confd.json")
+}
+
+/// Convert the configuration of another daemon
+pub fn run(migrate: &MigrateArgs) -> i32 {
+	let res = match migrate.from {
+		MigrateFrom::ConsulTemplate(ref args) => consul_template(args),
+		MigrateFrom::Confd(ref args) => confd(args),
+	};
+	match res {
+		Ok(()) => 0,
+		Err(e) => {
+			eprintln!("Error: {:?}", e);
+			1
+		}
+	}
+}
+
+#[test]
+fn split_pieces() {
+	assert_eq!(
+		pieces("a {{- .Data.b -}} \nc"),
+		vec![
+			Piece::Text("a".to_owned()),
+			Piece::Action(".Data.b".to_owned()),
+			Piece::Text("c".to_owned())
+		]
+	);
+}
+
+#[test]
+fn convert_secret() {
+	let converted = convert(
+		"{{ with secret \"secret/data/db\" }}password={{ .Data.data.password }}{{ end }}\n",
+		None,
+	);
+	assert_eq!(
+		converted.secrets,
+		vec![(
+			"vault:${VAULT_ROLE}:secret/data/db".to_owned(),
+			"db".to_owned()
+		)]
+	);
+	assert_eq!(
+		converted.expr,
+		"\"password=\"\n\t\t+ std.toString(secrets.db.data.password)\n\t\t+ \"\\n\""
+	);
+	assert!(converted.warnings.is_empty());
+}
+
+#[test]
+fn convert_getv() {
+	let getv = |key: &str| confd_key("env", &format!("/myapp{}", key));
+	let converted = convert("url={{getv \"/database/url\"}}", Some(&getv));
+	assert_eq!(
+		converted.secrets,
+		vec![("env:str:MYAPP_DATABASE_URL".to_owned(), "url".to_owned())]
+	);
+	assert_eq!(converted.expr, "\"url=\"\n\t\t+ std.toString(secrets.url)");
+}
@@ -0,0 +1,69 @@
+use crate::result::{Error, Result};
+
+/// variable giving the path of the PKCS#11 module
+pub const MODULE_VAR: &str = "PKCS11_MODULE";
+
+/// variable giving the user PIN of the token
+pub const PIN_VAR: &str = "PKCS11_PIN";
+
+/// Decrypt the content of the file at path (RSA OAEP with SHA-256) with the private key labeled label
+/// on the first token of the PKCS#11 module
+#[cfg(feature = "pkcs11")]
+pub fn decrypt(path: &str, label: &str) -> Result<Vec<u8>> {
+	use cryptoki::{
+		context::{CInitializeArgs, Pkcs11},
+		mechanism::{
+			rsa::{PkcsMgfType, PkcsOaepParams, PkcsOaepSource},
+			Mechanism, MechanismType,
+		},
+		object::{Attribute, ObjectClass},
+		session::UserType,
+	};
+	use std::{env, fs};
+
+	let hsm_err = |e: cryptoki::error::Error| Error::Pkcs11(format!("{}: {}", path, e));
+	let module = env::var(MODULE_VAR)
+		.map_err(|_| Error::Pkcs11(format!("{} is not defined", MODULE_VAR)))?;
+	let ciphertext = fs::read(path).map_err(|e| Error::Pkcs11(format!("{}: {}", path, e)))?;
+
+	let pkcs11 = Pkcs11::new(&module).map_err(hsm_err)?;
+	pkcs11
+		.initialize(CInitializeArgs::OsThreads)
+		.map_err(hsm_err)?;
+	let slot = pkcs11
+		.get_slots_with_token()
+		.map_err(hsm_err)?
+		.into_iter()
+		.next()
+		.ok_or_else(|| Error::Pkcs11(format!("{}: no token found", module)))?;
+	let session = pkcs11.open_ro_session(slot).map_err(hsm_err)?;
+	let pin = env::var(PIN_VAR).ok();
+	session
+		.login(UserType::User, pin.as_deref())
+		.map_err(hsm_err)?;
+	let key = session
+		.find_objects(&[
+			Attribute::Class(ObjectClass::PRIVATE_KEY),
+			Attribute::Label(label.as_bytes().to_vec()),
+		])
+		.map_err(hsm_err)?
+		.into_iter()
+		.next()
+		.ok_or_else(|| Error::Pkcs11(format!("no private key labeled {}", label)))?;
+	let mechanism = Mechanism::RsaPkcsOaep(PkcsOaepParams::new(
+		MechanismType::SHA256,
+		PkcsMgfType::MGF1_SHA256,
+		PkcsOaepSource::empty(),
+	));
+	session
+		.decrypt(&mechanism, key, &ciphertext)
+		.map_err(hsm_err)
+}
+
+/// Can't decrypt anything without PKCS#11 support
+#[cfg(not(feature = "pkcs11"))]
+pub fn decrypt(_path: &str, _label: &str) -> Result<Vec<u8>> {
+	Err(Error::Pkcs11(
+		"rconfd was built without the pkcs11 feature".to_owned(),
+	))
+}
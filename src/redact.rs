@@ -0,0 +1,132 @@
+use serde_json::Value;
+use std::{
+	collections::BTreeMap,
+	fmt,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		RwLock,
+	},
+};
+use zeroize::Zeroizing;
+
+/// text displayed in place of a secret value
+const REDACTED: &str = "<redacted>";
+
+/// secret strings shorter than that are not redacted (booleans, small numbers...)
+const MIN_LEN: usize = 4;
+
+/// show secret values in logs (debugging only)
+static UNSAFE: AtomicBool = AtomicBool::new(false);
+
+/// secret strings of the current value of each secret (path)
+static SECRETS: RwLock<BTreeMap<String, Vec<Zeroizing<String>>>> = RwLock::new(BTreeMap::new());
+
+/// Allow secret values to appear in logs and error messages
+pub fn set_unsafe(unsafe_log: bool) {
+	UNSAFE.store(unsafe_log, Ordering::Relaxed);
+}
+
+fn is_unsafe() -> bool {
+	UNSAFE.load(Ordering::Relaxed)
+}
+
+/// Collect all the strings contained in a secret value
+fn strings(value: &Value, res: &mut Vec<Zeroizing<String>>) {
+	match value {
+		Value::String(s) if s.len() >= MIN_LEN => {
+			if !res.iter().any(|secret| secret.as_str() == s) {
+				res.push(Zeroizing::new(s.to_owned()));
+			}
+		}
+		Value::Array(values) => values.iter().for_each(|value| strings(value, res)),
+		Value::Object(map) => map.values().for_each(|value| strings(value, res)),
+		_ => {}
+	}
+}
+
+/// Register all the strings contained in the value of a secret (path) to be able to redact them later,
+/// replacing the ones of its previous value
+pub fn register(path: &str, value: &Value) {
+	let mut res = Vec::new();
+	strings(value, &mut res);
+	SECRETS.write().unwrap().insert(path.to_owned(), res);
+}
+
+/// Forget the strings of a secret (path) not used anymore. They are zeroized when dropped
+pub fn forget(path: &str) {
+	SECRETS.write().unwrap().remove(path);
+}
+
+/// Return a copy of text with all registered secret strings replaced
+pub fn redact(text: &str) -> String {
+	let mut res = text.to_owned();
+	if !is_unsafe() {
+		let secrets = SECRETS.read().unwrap();
+		let mut strings = secrets
+			.values()
+			.flatten()
+			.map(|s| s.as_str())
+			.collect::<Vec<_>>();
+		// replace longest secrets first in case a secret contains another one
+		strings.sort_unstable_by(|a, b| b.len().cmp(&a.len()));
+		for secret in strings {
+			if res.contains(secret) {
+				res = res.replace(secret, REDACTED);
+			}
+		}
+	}
+	res
+}
+
+/// Display a secret value only if allowed
+pub struct Redacted<'a>(pub &'a Value);
+
+impl<'a> fmt::Display for Redacted<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if is_unsafe() {
+			write!(f, "{}", self.0)
+		} else {
+			write!(f, "{}", REDACTED)
+		}
+	}
+}
+
+impl<'a> fmt::Debug for Redacted<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Display::fmt(self, f)
+	}
+}
+
+#[test]
+fn redact_nested() {
+	register(
+		"vault:role:secret/nested",
+		&serde_json::json!({ "data": { "password": "s3cr3t-redact-nested" } }),
+	);
+	assert_eq!(
+		redact("failed to connect with s3cr3t-redact-nested"),
+		"failed to connect with <redacted>"
+	);
+}
+
+#[test]
+fn rotated_values() {
+	register(
+		"vault:role:secret/rotated",
+		&serde_json::json!("s3cr3t-rotated-1"),
+	);
+	register(
+		"vault:role:secret/rotated",
+		&serde_json::json!("s3cr3t-rotated-2"),
+	);
+	assert_eq!(redact("s3cr3t-rotated-1"), "s3cr3t-rotated-1");
+	assert_eq!(redact("s3cr3t-rotated-2"), "<redacted>");
+	forget("vault:role:secret/rotated");
+	assert_eq!(redact("s3cr3t-rotated-2"), "s3cr3t-rotated-2");
+}
+
+#[test]
+fn short_values() {
+	register("vault:role:secret/short", &serde_json::json!(["abc"]));
+	assert_eq!(redact("abc"), "abc");
+}
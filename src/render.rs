@@ -0,0 +1,181 @@
+use crate::{conf::TemplateConf, memory::zeroize_value, redact::redact, result::Error, validate};
+
+use anyhow::{Context, Result};
+use jrsonnet_evaluator::{
+	trace::{CompactFormat, PathResolver},
+	EvaluationState, FileImportResolver, ManifestFormat, Val,
+};
+use jrsonnet_interner::IStr;
+use jrsonnet_types::ValType;
+use serde_json::Value;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Evaluate jsonnet templates
+pub struct TemplateEngine {
+	/// directories of templates with a relative path, by increasing precedence
+	dirs: Vec<PathBuf>,
+	/// additional path for jsonnet libraries
+	library_paths: Vec<PathBuf>,
+	/// maximum depth of the evaluation stack
+	max_stack: usize,
+	/// maximum number of frames shown in error traces
+	max_trace: usize,
+	/// width of the line numbers column in error traces
+	trace_padding: usize,
+	/// show absolute paths in error traces
+	trace_absolute: bool,
+}
+
+/// default maximum depth of the evaluation stack
+pub const MAX_STACK: usize = 200;
+
+/// default maximum number of frames shown in error traces
+pub const MAX_TRACE: usize = 20;
+
+/// default width of the line numbers column in error traces
+pub const TRACE_PADDING: usize = 4;
+
+impl TemplateEngine {
+	pub fn new(dirs: &[&str], jpath: Option<&str>) -> Self {
+		let library_paths = if let Some(jpath) = jpath {
+			jpath.split(',').map(|s| PathBuf::from(s.trim())).collect()
+		} else {
+			vec![]
+		};
+		Self {
+			dirs: dirs.iter().map(PathBuf::from).collect(),
+			library_paths,
+			max_stack: MAX_STACK,
+			max_trace: MAX_TRACE,
+			trace_padding: TRACE_PADDING,
+			trace_absolute: false,
+		}
+	}
+
+	/// Set the maximum depth of the evaluation stack, above which a template is rejected
+	pub fn set_max_stack(&mut self, max_stack: usize) {
+		self.max_stack = max_stack;
+	}
+
+	/// Set the number of frames, the line numbers padding and the paths kind (relative to the template dir or
+	/// absolute) of error traces
+	pub fn set_trace(&mut self, max_trace: usize, padding: usize, absolute: bool) {
+		self.max_trace = max_trace;
+		self.trace_padding = padding;
+		self.trace_absolute = absolute;
+	}
+
+	/// Evaluate a template with the given "secrets" and "secrets_meta" extVars and the files it embeds as "files"
+	/// extVar, and return the files to generate (name, content). The secrets are zeroized once handed to the jsonnet
+	/// engine, and the contents when dropped
+	pub fn render(
+		&self,
+		tmpl: &str,
+		conf: &TemplateConf,
+		mut secrets: Value,
+		secrets_meta: Value,
+		files: Value,
+	) -> Result<Vec<(IStr, Zeroizing<String>)>> {
+		// prepare the evaluation state
+		let state = EvaluationState::default();
+		state
+			.with_stdlib()
+			.set_manifest_format(ManifestFormat::ToString);
+		// add file import resolver
+		state.set_import_resolver(Box::new(FileImportResolver {
+			library_paths: self.library_paths.clone(),
+		}));
+		// set trace format
+		let resolver = if self.trace_absolute {
+			PathResolver::Absolute
+		} else {
+			PathResolver::Relative(PathBuf::from(&conf.dir))
+		};
+		state.set_trace_format(Box::new(CompactFormat {
+			resolver,
+			padding: self.trace_padding,
+		}));
+		// set trace depth
+		state.set_max_trace(self.max_trace);
+		// reject runaway recursions before they exhaust the process stack
+		state.set_max_stack(self.max_stack);
+
+		// inject secret_key: secret_value in "secrets" extVar
+		state.add_ext_var(IStr::from("secrets"), Val::from(&secrets));
+		// the jsonnet engine has its own copy
+		zeroize_value(&mut secrets);
+		// inject secret_key: lease and expiry of the secret in "secrets_meta" extVar
+		state.add_ext_var(IStr::from("secrets_meta"), Val::from(&secrets_meta));
+		// inject file_key: content of the files generated by other templates in "files" extVar
+		state.add_ext_var(IStr::from("files"), Val::from(&files));
+
+		// look for a template with a relative path in the dirs, starting with the last one
+		let tmpl_path = if tmpl.starts_with('/') {
+			PathBuf::from(tmpl)
+		} else {
+			self.dirs
+				.iter()
+				.rev()
+				.map(|dir| dir.join(tmpl))
+				.find(|path| path.exists())
+				.unwrap_or_else(|| match self.dirs.last() {
+					Some(dir) => dir.join(tmpl),
+					None => PathBuf::from(tmpl),
+				})
+		};
+
+		// add the template file
+		let val = state
+			.evaluate_file_raw(&tmpl_path)
+			.map_err(|e| anyhow::Error::msg(redact(&state.stringify_err(&e))))
+			.with_context(|| format!("Evaluating {:?}", tmpl_path))?;
+
+		// generate files from template top keys, one at a time to tell which one fails
+		let obj = match val {
+			Val::Obj(obj) => obj,
+			val => {
+				return Err(
+					Error::NotAnObject(tmpl.to_owned(), val.value_type().to_string()).into(),
+				)
+			}
+		};
+		let mut manifests = Vec::new();
+		for key in obj.fields() {
+			let (data, kind) = state
+				.run_in_state(|| obj.get(key.clone()))
+				.and_then(|val| {
+					let val = val.expect("field exists");
+					let kind = val.value_type();
+					state.manifest(val).map(|data| (data, kind))
+				})
+				.map_err(|e| anyhow::Error::msg(redact(&state.stringify_err(&e))))
+				.with_context(|| format!("Manifesting \"{}\"", key))?;
+			// only strings are written as is
+			if !matches!(kind, ValType::Str) {
+				log::debug!("\"{}\" is a {} manifested as JSON", key, kind);
+			}
+			manifests.push((key, Zeroizing::new(data.to_string())));
+		}
+
+		// refuse to fill the filesystem because of a template bug
+		if let Some(max_size) = conf.max_size {
+			let size = manifests
+				.iter()
+				.map(|(_, data)| data.len() as u64 + 1)
+				.sum::<u64>();
+			if size > max_size {
+				return Err(Error::TooLarge(tmpl.to_owned(), size, max_size).into());
+			}
+		}
+
+		// catch syntax errors before the consuming service does
+		for (file, data) in manifests.iter() {
+			if let Some(format) = conf.format(file) {
+				validate::check(format, data)
+					.map_err(|e| Error::InvalidOutput(file.to_string(), format.to_string(), e))?;
+			}
+		}
+		Ok(manifests)
+	}
+}
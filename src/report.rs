@@ -0,0 +1,119 @@
+use crate::redact::redact;
+
+use async_std::task;
+use isahc::{config::Configurable, Request, RequestExt};
+use serde_json::json;
+use std::{panic, time::Duration};
+
+/// Report failures to an error tracking service
+pub struct Reporter {
+	/// url where to post failures as JSON
+	webhook: Option<String>,
+	/// keep the sentry client alive
+	#[cfg(feature = "sentry")]
+	_sentry: Option<sentry::ClientInitGuard>,
+}
+
+/// Build the request posting a JSON failure report to an url
+fn request(
+	url: &str,
+	kind: &str,
+	tags: &[(&str, &str)],
+	message: &str,
+) -> Result<Request<String>, isahc::Error> {
+	let tags: serde_json::Map<String, serde_json::Value> = tags
+		.iter()
+		.map(|(k, v)| ((*k).to_owned(), json!(v)))
+		.collect();
+	let body = json!({
+		"service": env!("CARGO_PKG_NAME"),
+		"version": env!("CARGO_PKG_VERSION"),
+		"kind": kind,
+		"tags": tags,
+		"message": message,
+	});
+	Request::post(url)
+		.header("Content-Type", "application/json")
+		.timeout(Duration::from_secs(10))
+		.body(body.to_string())
+		.map_err(isahc::Error::from)
+}
+
+/// Post a JSON failure report to an url in the background
+fn post(url: &str, kind: &str, tags: &[(&str, &str)], message: &str) {
+	match request(url, kind, tags, message) {
+		Ok(req) => {
+			let url = url.to_owned();
+			task::spawn(async move {
+				if let Err(e) = req.send_async().await {
+					log::error!("Reporting failure to {}: {}", url, e);
+				}
+			});
+		}
+		Err(e) => log::error!("Reporting failure to {}: {}", url, e),
+	}
+}
+
+/// Post a JSON failure report to an url and wait for the response
+fn post_blocking(url: &str, kind: &str, tags: &[(&str, &str)], message: &str) {
+	if let Err(e) = request(url, kind, tags, message).and_then(|req| req.send()) {
+		log::error!("Reporting failure to {}: {}", url, e);
+	}
+}
+
+impl Reporter {
+	/// Initialize the reporter from an optional webhook url and an optional sentry dsn
+	pub fn new(webhook: Option<String>, sentry_dsn: Option<&str>) -> Self {
+		if let Some(ref url) = webhook {
+			// report panics before the default handler prints them (blocking as the process may be exiting)
+			let url = url.clone();
+			let default_hook = panic::take_hook();
+			panic::set_hook(Box::new(move |info| {
+				post_blocking(&url, "panic", &[], &redact(&info.to_string()));
+				default_hook(info);
+			}));
+		}
+		#[cfg(not(feature = "sentry"))]
+		if let Some(dsn) = sentry_dsn {
+			log::warn!(
+				"Ignoring sentry dsn {}: rconfd was built without the sentry feature",
+				dsn
+			);
+		}
+		Self {
+			webhook,
+			// sentry integrations report panics on their own
+			#[cfg(feature = "sentry")]
+			_sentry: sentry_dsn.map(|dsn| {
+				sentry::init((
+					dsn,
+					sentry::ClientOptions {
+						release: sentry::release_name!(),
+						..Default::default()
+					},
+				))
+			}),
+		}
+	}
+
+	/// Report a failure of a given kind tagged with template names or secret paths
+	pub fn report(&self, kind: &str, tags: &[(&str, &str)], err: &anyhow::Error) {
+		// secrets must never leave rconfd
+		let message = redact(&format!("{:#}", err));
+		if let Some(ref url) = self.webhook {
+			post(url, kind, tags, &message);
+		}
+		#[cfg(feature = "sentry")]
+		if self._sentry.is_some() {
+			sentry::with_scope(
+				|scope| {
+					scope.set_tag("kind", kind);
+					for (k, v) in tags.iter() {
+						scope.set_tag(k, v);
+					}
+				},
+				|| sentry::capture_message(&message, sentry::Level::Error),
+			);
+		}
+	}
+}
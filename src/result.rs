@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
 	#[error(transparent)]
@@ -14,8 +16,148 @@ pub enum Error {
 	RightBrace,
 	#[error("in \"{0}\", command \"{1}\" should be absolute and start with /")]
 	RelativePath(String, String),
+	#[error("invalid path expression %{{{0}}}")]
+	InvalidPathVar(String),
 	#[error("{1}: {0}")]
 	UnknownVar(String, #[source] std::env::VarError),
+	#[error("{0}: {1}")]
+	MissingVar(String, String),
+	#[error("executing \"{0}\" is forbidden (exe disabled)")]
+	ExeDisabled(String),
+	#[error("executing \"{0}\" is forbidden (not in allowed executables)")]
+	ExeNotAllowed(String),
+	#[error("refusing to write secrets to persistent storage in {0}")]
+	NotMemoryFs(String),
+	#[error("line {0}: {1}")]
+	Hcl(usize, String),
+	#[error("invalid base64 content for {0}: {1}")]
+	InvalidBase64(String, #[source] base64::DecodeError),
+	#[error("in \"{0}\", only files inside /proc and /sys can be read")]
+	ForbiddenPath(String),
+	#[error("unknown host fact \"{0}\"")]
+	UnknownFact(String),
+	#[error("{0} is larger than the maximum of {1} bytes")]
+	SecretTooLarge(String, u64),
+	#[error("{0} should evaluate to an object of files, not a {1}")]
+	NotAnObject(String, String),
+	#[error("invalid {1} in {0}: {2}")]
+	InvalidOutput(String, String, String),
+	#[error("output of {0} is {1} bytes, more than the maximum of {2} bytes")]
+	TooLarge(String, u64, u64),
+	#[error("{0} can't be used in deterministic mode")]
+	NotDeterministic(String),
+	#[error("secret \"{0}\" is leased or dynamic")]
+	NotStatic(String),
+	#[error("invalid time window \"{0}\" (expected HH:MM-HH:MM)")]
+	InvalidWindow(String),
+	#[error("{0} is outside of the swapped directory {1}")]
+	OutsideDir(String, String),
+	#[error("{0} is outside of the fixtures directory {1}")]
+	OutsideFixtures(String, String),
+	#[error("{0} should be a symlink to be swapped")]
+	NotSymlink(String),
+	#[error("refusing to overwrite {0} which was not generated by rconfd")]
+	Clobber(String),
+	#[error("unknown checksum algorithm \"{0}\" (expected blake3 or sha1)")]
+	UnknownChecksum(String),
+	#[error("unknown output format \"{0}\" (expected text or json)")]
+	UnknownFormat(String),
+	#[error("another instance holds the lock {0}")]
+	Locked(String),
+	#[error("invalid ACL entry \"{0}\"")]
+	InvalidAcl(String),
+	#[error("decryption failed: {0}")]
+	Pkcs11(String),
+	#[error("unsealing failed: {0}")]
+	Tpm(String),
+	#[error("resolving TXT records of {0} failed: {1}")]
+	Dns(String, String),
+	#[error("reading kubernetes secret {0} failed: {1}")]
+	K8s(String, String),
+	#[error("reading etcd key {0} failed: {1}")]
+	Etcd(String, String),
+	#[error("fetching {0} failed: {1}")]
+	Http(String, String),
+	#[error("encryption failed: {0}")]
+	Encrypt(String),
+	#[error("unknown user or group in \"{0}\"")]
+	UnknownUser(String),
+	#[error("unknown phase \"{1}\" in {0}")]
+	UnknownPhase(String, String),
+	#[error("a hook failed before signaling readiness")]
+	HookFailed,
+	#[error("not ready after {0:?}, waiting for {1}")]
+	ReadyTimeout(std::time::Duration, String),
+	#[error("login with role {0} failed: {1}")]
+	LoginFailed(String, String),
+	#[error("chaos: injected failure of the {0}")]
+	Chaos(String),
+	#[error("{0} template(s) skipped due to errors")]
+	Skipped(usize),
+	#[error("invalid arguments: {0}")]
+	InvalidArgs(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Class of failure determining the exit code of the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+	/// invalid arguments or config files
+	Config,
+	/// unable to read the JWT token or to log in
+	Auth,
+	/// unable to fetch a secret
+	Secret,
+	/// unable to evaluate or manifest a template
+	Template,
+	/// unable to write a generated file
+	Write,
+	/// some templates have been skipped in keep-going mode
+	Degraded,
+	/// a hook failed before signaling readiness
+	Hook,
+	/// readiness not signaled in time
+	Timeout,
+}
+
+impl Failure {
+	/// Return the class of failure attached as context of an error
+	pub fn of(err: &anyhow::Error) -> Option<Self> {
+		err.downcast_ref::<Failure>().copied()
+	}
+
+	/// Exit code corresponding to the failure
+	pub fn code(&self) -> i32 {
+		match self {
+			Failure::Config => 2,
+			Failure::Auth => 3,
+			Failure::Secret => 4,
+			Failure::Template => 5,
+			Failure::Write => 6,
+			Failure::Degraded => 7,
+			Failure::Hook => 8,
+			Failure::Timeout => 9,
+		}
+	}
+
+	/// Description of the failure
+	fn description(&self) -> &'static str {
+		match self {
+			Failure::Config => "configuration error",
+			Failure::Auth => "authentication error",
+			Failure::Secret => "secret error",
+			Failure::Template => "template error",
+			Failure::Write => "write error",
+			Failure::Degraded => "degraded run",
+			Failure::Hook => "hook error",
+			Failure::Timeout => "readiness timeout",
+		}
+	}
+}
+
+impl fmt::Display for Failure {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.description())
+	}
+}
@@ -1,3 +1,9 @@
+use crate::{
+	memory::zeroize_value,
+	redact::{forget, register, Redacted},
+};
+
+use serde_json::{Map, Value};
 use std::{
 	collections::HashMap,
 	ops::{Deref, DerefMut},
@@ -5,41 +11,155 @@ use std::{
 use vault_jwt::secret::Secret;
 
 /// new type to define new methods over HashMap
-pub struct Secrets(HashMap<String, Option<Secret>>);
+pub struct Secrets {
+	secrets: HashMap<String, Option<Secret>>,
+	/// consider a secret changed when its lease changes even if its value is the same
+	compare_leases: bool,
+}
 
 impl Deref for Secrets {
 	type Target = HashMap<String, Option<Secret>>;
 	fn deref(&self) -> &Self::Target {
-		&self.0
+		&self.secrets
 	}
 }
 
 impl DerefMut for Secrets {
 	fn deref_mut(&mut self) -> &mut Self::Target {
-		&mut self.0
+		&mut self.secrets
 	}
 }
 
 impl Secrets {
-	pub fn new() -> Self {
-		Self(HashMap::<String, Option<Secret>>::new())
+	pub fn new(compare_leases: bool) -> Self {
+		Self {
+			secrets: HashMap::<String, Option<Secret>>::new(),
+			compare_leases,
+		}
 	}
 
 	/// Replace the secret at path if it has changed, and return true if it has been replaced
 	pub fn replace(&mut self, path: &str, secret: Secret) -> bool {
+		let compare_leases = self.compare_leases;
 		let val = self.entry(path.to_owned()).or_insert(None);
 		let prev_val = val.take();
 
-		// if the secret has changed
-		let res = prev_val.is_none() || prev_val.unwrap() != secret;
+		// if the secret has changed (a renewed lease with the same value is not a change by default)
+		let res = match prev_val {
+			Some(mut prev) => {
+				let res = if compare_leases {
+					prev != secret
+				} else {
+					prev.value != secret.value
+				};
+				// scrub the previous value before dropping it
+				zeroize_value(&mut prev.value);
+				res
+			}
+			None => true,
+		};
+		if res {
+			// never show the new value in logs or errors
+			register(path, &secret.value);
+			log::debug!("  secret \"{}\" set to {}", path, Redacted(&secret.value));
+		}
 		// replace/restore (after take) the secret value
 		*val = Some(secret);
 		res
 	}
 
+	/// Remove a secret not used anymore, scrubbing its value and forgetting it for redaction
+	pub fn forget(&mut self, path: &str) {
+		if let Some(Some(mut secret)) = self.remove(path) {
+			zeroize_value(&mut secret.value);
+		}
+		forget(path);
+	}
+
 	/// Tell if the secrets map contains at least a leased secret
 	pub fn any_leased(&self) -> bool {
 		self.iter()
 			.any(|(_, secret)| secret.as_ref().filter(|s| s.has_lease()).is_some())
 	}
 }
+
+/// scrub all secret values when the map is dropped
+impl Drop for Secrets {
+	fn drop(&mut self) {
+		for secret in self.values_mut().flatten() {
+			zeroize_value(&mut secret.value);
+		}
+	}
+}
+
+/// Insert a secret value in map, interpreting dots in name as nested objects
+pub fn insert_nested(map: &mut Map<String, Value>, name: &str, value: Value) {
+	match name.split_once('.') {
+		Some((key, rest)) => {
+			let entry = map
+				.entry(key.to_owned())
+				.or_insert_with(|| Value::Object(Map::new()));
+			if !entry.is_object() {
+				log::warn!("Secret {} is replaced by an object to hold {}", key, rest);
+				*entry = Value::Object(Map::new());
+			}
+			if let Value::Object(map) = entry {
+				insert_nested(map, rest, value);
+			}
+		}
+		None => {
+			map.insert(name.to_owned(), value);
+		}
+	}
+}
+
+/// Return the secret value inserted in map with a (dotted) name
+pub fn get_nested<'a>(map: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+	match name.split_once('.') {
+		Some((key, rest)) => map
+			.get(key)
+			.and_then(|value| value.as_object())
+			.and_then(|map| get_nested(map, rest)),
+		None => map.get(name),
+	}
+}
+
+/// Deep merge value into target: objects are merged recursively, other values are replaced
+pub fn merge_values(target: &mut Value, value: &Value) {
+	match (target, value) {
+		(Value::Object(target), Value::Object(value)) => {
+			for (key, value) in value.iter() {
+				merge_values(target.entry(key.clone()).or_insert(Value::Null), value);
+			}
+		}
+		(target, value) => *target = value.clone(),
+	}
+}
+
+#[test]
+fn merge_objects() {
+	let mut target = serde_json::json!({ "db": { "host": "shared", "port": 5432 } });
+	merge_values(
+		&mut target,
+		&serde_json::json!({ "db": { "host": "service" } }),
+	);
+	assert_eq!(
+		target,
+		serde_json::json!({ "db": { "host": "service", "port": 5432 } })
+	);
+}
+
+#[test]
+fn nested_names() {
+	let mut map = Map::new();
+	insert_nested(&mut map, "db.primary.password", Value::from("pass"));
+	insert_nested(&mut map, "db.primary.user", Value::from("user"));
+	insert_nested(&mut map, "token", Value::from("token"));
+	assert_eq!(
+		Value::Object(map),
+		serde_json::json!({
+			"db": { "primary": { "password": "pass", "user": "user" } },
+			"token": "token"
+		})
+	);
+}
@@ -0,0 +1,677 @@
+use crate::{
+	backend::Backend,
+	clients::kwarg,
+	conf::TemplateConf,
+	dns, etcd,
+	exec::ExePolicy,
+	k8s,
+	libc::{keyring_id, read_key, User},
+	pkcs11,
+	redact::redact,
+	result::Error,
+	sys, tpm, url,
+};
+
+use anyhow::{Context as _, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde_json::Value;
+use std::{
+	env,
+	fs::{self, File},
+	io::Read,
+	os::unix::process::CommandExt,
+	process::Command,
+	time::Duration,
+};
+use vault_jwt::secret::SecretPath;
+use zeroize::Zeroize;
+
+/// State of the process the secret backends may need
+pub struct Context<'a> {
+	/// maximum size of file secrets
+	pub max_secret_size: u64,
+	/// policy applied to exe secrets
+	pub policy: &'a ExePolicy,
+	/// user running rconfd
+	pub current_user: &'a User,
+	/// user the exe secrets are executed as when rconfd runs as root
+	pub exe_user: &'a User,
+	/// JWT token of the service account rconfd runs with
+	pub jwt: &'a str,
+	/// endpoints of the etcd cluster
+	pub etcd: &'a [String],
+	/// template giving its environment and working directory to the exe secrets
+	pub template: Option<&'a TemplateConf>,
+}
+
+/// A source of secrets other than vault
+pub trait SecretBackend {
+	/// Check the arguments of a secret path before fetching anything
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		kind(secret_path).map(|_| ())
+	}
+
+	/// Lease of the fetched secrets: None for static secrets, 0s for secrets invalid as soon as fetched
+	fn lease(&self, _secret_path: &SecretPath<Backend>) -> Result<Option<Duration>> {
+		Ok(None)
+	}
+
+	/// Fetch the value of a secret
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>>;
+}
+
+/// name of the secret parameter giving the file containing the bearer token of http secrets
+const BEARER_KWARG: &str = "bearer";
+
+/// name of the secret parameter giving the interval in seconds between fetches of http secrets
+const REFRESH_KWARG: &str = "refresh";
+
+/// How the content of a secret is converted into a jsonnet value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+	/// string
+	Str,
+	/// parsed json
+	Js,
+}
+
+/// lookup list for secret kinds
+const KINDS: &[(&str, Kind)] = &[("str", Kind::Str), ("js", Kind::Js)];
+
+/// Return the kind of secret given as first argument
+fn kind(secret_path: &SecretPath<Backend>) -> Result<Kind> {
+	secret_path
+		.args
+		.get(0)
+		.and_then(|arg| KINDS.iter().find(|(name, _)| name == arg))
+		.map(|(_, kind)| *kind)
+		.ok_or_else(|| {
+			Error::ExpectedArg("\"str\" or \"js\"".to_owned(), secret_path.to_string()).into()
+		})
+}
+
+/// Convert the bytes of a secret into a string if "str" or parse them as JSON if "js", zeroizing
+/// them in any case
+fn bytes_value(mut data: Vec<u8>, secret_path: &SecretPath<Backend>) -> Result<Value> {
+	match kind(secret_path) {
+		Ok(Kind::Str) => Ok(Value::String(String::from_utf8(data).map_err(|e| {
+			let mut data = e.into_bytes();
+			data.zeroize();
+			anyhow::anyhow!("Reading \"{}\": invalid UTF-8", secret_path.full_path)
+		})?)),
+		Ok(Kind::Js) => {
+			let value = serde_json::from_slice(&data);
+			data.zeroize();
+			value.with_context(|| format!("Parsing \"{}\"", secret_path.full_path))
+		}
+		Err(e) => {
+			data.zeroize();
+			Err(e)
+		}
+	}
+}
+
+/// Environment variables
+struct Env;
+
+impl SecretBackend for Env {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			Ok(match kind(secret_path)? {
+				Kind::Str => {
+					Value::String(env::var(secret_path.full_path).unwrap_or_else(|_| "".to_owned()))
+				}
+				Kind::Js => serde_json::from_str(
+					&env::var(secret_path.full_path).unwrap_or_else(|_| "\"\"".to_owned()),
+				)
+				.with_context(|| {
+					format!("Parsing \"{}\" variable content", secret_path.full_path)
+				})?,
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// Host facts
+struct Sys;
+
+impl SecretBackend for Sys {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let kind = kind(secret_path)?;
+			let facts = sys::facts();
+			let value = if secret_path.full_path == "all" {
+				facts
+			} else {
+				facts
+					.get(secret_path.full_path)
+					.cloned()
+					.ok_or_else(|| Error::UnknownFact(secret_path.full_path.to_owned()))?
+			};
+			Ok(match (kind, value) {
+				(Kind::Str, Value::String(s)) => Value::String(s),
+				(Kind::Str, value) => Value::String(value.to_string()),
+				(Kind::Js, value) => value,
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// Kernel command line and procfs/sysfs files
+struct Proc;
+
+impl SecretBackend for Proc {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let kind = kind(secret_path)?;
+			let proc_path = if secret_path.full_path == "cmdline" {
+				sys::CMDLINE
+			} else {
+				secret_path.full_path
+			};
+			// only kernel exposed files can be read
+			if !sys::PROC_DIRS.iter().any(|dir| proc_path.starts_with(dir))
+				|| proc_path.contains("/../")
+			{
+				return Err(Error::ForbiddenPath(secret_path.to_string()).into());
+			}
+			let content = fs::read_to_string(proc_path)
+				.with_context(|| format!("Reading \"{}\"", proc_path))?;
+			Ok(match kind {
+				Kind::Str => Value::String(content.trim().to_owned()),
+				Kind::Js if proc_path == sys::CMDLINE => sys::parse_cmdline(&content),
+				Kind::Js => serde_json::from_str(&content)
+					.with_context(|| format!("Parsing \"{}\"", proc_path))?,
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// TPM sealed objects
+struct Tpm;
+
+impl SecretBackend for Tpm {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			// persistent handle of the parent key (hexadecimal)
+			let parent = match secret_path.args.get(1) {
+				Some(handle) => {
+					u32::from_str_radix(handle.trim_start_matches("0x"), 16).map_err(|_| {
+						Error::ExpectedArg("a parent handle".to_owned(), secret_path.to_string())
+					})?
+				}
+				None => tpm::PARENT_HANDLE,
+			};
+			let data = tpm::unseal(secret_path.full_path, parent)?;
+			bytes_value(data, secret_path)
+		}
+		.boxed_local()
+	}
+}
+
+/// Files encrypted for a PKCS#11 private key
+struct Pkcs11;
+
+impl SecretBackend for Pkcs11 {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let label = secret_path.args.get(1).ok_or_else(|| {
+				Error::ExpectedArg("a key label".to_owned(), secret_path.to_string())
+			})?;
+			let data = pkcs11::decrypt(secret_path.full_path, label)?;
+			bytes_value(data, secret_path)
+		}
+		.boxed_local()
+	}
+}
+
+/// Linux kernel keyrings
+struct Keyring;
+
+impl SecretBackend for Keyring {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let keyring = secret_path.args.get(1).unwrap_or(&"session");
+			let id = keyring_id(keyring).ok_or_else(|| {
+				Error::ExpectedArg("a keyring".to_owned(), secret_path.to_string())
+			})?;
+			let data = read_key(id, secret_path.full_path).with_context(|| {
+				format!(
+					"Reading key \"{}\" from the {} keyring",
+					secret_path.full_path, keyring
+				)
+			})?;
+			bytes_value(data, secret_path)
+		}
+		.boxed_local()
+	}
+}
+
+/// DNS TXT records
+struct Dns;
+
+impl SecretBackend for Dns {
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		kind(secret_path)?;
+		dnssec(secret_path).map(|_| ())
+	}
+
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		_ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let kind = kind(secret_path)?;
+			let content = dns::txt(secret_path.full_path, dnssec(secret_path)?).await?;
+			Ok(match kind {
+				Kind::Str => Value::String(content),
+				Kind::Js => serde_json::from_str(&content).with_context(|| {
+					format!("Parsing TXT records of \"{}\"", secret_path.full_path)
+				})?,
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// Kubernetes Secrets
+struct K8s;
+
+impl SecretBackend for K8s {
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		k8s_path(secret_path).map(|_| ())
+	}
+
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let (namespace, name, key) = k8s_path(secret_path)?;
+			let mut data = k8s::secret(namespace, name, ctx.jwt).await?;
+			Ok(match key {
+				Some(key) => data.remove(key).ok_or_else(|| {
+					Error::ExpectedArg(format!("a key {}", key), secret_path.to_string())
+				})?,
+				None => Value::Object(data),
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// Return the namespace, the name and the key of a kubernetes Secret: namespace/name for the whole secret
+/// (js), namespace/name/key for a single key (str)
+fn k8s_path<'a>(
+	secret_path: &SecretPath<'a, Backend>,
+) -> Result<(&'a str, &'a str, Option<&'a str>)> {
+	let parts = secret_path.full_path.split('/').collect::<Vec<_>>();
+	match (kind(secret_path)?, parts.as_slice()) {
+		(Kind::Js, [namespace, name]) => Ok((*namespace, *name, None)),
+		(Kind::Str, [namespace, name, key]) => Ok((*namespace, *name, Some(*key))),
+		(Kind::Js, _) => {
+			Err(Error::ExpectedArg("namespace/name".to_owned(), secret_path.to_string()).into())
+		}
+		(Kind::Str, _) => {
+			Err(Error::ExpectedArg("namespace/name/key".to_owned(), secret_path.to_string()).into())
+		}
+	}
+}
+
+/// etcd keys
+struct Etcd;
+
+impl SecretBackend for Etcd {
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		etcd_key(secret_path).map(|_| ())
+	}
+
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let (key, prefix) = etcd_key(secret_path)?;
+			let mut values = etcd::get(ctx.etcd, key, prefix).await?;
+			if prefix {
+				return Ok(Value::Object(values));
+			}
+			let value = values.remove(key).unwrap_or_default();
+			Ok(match (kind(secret_path)?, value) {
+				(Kind::Js, Value::String(s)) => serde_json::from_str(&s)
+					.with_context(|| format!("Parsing etcd key \"{}\"", key))?,
+				(_, value) => value,
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// HTTP(S) urls
+struct Http;
+
+impl SecretBackend for Http {
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		kind(secret_path)?;
+		http_method(secret_path)?;
+		self.lease(secret_path).map(|_| ())
+	}
+
+	/// documents fetched again periodically are valid until the next fetch
+	fn lease(&self, secret_path: &SecretPath<Backend>) -> Result<Option<Duration>> {
+		kwarg(secret_path, REFRESH_KWARG)
+			.map(|secs| {
+				secs.parse::<u64>()
+					.ok()
+					.filter(|secs| *secs > 0)
+					.map(Duration::from_secs)
+					.ok_or_else(|| {
+						Error::ExpectedArg(
+							format!("{} as a number of seconds", REFRESH_KWARG),
+							secret_path.to_string(),
+						)
+						.into()
+					})
+			})
+			.transpose()
+	}
+
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let method = http_method(secret_path)?;
+			let mut bearer = match kwarg(secret_path, BEARER_KWARG) {
+				Some(token_path) => Some(
+					fs::read_to_string(&token_path)
+						.with_context(|| format!("Reading bearer token \"{}\"", token_path))?,
+				),
+				None => None,
+			};
+			let data = url::fetch(
+				secret_path.full_path,
+				method,
+				bearer.as_deref().map(str::trim),
+				ctx.max_secret_size,
+			)
+			.await;
+			bearer.zeroize();
+			bytes_value(data?, secret_path)
+		}
+		.boxed_local()
+	}
+}
+
+/// Return the HTTP method given as second argument (GET by default)
+fn http_method<'a>(secret_path: &SecretPath<'a, Backend>) -> Result<&'a str> {
+	match secret_path.args.get(1) {
+		None => Ok("GET"),
+		Some(method) if url::METHODS.contains(method) => Ok(*method),
+		_ => Err(
+			Error::ExpectedArg("\"GET\" or \"POST\"".to_owned(), secret_path.to_string()).into(),
+		),
+	}
+}
+
+/// Return the etcd key of a secret and true if all the keys starting with it must be read (js only)
+pub fn etcd_key<'a>(secret_path: &SecretPath<'a, Backend>) -> Result<(&'a str, bool)> {
+	match (kind(secret_path)?, secret_path.args.get(1)) {
+		(_, None) => Ok((secret_path.full_path, false)),
+		(Kind::Js, Some(&"prefix")) => Ok((secret_path.full_path, true)),
+		_ => {
+			Err(Error::ExpectedArg("\"prefix\" with js".to_owned(), secret_path.to_string()).into())
+		}
+	}
+}
+
+/// return true if the TXT records must be validated with DNSSEC
+fn dnssec(secret_path: &SecretPath<Backend>) -> Result<bool> {
+	match secret_path.args.get(1) {
+		Some(&"dnssec") => Ok(true),
+		None => Ok(false),
+		_ => Err(Error::ExpectedArg("\"dnssec\"".to_owned(), secret_path.to_string()).into()),
+	}
+}
+
+/// Files
+struct FileSource;
+
+impl SecretBackend for FileSource {
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let kind = kind(secret_path)?;
+			let file = File::open(secret_path.full_path)
+				.with_context(|| format!("Opening \"{}\"", secret_path.full_path))?;
+			// read at most max_secret_size bytes (plus one to detect bigger files)
+			let too_large = || Error::SecretTooLarge(secret_path.to_string(), ctx.max_secret_size);
+			if file.metadata()?.len() > ctx.max_secret_size {
+				return Err(too_large().into());
+			}
+			let mut buffer = Vec::new();
+			file.take(ctx.max_secret_size + 1)
+				.read_to_end(&mut buffer)
+				.with_context(|| format!("Reading \"{}\"", secret_path.full_path))?;
+			if buffer.len() as u64 > ctx.max_secret_size {
+				return Err(too_large().into());
+			}
+			Ok(match kind {
+				Kind::Str => Value::String(
+					String::from_utf8(buffer)
+						.with_context(|| format!("Reading \"{}\"", secret_path.full_path))?,
+				),
+				Kind::Js => {
+					let value = serde_json::from_slice(&buffer)
+						.with_context(|| format!("Parsing \"{}\"", secret_path.full_path))?;
+					buffer.zeroize();
+					value
+				}
+			})
+		}
+		.boxed_local()
+	}
+}
+
+/// Commands
+struct Exe;
+
+impl SecretBackend for Exe {
+	fn check(&self, secret_path: &SecretPath<Backend>) -> Result<()> {
+		kind(secret_path)?;
+		self.lease(secret_path).map(|_| ())
+	}
+
+	/// secret declared as static (default) have no lease, whereas dynamic are invalid as soon as fetched (0s lease)
+	fn lease(&self, secret_path: &SecretPath<Backend>) -> Result<Option<Duration>> {
+		match secret_path.args.get(1) {
+			Some(&"static") | None => Ok(None),
+			Some(&"dynamic") => Ok(Some(Duration::from_secs(0))),
+			_ => Err(Error::ExpectedArg(
+				"\"static\" or \"dynamic\"".to_owned(),
+				secret_path.to_string(),
+			)
+			.into()),
+		}
+	}
+
+	fn fetch<'a>(
+		&'a self,
+		secret_path: &'a SecretPath<'a, Backend>,
+		ctx: &'a Context<'a>,
+	) -> LocalBoxFuture<'a, Result<Value>> {
+		async move {
+			let kind = kind(secret_path)?;
+			let args: Vec<&str> = secret_path.full_path.split_whitespace().collect();
+			// enforce absolute exec path for security reason
+			if !args[0].starts_with('/') {
+				return Err(
+					Error::RelativePath(secret_path.to_string(), args[0].to_owned()).into(),
+				);
+			}
+			ctx.policy.check(args[0])?;
+			let mut cmd = &mut Command::new(args[0]);
+			// drop privileges if uid is 0 before executing (supplementary groups are dropped too)
+			if ctx.current_user.uid == 0 {
+				log::debug!(
+					"    executing \"{}\" as {}:{}",
+					secret_path.full_path,
+					ctx.exe_user.uid,
+					ctx.exe_user.gid
+				);
+				cmd = cmd.uid(ctx.exe_user.uid).gid(ctx.exe_user.gid);
+			}
+			if args.len() > 1 {
+				cmd = cmd.args(&args[1..]);
+			}
+			if let Some(template) = ctx.template {
+				template.prepare(cmd);
+			}
+			ctx.policy
+				.sandbox(cmd, &[])
+				.with_context(|| format!("Sandboxing \"{}\"", secret_path.full_path))?;
+			let mut output = cmd
+				.output()
+				.with_context(|| format!("Executing \"{}\"", secret_path.full_path))?;
+			if !output.status.success() {
+				return Err(Error::Cmd(
+					secret_path.full_path.to_owned(),
+					output.status.code().unwrap_or(1),
+					redact(&String::from_utf8_lossy(&output.stderr)),
+				)
+				.into());
+			}
+			let value = match kind {
+				Kind::Str => {
+					Value::String(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+				}
+				Kind::Js => serde_json::from_str(
+					&env::var(secret_path.full_path).unwrap_or_else(|_| "\"\"".to_owned()),
+				)
+				.with_context(|| {
+					format!("Parsing \"{}\" variable content", secret_path.full_path)
+				})?,
+			};
+			// scrub the command output now that it has been parsed
+			output.stdout.zeroize();
+			Ok(value)
+		}
+		.boxed_local()
+	}
+}
+
+/// registry of the secret backends other than vault
+static SOURCES: &[(Backend, &(dyn SecretBackend + Sync))] = &[
+	(Backend::Env, &Env),
+	(Backend::File, &FileSource),
+	(Backend::Exe, &Exe),
+	(Backend::Sys, &Sys),
+	(Backend::Proc, &Proc),
+	(Backend::Tpm, &Tpm),
+	(Backend::Pkcs11, &Pkcs11),
+	(Backend::Keyring, &Keyring),
+	(Backend::Dns, &Dns),
+	(Backend::K8s, &K8s),
+	(Backend::Etcd, &Etcd),
+	(Backend::Http, &Http),
+];
+
+/// Return the implementation of a secret backend (None for vault which is handled by the actor loop)
+pub fn get(backend: Backend) -> Option<&'static (dyn SecretBackend + Sync)> {
+	SOURCES
+		.iter()
+		.find(|(b, _)| *b == backend)
+		.map(|(_, source)| *source)
+}
+
+#[test]
+fn env_source() {
+	use std::convert::TryFrom;
+	let policy = ExePolicy::new(true, Vec::new(), None);
+	let ctx = Context {
+		max_secret_size: 1024,
+		policy: &policy,
+		current_user: &User {
+			uid: 1000,
+			gid: 1000,
+		},
+		exe_user: &User {
+			uid: 65534,
+			gid: 65534,
+		},
+		jwt: "",
+		etcd: &[],
+		template: None,
+	};
+	env::set_var("RCONFD_TEST_SOURCE", "{\"user\": \"admin\"}");
+	let secret_path = SecretPath::<Backend>::try_from("env:js:RCONFD_TEST_SOURCE").unwrap();
+	let value = async_std::task::block_on(get(Backend::Env).unwrap().fetch(&secret_path, &ctx));
+	assert_eq!(value.unwrap()["user"], "admin");
+	let secret_path = SecretPath::<Backend>::try_from("env:bin:RCONFD_TEST_SOURCE").unwrap();
+	assert!(get(Backend::Env).unwrap().check(&secret_path).is_err());
+}
+
+#[test]
+fn exe_lease() {
+	use std::convert::TryFrom;
+	let secret_path = SecretPath::<Backend>::try_from("exe:str,dynamic:/usr/bin/date +%s").unwrap();
+	assert_eq!(
+		Exe.lease(&secret_path).unwrap(),
+		Some(Duration::from_secs(0))
+	);
+	let secret_path = SecretPath::<Backend>::try_from("exe:str:/usr/bin/nproc").unwrap();
+	assert_eq!(Exe.lease(&secret_path).unwrap(), None);
+}
+
+#[test]
+fn http_lease() {
+	use std::convert::TryFrom;
+	let secret_path =
+		SecretPath::<Backend>::try_from("http:js,GET,refresh=60:https://config.internal/app.json")
+			.unwrap();
+	assert_eq!(
+		Http.lease(&secret_path).unwrap(),
+		Some(Duration::from_secs(60))
+	);
+	let secret_path =
+		SecretPath::<Backend>::try_from("http:str,PUT:https://config.internal/app").unwrap();
+	assert!(Http.check(&secret_path).is_err());
+}
@@ -0,0 +1,35 @@
+/// Rotate over the performance standbys (or replicas) of a vault cluster to send them the read requests
+pub struct Standbys {
+	urls: Vec<String>,
+	next: usize,
+}
+
+impl Standbys {
+	pub fn new(urls: &[String]) -> Self {
+		Self {
+			urls: urls.to_vec(),
+			next: 0,
+		}
+	}
+
+	/// Return the url of the standby to send a request with the given method to, or None if the request
+	/// must go to the active node
+	pub fn url(&mut self, method: &str) -> Option<&str> {
+		if method != "GET" || self.urls.is_empty() {
+			return None;
+		}
+		let url = &self.urls[self.next];
+		self.next = (self.next + 1) % self.urls.len();
+		Some(url)
+	}
+}
+
+#[test]
+fn rotation() {
+	let mut standbys = Standbys::new(&["a".to_owned(), "b".to_owned()]);
+	assert_eq!(standbys.url("POST"), None);
+	assert_eq!(standbys.url("GET"), Some("a"));
+	assert_eq!(standbys.url("GET"), Some("b"));
+	assert_eq!(standbys.url("GET"), Some("a"));
+	assert_eq!(Standbys::new(&[]).url("GET"), None);
+}
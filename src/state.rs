@@ -0,0 +1,158 @@
+use crate::libc::User;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+	collections::HashMap,
+	fs::{self, create_dir_all, File, OpenOptions},
+	io::{BufReader, Write},
+	os::unix::fs::{OpenOptionsExt, PermissionsExt},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use vault_jwt::secret::Secret;
+
+/// name of the file holding the leased secrets inside the state directory
+const LEASES_FILE: &str = "leases.json";
+
+/// A leased secret as saved on disk
+#[derive(Serialize, Deserialize)]
+struct Lease {
+	/// secret value
+	value: Value,
+	/// unix time (s) at which the secret should be renewed
+	renew_at: u64,
+	/// unix time (s) at which the lease expires
+	expire_at: u64,
+}
+
+/// Persist leased secrets inside a directory so that a restarted daemon can resume renewals
+/// instead of issuing new dynamic credentials
+pub struct State {
+	dir: PathBuf,
+	leases: HashMap<String, Lease>,
+}
+
+/// return the current unix time in seconds
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+impl State {
+	/// Open (and create if necessary) the state directory, restricting its access to the current user
+	pub fn new(dir: &str) -> Result<Self> {
+		let dir = PathBuf::from(dir);
+		create_dir_all(&dir).with_context(|| format!("Creating state dir {:?}", &dir))?;
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))
+			.with_context(|| format!("Setting permissions of state dir {:?}", &dir))?;
+		let path = dir.join(LEASES_FILE);
+		let leases = if path.exists() {
+			let reader =
+				BufReader::new(File::open(&path).with_context(|| format!("Opening {:?}", &path))?);
+			// a corrupted state is not fatal: we just fetch everything again
+			serde_json::from_reader(reader).unwrap_or_else(|e| {
+				log::warn!("Ignoring invalid state file {:?}: {}", &path, e);
+				HashMap::new()
+			})
+		} else {
+			HashMap::new()
+		};
+		Ok(Self { dir, leases })
+	}
+
+	/// Return the secret saved at path, valid until its lease expires, and the delay before its renewal if it
+	/// doesn't need to be renewed yet
+	pub fn restore(&self, path: &str) -> Option<(Secret, Duration)> {
+		let now = now();
+		self.leases
+			.get(path)
+			.filter(|lease| lease.renew_at > now && lease.expire_at > lease.renew_at)
+			.map(|lease| {
+				(
+					Secret::new(
+						lease.value.clone(),
+						Some(Duration::from_secs(lease.expire_at - now)),
+					),
+					Duration::from_secs(lease.renew_at - now),
+				)
+			})
+	}
+
+	/// Forget the lease of a secret not used anymore
+	pub fn remove(&mut self, path: &str) -> Result<()> {
+		if self.leases.remove(path).is_none() {
+			return Ok(());
+		}
+		self.write()
+	}
+
+	/// Save a freshly fetched leased secret
+	pub fn save(&mut self, path: &str, secret: &Secret) -> Result<()> {
+		match secret.renew_delay() {
+			Some(renew_delay) if secret.has_lease() => {
+				let now = now();
+				// leases are renewed at 2/3 of their duration
+				self.leases.insert(
+					path.to_owned(),
+					Lease {
+						value: secret.value.clone(),
+						renew_at: now + renew_delay.as_secs(),
+						expire_at: now + renew_delay.as_secs() * 3 / 2,
+					},
+				);
+			}
+			_ => {
+				// nothing to resume for that secret
+				if self.leases.remove(path).is_none() {
+					return Ok(());
+				}
+			}
+		}
+		self.write()
+	}
+
+	/// Give the state directory and its files to user
+	pub fn chown(&self, user: &User) -> Result<()> {
+		user.chown(&self.dir);
+		for entry in fs::read_dir(&self.dir)? {
+			user.chown(entry?.path());
+		}
+		Ok(())
+	}
+
+	/// Return the path of a file inside the state directory
+	pub fn path(&self, name: &str) -> PathBuf {
+		self.dir.join(name)
+	}
+
+	/// Write the leases file
+	fn write(&self) -> Result<()> {
+		write_private(&self.path(LEASES_FILE), &serde_json::to_vec(&self.leases)?)
+	}
+}
+
+/// Atomically write a file readable only by the current user
+pub fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+	let mut tmp_path = path.to_owned();
+	tmp_path.set_file_name(format!(
+		".{}",
+		path.file_name()
+			.map(|s| s.to_string_lossy())
+			.unwrap_or_default()
+	));
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(0o600)
+		.open(&tmp_path)
+		.with_context(|| format!("Opening {:?}", &tmp_path))?;
+	file.write_all(content)
+		.with_context(|| format!("Writing {:?}", &tmp_path))?;
+	fs::rename(&tmp_path, path).with_context(|| format!("Renaming {:?}", &tmp_path))?;
+	Ok(())
+}
@@ -0,0 +1,124 @@
+use serde_json::{json, Map, Value};
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Mutex,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// State of the main loop shared with the tasks reporting it
+#[derive(Default)]
+pub struct Status {
+	/// the actor loop is running
+	alive: AtomicBool,
+	/// all templates have been generated at least once
+	ready: AtomicBool,
+	/// number of templates
+	templates: AtomicUsize,
+	/// number of templates skipped in keep-going mode
+	failed: AtomicUsize,
+	/// vault is unreachable and the files are left as is
+	degraded: AtomicBool,
+	/// evaluation and write durations of the last manifestation of each template
+	timings: Mutex<HashMap<String, (Duration, Duration)>>,
+	/// expiry time of the secrets about to expire without having been renewed
+	expiring: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Status {
+	pub fn is_alive(&self) -> bool {
+		self.alive.load(Ordering::Relaxed)
+	}
+
+	pub fn set_alive(&self, alive: bool) {
+		self.alive.store(alive, Ordering::Relaxed)
+	}
+
+	pub fn is_ready(&self) -> bool {
+		self.ready.load(Ordering::Relaxed)
+	}
+
+	pub fn set_ready(&self, ready: bool) {
+		self.ready.store(ready, Ordering::Relaxed)
+	}
+
+	pub fn templates(&self) -> usize {
+		self.templates.load(Ordering::Relaxed)
+	}
+
+	pub fn failed(&self) -> usize {
+		self.failed.load(Ordering::Relaxed)
+	}
+
+	pub fn set_templates(&self, templates: usize, failed: usize) {
+		self.templates.store(templates, Ordering::Relaxed);
+		self.failed.store(failed, Ordering::Relaxed);
+	}
+
+	pub fn is_degraded(&self) -> bool {
+		self.degraded.load(Ordering::Relaxed)
+	}
+
+	pub fn set_degraded(&self, degraded: bool) {
+		self.degraded.store(degraded, Ordering::Relaxed)
+	}
+
+	pub fn set_timings(&self, tmpl: &str, render: Duration, write: Duration) {
+		self.timings
+			.lock()
+			.unwrap()
+			.insert(tmpl.to_owned(), (render, write));
+	}
+
+	/// Set or clear (None) the expiry time of a secret that hasn't been renewed
+	pub fn set_expiring(&self, path: &str, expiry: Option<SystemTime>) {
+		let mut expiring = self.expiring.lock().unwrap();
+		match expiry {
+			Some(expiry) => expiring.insert(path.to_owned(), expiry),
+			None => expiring.remove(path),
+		};
+	}
+
+	/// Return the status as a JSON object
+	pub fn to_json(&self) -> Value {
+		let timings: Map<String, Value> = self
+			.timings
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(tmpl, (render, write))| {
+				(
+					tmpl.to_owned(),
+					json!({
+						"render_ms": render.as_millis() as u64,
+						"write_ms": write.as_millis() as u64,
+					}),
+				)
+			})
+			.collect();
+		let expiring: Map<String, Value> = self
+			.expiring
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(path, expiry)| {
+				let ts = expiry
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_secs())
+					.unwrap_or(0);
+				(path.to_owned(), json!(ts))
+			})
+			.collect();
+		json!({
+			"alive": self.is_alive(),
+			"ready": self.is_ready(),
+			"templates": self.templates(),
+			"failed": self.failed(),
+			"degraded": self.is_degraded(),
+			"timings": timings,
+			"expiring": expiring,
+		})
+	}
+}
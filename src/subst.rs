@@ -1,3 +1,4 @@
+use serde_json::Value;
 use std::env;
 
 use crate::result::{Error, Result};
@@ -9,14 +10,33 @@ pub enum Token<'a> {
 	BraceError,
 }
 
-/// Iterator over string slices and variable expressions (${NAME}) on a given string
+/// Iterator over string slices and variable expressions (${NAME}) on a given string. $$ is an escaped $
 pub struct SubstIterator<'a> {
 	remainder: &'a str,
+	/// start of a variable expression
+	start: &'static str,
+	/// escaped first character of start
+	escape: &'static str,
 }
 
 impl<'a> SubstIterator<'a> {
 	pub fn new(string: &'a str) -> Self {
-		Self { remainder: string }
+		Self::with_start(string, "${", "$$")
+	}
+
+	/// Iterate over variable expressions starting with start instead of ${, and with escape instead of $$
+	pub fn with_start(string: &'a str, start: &'static str, escape: &'static str) -> Self {
+		Self {
+			remainder: string,
+			start,
+			escape,
+		}
+	}
+
+	pub fn yield_escape(&mut self) -> Option<Token<'a>> {
+		let chunk = &self.remainder[..1];
+		self.remainder = &self.remainder[self.escape.len()..];
+		Some(Token::Str(chunk))
 	}
 
 	pub fn yield_remainder(&mut self) -> Option<Token<'a>> {
@@ -27,7 +47,7 @@ impl<'a> SubstIterator<'a> {
 
 	pub fn yield_var(&mut self) -> Option<Token<'a>> {
 		return if let Some(end) = self.remainder.find('}') {
-			let name = &self.remainder[2..end];
+			let name = &self.remainder[self.start.len()..end];
 			self.remainder = &self.remainder[end + 1..];
 			Some(Token::Var(name))
 		} else {
@@ -49,10 +69,18 @@ impl<'a> Iterator for SubstIterator<'a> {
 	fn next(&mut self) -> Option<Self::Item> {
 		return if self.remainder.is_empty() {
 			None
-		} else if self.remainder.starts_with("${") {
+		} else if self.remainder.starts_with(self.start) {
 			self.yield_var()
+		} else if self.remainder.starts_with(self.escape) {
+			self.yield_escape()
 		} else {
-			match self.remainder.find("${") {
+			let (remainder, start, escape) = (self.remainder, self.start, self.escape);
+			// next variable expression or escape
+			let end = remainder
+				.match_indices(&start[..1])
+				.map(|(i, _)| i)
+				.find(|&i| remainder[i..].starts_with(start) || remainder[i..].starts_with(escape));
+			match end {
 				None => self.yield_remainder(),
 				Some(end) => self.yield_str(end),
 			}
@@ -60,6 +88,24 @@ impl<'a> Iterator for SubstIterator<'a> {
 	}
 }
 
+/// return the value of a variable expression: NAME, NAME:-default or NAME:?error message
+fn envar(expr: &str) -> Result<String> {
+	if let Some((name, default)) = expr.split_once(":-") {
+		// unset or empty variable
+		Ok(env::var(name)
+			.ok()
+			.filter(|val| !val.is_empty())
+			.unwrap_or_else(|| default.to_owned()))
+	} else if let Some((name, msg)) = expr.split_once(":?") {
+		env::var(name)
+			.ok()
+			.filter(|val| !val.is_empty())
+			.ok_or_else(|| Error::MissingVar(name.to_owned(), msg.to_owned()))
+	} else {
+		env::var(expr).map_err(|e| Error::UnknownVar(expr.to_owned(), e))
+	}
+}
+
 /// return expr with expression ${VAR} subsitued by variable content
 /// TODO: use a COW
 pub fn subst_envar(s: &str) -> Result<String> {
@@ -69,8 +115,54 @@ pub fn subst_envar(s: &str) -> Result<String> {
 			Token::Str(chunk) => {
 				res += chunk;
 			}
-			Token::Var(name) => {
-				let val = env::var(name).map_err(|e| Error::UnknownVar(name.to_owned(), e))?;
+			Token::Var(expr) => {
+				res += &envar(expr)?;
+			}
+			Token::BraceError => return Err(Error::RightBrace),
+		}
+	}
+	Ok(res)
+}
+
+/// return path with expressions %{secrets.name[.field]*} and %{env.NAME} substituted by the value of
+/// a secret (found by name with lookup) or of an environment variable
+pub fn subst_path<'a, F>(path: &str, lookup: F) -> Result<String>
+where
+	F: Fn(&str) -> Option<&'a Value>,
+{
+	let mut res = String::new();
+	for token in SubstIterator::with_start(path, "%{", "%%") {
+		match token {
+			Token::Str(chunk) => {
+				res += chunk;
+			}
+			Token::Var(expr) => {
+				let parts: Vec<&str> = expr.split('.').collect();
+				let val = match parts.as_slice() {
+					["env", name] => {
+						env::var(name).map_err(|e| Error::UnknownVar((*name).to_owned(), e))?
+					}
+					["secrets", names @ ..] if !names.is_empty() => {
+						// secret names can contain dots: try the longest name first
+						let value = (1..=names.len()).rev().find_map(|i| {
+							names[i..]
+								.iter()
+								.fold(lookup(&names[..i].join(".")), |value, field| {
+									value.and_then(|v| v.get(field))
+								})
+						});
+						match value {
+							Some(Value::String(s)) => s.to_owned(),
+							Some(Value::Number(n)) => n.to_string(),
+							_ => return Err(Error::InvalidPathVar(expr.to_owned())),
+						}
+					}
+					_ => return Err(Error::InvalidPathVar(expr.to_owned())),
+				};
+				// a value can't change the directory of the file
+				if val.is_empty() || val.contains('/') || val == ".." {
+					return Err(Error::InvalidPathVar(expr.to_owned()));
+				}
 				res += &val;
 			}
 			Token::BraceError => return Err(Error::RightBrace),
@@ -109,3 +201,37 @@ fn error() {
 	let tokens: Vec<_> = SubstIterator::new("${TEST").collect();
 	assert_eq!(tokens, &[Token::BraceError]);
 }
+
+#[test]
+fn secret_path() {
+	let secrets = serde_json::json!({ "cert": { "domain": "example.com" } });
+	let lookup = |name: &str| secrets.get(name);
+	assert_eq!(
+		subst_path("%{secrets.cert.domain}.crt", lookup).unwrap(),
+		"example.com.crt"
+	);
+	assert!(subst_path("%{secrets.cert.missing}.crt", lookup).is_err());
+}
+
+#[test]
+fn escape() {
+	let tokens: Vec<_> = SubstIterator::new("a$$b$c${TEST}").collect();
+	assert_eq!(
+		tokens,
+		&[
+			Token::Str("a"),
+			Token::Str("$"),
+			Token::Str("b$c"),
+			Token::Var("TEST")
+		]
+	);
+}
+
+#[test]
+fn default_value() {
+	assert_eq!(
+		subst_envar("${RCONFD_UNSET_VAR:-default}").unwrap(),
+		"default"
+	);
+	assert!(subst_envar("${RCONFD_UNSET_VAR:?required}").is_err());
+}
@@ -0,0 +1,85 @@
+use crate::result::Error;
+
+use serde::Serialize;
+use std::{collections::BTreeMap, str::FromStr, time::Instant};
+
+/// Format of the summary printed at the end of one-shot runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+	Text,
+	Json,
+}
+
+/// lookup list for summary formats
+const FORMATS: &[(&str, Format)] = &[("text", Format::Text), ("json", Format::Json)];
+
+impl FromStr for Format {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		FORMATS
+			.iter()
+			.find(|(name, _)| *name == s)
+			.map(|(_, format)| *format)
+			.ok_or_else(|| Error::UnknownFormat(s.to_owned()))
+	}
+}
+
+/// Statistics of a run
+#[derive(Debug, Serialize)]
+pub struct Summary {
+	/// templates rendered
+	pub templates: usize,
+	/// files written
+	pub written: usize,
+	/// files left as is because their content didn't change
+	pub unchanged: usize,
+	/// secrets fetched by backend
+	pub secrets: BTreeMap<String, usize>,
+	/// duration of the run in seconds
+	pub duration: f64,
+	#[serde(skip)]
+	start: Instant,
+}
+
+impl Summary {
+	pub fn new() -> Self {
+		Self {
+			templates: 0,
+			written: 0,
+			unchanged: 0,
+			secrets: BTreeMap::new(),
+			duration: 0.0,
+			start: Instant::now(),
+		}
+	}
+
+	/// Count a secret fetched from backend
+	pub fn fetched(&mut self, backend: &str) {
+		*self.secrets.entry(backend.to_owned()).or_default() += 1;
+	}
+
+	/// Print the summary in the given format on stdout
+	pub fn print(&mut self, format: Format) -> anyhow::Result<()> {
+		self.duration = self.start.elapsed().as_secs_f64();
+		match format {
+			Format::Json => println!("{}", serde_json::to_string(self)?),
+			Format::Text => {
+				println!("{:<20}{:>8}", "templates rendered", self.templates);
+				println!("{:<20}{:>8}", "files written", self.written);
+				println!("{:<20}{:>8}", "files unchanged", self.unchanged);
+				for (backend, count) in self.secrets.iter() {
+					println!("{:<20}{:>8}", format!("{} secrets", backend), count);
+				}
+				println!("{:<20}{:>7.2}s", "duration", self.duration);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[test]
+fn summary_format() {
+	assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+	assert!("yaml".parse::<Format>().is_err());
+}
@@ -0,0 +1,105 @@
+use crate::result::Error;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+	fs,
+	os::unix::fs::symlink,
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// suffix of the files staged before being swapped into place
+const STAGED_SUFFIX: &str = "rconfd";
+
+/// How the resulting files of a destination are swapped into place together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Swap {
+	/// stage all files next to their destination then rename them one after the other
+	Rename,
+	/// write all files in a new versioned directory then point the destination symlink to it
+	Symlink,
+}
+
+/// Return the hidden path where a file is staged before being renamed
+pub fn staged(path: &Path) -> PathBuf {
+	let mut staged = path.to_owned();
+	staged.set_file_name(format!(
+		".{}.{}",
+		path.file_name()
+			.map(|s| s.to_string_lossy())
+			.unwrap_or_default(),
+		STAGED_SUFFIX
+	));
+	staged
+}
+
+/// Return a new versioned directory next to dir
+pub fn version(dir: &Path) -> PathBuf {
+	let version = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis())
+		.unwrap_or(0);
+	let mut versioned = dir.to_owned();
+	versioned.set_file_name(format!(
+		"{}.{}",
+		dir.file_name()
+			.map(|s| s.to_string_lossy())
+			.unwrap_or_default(),
+		version
+	));
+	versioned
+}
+
+/// Return the path of a file inside the versioned directory
+pub fn versioned(dir: &Path, version: &Path, path: &Path) -> Result<PathBuf> {
+	path.strip_prefix(dir)
+		.map(|file| version.join(file))
+		.map_err(|_| {
+			Error::OutsideDir(
+				path.to_string_lossy().into_owned(),
+				dir.to_string_lossy().into_owned(),
+			)
+			.into()
+		})
+}
+
+/// Atomically point the dir symlink to version and remove the version it pointed to before
+pub fn flip(dir: &Path, version: &Path) -> Result<()> {
+	// a real directory can't be swapped atomically
+	let previous = match fs::symlink_metadata(dir) {
+		Ok(meta) if meta.file_type().is_symlink() => fs::read_link(dir).ok(),
+		Ok(_) => return Err(Error::NotSymlink(dir.to_string_lossy().into_owned()).into()),
+		Err(_) => None,
+	};
+	// relative link so that the tree can be moved (or used in another root)
+	let target = version.file_name().map(PathBuf::from).unwrap_or_default();
+	let link = staged(dir);
+	let _ = fs::remove_file(&link);
+	symlink(&target, &link).with_context(|| format!("Linking {:?} to {:?}", &link, &target))?;
+	fs::rename(&link, dir).with_context(|| format!("Renaming {:?}", &link))?;
+	// the previous version is not used anymore
+	if let Some(previous) = previous.filter(|previous| previous != &target) {
+		let previous = dir.with_file_name(previous);
+		fs::remove_dir_all(&previous).with_context(|| format!("Removing {:?}", &previous))?;
+	}
+	Ok(())
+}
+
+#[test]
+fn staged_path() {
+	assert_eq!(
+		staged(Path::new("/etc/app/tls/key.pem")),
+		PathBuf::from("/etc/app/tls/.key.pem.rconfd")
+	);
+	assert_eq!(
+		versioned(
+			Path::new("/etc/app"),
+			Path::new("/etc/app.1"),
+			Path::new("/etc/app/tls/key.pem")
+		)
+		.unwrap(),
+		PathBuf::from("/etc/app.1/tls/key.pem")
+	);
+}
@@ -0,0 +1,81 @@
+use crate::libc::{addresses, canonical_name, hostname, total_memory};
+
+use serde_json::{json, Map, Value};
+use std::{fs, thread};
+
+/// file containing the machine id
+const MACHINE_ID: &str = "/etc/machine-id";
+
+/// file containing the kernel command line
+pub const CMDLINE: &str = "/proc/cmdline";
+
+/// directories proc secrets can be read from
+pub const PROC_DIRS: &[&str] = &["/proc/", "/sys/"];
+
+/// Return the facts about the host (hostname, fqdn, machine_id, addresses, cpus, memory) as a JSON object
+pub fn facts() -> Value {
+	let hostname = hostname().unwrap_or_default();
+	let fqdn = canonical_name(&hostname).unwrap_or_else(|| hostname.clone());
+	let addresses: Vec<String> = addresses()
+		.unwrap_or_default()
+		.iter()
+		.map(|addr| addr.to_string())
+		.collect();
+	json!({
+		"hostname": hostname,
+		"fqdn": fqdn,
+		"machine_id": fs::read_to_string(MACHINE_ID)
+			.map(|id| id.trim().to_owned())
+			.unwrap_or_default(),
+		"addresses": addresses,
+		"cpus": thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+		"memory": total_memory().unwrap_or(0),
+	})
+}
+
+/// Parse a kernel command line into a JSON object. Parameters without value are true, and repeated
+/// parameters are collected in an array
+pub fn parse_cmdline(cmdline: &str) -> Value {
+	let mut params = Map::new();
+	let mut chars = cmdline.trim().chars().peekable();
+	while chars.peek().is_some() {
+		// a parameter ends with a space outside of double quotes
+		let mut param = String::new();
+		let mut quoted = false;
+		for c in chars.by_ref() {
+			match c {
+				'"' => quoted = !quoted,
+				' ' | '\n' if !quoted => break,
+				c => param.push(c),
+			}
+		}
+		if param.is_empty() {
+			continue;
+		}
+		let (key, value) = match param.split_once('=') {
+			Some((key, value)) => (key.to_owned(), Value::String(value.to_owned())),
+			None => (param, Value::Bool(true)),
+		};
+		match params.get_mut(&key) {
+			Some(Value::Array(values)) => values.push(value),
+			Some(prev) => *prev = Value::Array(vec![prev.take(), value]),
+			None => {
+				params.insert(key, value);
+			}
+		}
+	}
+	Value::Object(params)
+}
+
+#[test]
+fn cmdline() {
+	assert_eq!(
+		parse_cmdline("ro quiet console=tty0 console=ttyS0 init=\"/sbin/my init\"\n"),
+		json!({
+			"ro": true,
+			"quiet": true,
+			"console": ["tty0", "ttyS0"],
+			"init": "/sbin/my init"
+		})
+	);
+}
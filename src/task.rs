@@ -1,6 +1,8 @@
+use crate::message::{send_message, Message};
+
 use anyhow::Result;
-use async_std::{future, task};
-use std::time::Duration;
+use async_std::{channel::Sender, future, task};
+use std::{collections::HashMap, ops::Deref, time::Duration};
 
 /// delay a future by a duration
 pub fn delay_task<F>(fut: F, dur: Duration) -> task::JoinHandle<Result<()>>
@@ -15,3 +17,52 @@ where
 		Ok::<(), anyhow::Error>(())
 	})
 }
+
+/// Scheduled tasks by the message they send, to be able to cancel them
+#[derive(Default)]
+pub struct Tasks(HashMap<Message, task::JoinHandle<Result<()>>>);
+
+impl Deref for Tasks {
+	type Target = HashMap<Message, task::JoinHandle<Result<()>>>;
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl Tasks {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Keep the handle of a task sending msg, cancelling the task previously scheduled for the same message
+	pub async fn insert(&mut self, msg: Message, handle: task::JoinHandle<Result<()>>) {
+		if let Some(prev) = self.0.insert(msg, handle) {
+			prev.cancel().await;
+		}
+	}
+
+	/// Send msg after a duration
+	pub async fn schedule(&mut self, sender: &Sender<Message>, msg: Message, dur: Duration) {
+		let handle = delay_task(send_message(sender.clone(), msg.clone()), dur);
+		self.insert(msg, handle).await;
+	}
+
+	/// Cancel the tasks whose message is not kept and return their number
+	pub async fn retain<F>(&mut self, keep: F) -> usize
+	where
+		F: Fn(&Message) -> bool,
+	{
+		let cancelled = self
+			.0
+			.keys()
+			.filter(|msg| !keep(msg))
+			.cloned()
+			.collect::<Vec<_>>();
+		for msg in cancelled.iter() {
+			if let Some(handle) = self.0.remove(msg) {
+				handle.cancel().await;
+			}
+		}
+		cancelled.len()
+	}
+}
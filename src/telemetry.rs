@@ -0,0 +1,32 @@
+use anyhow::{Context, Result};
+use opentelemetry::{
+	sdk::{trace, Resource},
+	KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Export the tracing spans to an OTLP collector (http/protobuf)
+pub fn init(endpoint: &str) -> Result<()> {
+	let tracer = opentelemetry_otlp::new_pipeline()
+		.tracing()
+		.with_exporter(
+			opentelemetry_otlp::new_exporter()
+				.http()
+				.with_endpoint(endpoint),
+		)
+		.with_trace_config(trace::config().with_resource(Resource::new(vec![
+			KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+			KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+		])))
+		.install_batch(opentelemetry::runtime::AsyncStd)
+		.with_context(|| format!("Initializing OTLP exporter to {}", endpoint))?;
+	let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+	tracing::subscriber::set_global_default(subscriber)?;
+	Ok(())
+}
+
+/// Flush the remaining spans before exiting
+pub fn shutdown() {
+	opentelemetry::global::shutdown_tracer_provider();
+}
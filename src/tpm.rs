@@ -0,0 +1,50 @@
+use crate::result::{Error, Result};
+
+/// default persistent handle of the parent key of sealed objects
+pub const PARENT_HANDLE: u32 = 0x8100_0001;
+
+/// Unseal with the TPM the object created under the persistent parent key (tpm2_create -u <path>.pub -r
+/// <path>.priv) and return its content
+#[cfg(feature = "tpm")]
+pub fn unseal(path: &str, parent: u32) -> Result<Vec<u8>> {
+	use std::{convert::TryFrom, fs};
+	use tss_esapi::{
+		handles::{KeyHandle, PersistentTpmHandle, TpmHandle},
+		structures::{Private, Public},
+		traits::UnMarshall,
+		Context, TctiNameConf,
+	};
+
+	let tpm_err = |e: tss_esapi::Error| Error::Tpm(format!("{}: {}", path, e));
+	// tpm2-tools files are size prefixed TPM2B structures
+	let read = |ext: &str| {
+		let file = format!("{}.{}", path, ext);
+		fs::read(&file)
+			.map(|bytes| bytes.get(2..).unwrap_or_default().to_vec())
+			.map_err(|e| Error::Tpm(format!("{}: {}", file, e)))
+	};
+	let public = Public::unmarshall(&read("pub")?).map_err(tpm_err)?;
+	let private = Private::try_from(read("priv")?).map_err(tpm_err)?;
+
+	let tcti = TctiNameConf::from_environment_variable().map_err(tpm_err)?;
+	let mut context = Context::new(tcti).map_err(tpm_err)?;
+	let handle = PersistentTpmHandle::new(parent).map_err(tpm_err)?;
+	let parent = context
+		.tr_from_tpm_public(TpmHandle::Persistent(handle))
+		.map_err(tpm_err)?;
+	let key = context
+		.execute_with_nullauth_session(|ctx| ctx.load(KeyHandle::from(parent), private, public))
+		.map_err(tpm_err)?;
+	let data = context.execute_with_nullauth_session(|ctx| ctx.unseal(key.into()));
+	// don't leave the object loaded whatever the result
+	let _ = context.flush_context(key.into());
+	Ok(data.map_err(tpm_err)?.value().to_vec())
+}
+
+/// Can't unseal anything without TPM support
+#[cfg(not(feature = "tpm"))]
+pub fn unseal(_path: &str, _parent: u32) -> Result<Vec<u8>> {
+	Err(Error::Tpm(
+		"rconfd was built without the tpm feature".to_owned(),
+	))
+}
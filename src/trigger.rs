@@ -0,0 +1,33 @@
+use crate::message::Message;
+
+use async_std::{channel::Sender, task};
+use std::{fs, path::Path, time::Duration, time::SystemTime};
+
+/// delay (s) between two checks of the trigger file
+const TRIGGER_POLL: u64 = 1;
+
+/// return the modification time of path if it exists
+fn modified(path: &Path) -> Option<SystemTime> {
+	fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Watch the modification time of the file at path in the background and ask for the regeneration
+/// of all templates each time it is touched
+pub fn watch_trigger(path: &str, sender: Sender<Message>) {
+	let path = Path::new(path).to_owned();
+	log::info!("Regenerating all templates when {:?} is touched", &path);
+	task::spawn(async move {
+		let mut last = modified(&path);
+		loop {
+			task::sleep(Duration::from_secs(TRIGGER_POLL)).await;
+			let current = modified(&path);
+			if current.is_some() && current != last {
+				log::info!("{:?} touched", &path);
+				if sender.send(Message::RenderAll).await.is_err() {
+					break;
+				}
+			}
+			last = current;
+		}
+	});
+}
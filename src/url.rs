@@ -0,0 +1,51 @@
+use crate::result::{Error, Result};
+
+use futures::io::AsyncReadExt;
+use isahc::{
+	config::{Configurable, RedirectPolicy},
+	Request, RequestExt,
+};
+use std::time::Duration;
+
+/// lookup list for the supported HTTP methods
+pub const METHODS: &[&str] = &["GET", "POST"];
+
+/// Send a request with an optional bearer token to url and return the body of the response, which must
+/// not be larger than max_size
+pub async fn fetch(
+	url: &str,
+	method: &str,
+	bearer: Option<&str>,
+	max_size: u64,
+) -> Result<Vec<u8>> {
+	let http_err = |e: String| Error::Http(url.to_owned(), e);
+	let mut request = Request::builder()
+		.method(method)
+		.uri(url)
+		.redirect_policy(RedirectPolicy::Limit(5))
+		.timeout(Duration::from_secs(10));
+	if let Some(token) = bearer {
+		request = request.header("Authorization", format!("Bearer {}", token));
+	}
+	let mut response = request
+		.body(())
+		.map_err(|e| http_err(e.to_string()))?
+		.send_async()
+		.await
+		.map_err(|e| http_err(e.to_string()))?;
+	if !response.status().is_success() {
+		return Err(http_err(format!("status {}", response.status())));
+	}
+	// read at most max_size bytes (plus one to detect bigger bodies)
+	let mut body = Vec::new();
+	response
+		.body_mut()
+		.take(max_size + 1)
+		.read_to_end(&mut body)
+		.await
+		.map_err(|e| http_err(e.to_string()))?;
+	if body.len() as u64 > max_size {
+		return Err(Error::SecretTooLarge(url.to_owned(), max_size));
+	}
+	Ok(body)
+}
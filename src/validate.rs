@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Syntax of a generated file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+	Json,
+	Yaml,
+	Toml,
+	Ini,
+}
+
+/// lookup list for formats
+const FORMATS: &[(&str, Format)] = &[
+	("json", Format::Json),
+	("yaml", Format::Yaml),
+	("toml", Format::Toml),
+	("ini", Format::Ini),
+];
+
+impl fmt::Display for Format {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (s, format) in FORMATS.iter() {
+			if self == format {
+				return write!(f, "{}", s);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Check the syntax of an ini file (sections, key=value or key: value lines and ; or # comments)
+fn check_ini(content: &str) -> Result<(), String> {
+	for (i, line) in content.lines().enumerate() {
+		let line = line.trim();
+		let valid = if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+			true
+		} else if line.starts_with('[') {
+			line.ends_with(']') && line.len() > 2
+		} else {
+			line.find(|c| c == '=' || c == ':')
+				.map(|pos| !line[..pos].trim().is_empty())
+				.unwrap_or(false)
+		};
+		if !valid {
+			return Err(format!("line {}: expected a section or a key=value", i + 1));
+		}
+	}
+	Ok(())
+}
+
+/// Check that content is valid according to format
+pub fn check(format: Format, content: &str) -> Result<(), String> {
+	match format {
+		Format::Json => serde_json::from_str::<serde::de::IgnoredAny>(content)
+			.map(|_| ())
+			.map_err(|e| e.to_string()),
+		Format::Yaml => serde_yaml::from_str::<serde::de::IgnoredAny>(content)
+			.map(|_| ())
+			.map_err(|e| e.to_string()),
+		Format::Toml => toml::from_str::<toml::Value>(content)
+			.map(|_| ())
+			.map_err(|e| e.to_string()),
+		Format::Ini => check_ini(content),
+	}
+}
+
+#[test]
+fn ini() {
+	assert!(check_ini("; comment\n[section]\nkey = value\nother: value\n").is_ok());
+	assert!(check_ini("[section\nkey = value\n").is_err());
+	assert!(check_ini("[section]\n= value\n").is_err());
+}
@@ -0,0 +1,87 @@
+use crate::{libc::local_seconds, result::Error};
+
+use std::{
+	str::FromStr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// number of seconds in a day
+const DAY: u32 = 86400;
+
+/// Daily time window (seconds since midnight in local time), which can span midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+	start: u32,
+	end: u32,
+}
+
+/// parse a HH:MM time into seconds since midnight
+fn parse_time(time: &str) -> Option<u32> {
+	let (hours, minutes) = time.trim().split_once(':')?;
+	let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+	if hours < 24 && minutes < 60 {
+		Some(hours * 3600 + minutes * 60)
+	} else {
+		None
+	}
+}
+
+/// Parse a HH:MM-HH:MM window
+impl FromStr for Window {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		s.split_once('-')
+			.and_then(|(start, end)| {
+				Some(Window {
+					start: parse_time(start)?,
+					end: parse_time(end)?,
+				})
+			})
+			.ok_or_else(|| Error::InvalidWindow(s.to_owned()))
+	}
+}
+
+impl Window {
+	/// Return the delay (s) until the window opens, 0 if it is open
+	fn until_open(&self, now: u32) -> u32 {
+		let open = if self.start <= self.end {
+			self.start <= now && now < self.end
+		} else {
+			now >= self.start || now < self.end
+		};
+		if open {
+			0
+		} else {
+			(self.start + DAY - now) % DAY
+		}
+	}
+}
+
+/// Return the delay until one of the windows opens (zero if one is open or if there are no windows)
+pub fn until_open(windows: &[Window], now: SystemTime) -> Duration {
+	let secs = now
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs() as i64)
+		.unwrap_or(0);
+	let now = local_seconds(secs);
+	windows
+		.iter()
+		.map(|window| window.until_open(now))
+		.min()
+		.map(|delay| Duration::from_secs(delay as u64))
+		.unwrap_or_default()
+}
+
+#[test]
+fn windows() {
+	let window = "02:00-04:00".parse::<Window>().unwrap();
+	assert_eq!(window.until_open(3 * 3600), 0);
+	assert_eq!(window.until_open(3600), 3600);
+	assert_eq!(window.until_open(5 * 3600), 21 * 3600);
+	let night = "23:30-00:30".parse::<Window>().unwrap();
+	assert_eq!(night.until_open(0), 0);
+	assert_eq!(night.until_open(23 * 3600), 1800);
+	assert!("25:00-01:00".parse::<Window>().is_err());
+	assert!("02:00".parse::<Window>().is_err());
+}
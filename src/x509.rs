@@ -0,0 +1,24 @@
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::pem::Pem;
+
+/// Return the earliest expiry date of the PEM certificates found in a secret value
+pub fn not_after(value: &Value) -> Option<SystemTime> {
+	match value {
+		Value::String(s) if s.contains("-----BEGIN CERTIFICATE-----") => {
+			Pem::iter_from_buffer(s.as_bytes())
+				.filter_map(|pem| pem.ok())
+				.filter(|pem| pem.label == "CERTIFICATE")
+				.filter_map(|pem| {
+					pem.parse_x509()
+						.ok()
+						.map(|cert| cert.validity().not_after.timestamp())
+				})
+				.min()
+				.map(|ts| UNIX_EPOCH + Duration::from_secs(ts.max(0) as u64))
+		}
+		Value::Array(values) => values.iter().filter_map(not_after).min(),
+		Value::Object(map) => map.values().filter_map(not_after).min(),
+		_ => None,
+	}
+}